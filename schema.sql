@@ -1,3 +1,11 @@
+DROP TABLE IF EXISTS Hits;
+DROP TABLE IF EXISTS RoutingRules;
+DROP TABLE IF EXISTS Links;
+DROP TABLE IF EXISTS CouponCodes;
+DROP TABLE IF EXISTS MergeFields;
+DROP TABLE IF EXISTS WebhookEvents;
+DROP TABLE IF EXISTS Personalizations;
+DROP TABLE IF EXISTS CampaignFields;
 DROP TABLE IF EXISTS Campaigns;
 DROP TABLE IF EXISTS Members;
 DROP TABLE IF EXISTS Lists;
@@ -25,6 +33,7 @@ CREATE TABLE Lists(
     Id TEXT PRIMARY KEY,
     UserId INTEGER NOT NULL,
     WebhookId TEXT NOT NULL,
+    WebhookSecret TEXT NOT NULL,
     FOREIGN KEY (UserId)
         REFERENCES Users (Id)
             ON UPDATE CASCADE
@@ -36,8 +45,16 @@ CREATE TABLE Campaigns(
     Title TEXT NOT NULL,
     ListId TEXT NOT NULL,
     UserId INTEGER NOT NULL,
-    VideoTag TEXT NOT NULL,
-    ImageTag TEXT NOT NULL,
+    Status TEXT,
+    StatusReason TEXT,
+    StatusChangedAt TEXT,
+    Released INTEGER NOT NULL DEFAULT 0,
+    SentAt INTEGER,
+    Recipients INTEGER NOT NULL DEFAULT 0,
+    LandingPage INTEGER NOT NULL DEFAULT 0,
+    Headline TEXT,
+    CtaLabel TEXT,
+    CtaUrl TEXT,
     FOREIGN KEY (ListId)
         REFERENCES Lists (Id)
             ON UPDATE CASCADE
@@ -48,12 +65,137 @@ CREATE TABLE Campaigns(
             ON DELETE CASCADE
 );
 
+CREATE TABLE CampaignFields(
+    CampaignId TEXT NOT NULL,
+    Name TEXT NOT NULL,
+    Type TEXT NOT NULL,
+    Tag TEXT,
+    DefaultValue TEXT NOT NULL DEFAULT '',
+    Template TEXT,
+    Tracking TEXT,
+    ThumbnailOf TEXT,
+    Overlay INTEGER NOT NULL DEFAULT 0,
+    QrCodeOf TEXT,
+    Position INTEGER NOT NULL,
+    PRIMARY KEY (CampaignId, Name),
+    FOREIGN KEY (CampaignId)
+        REFERENCES Campaigns (Id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE TABLE Personalizations(
+    CampaignId TEXT NOT NULL,
+    EmailId TEXT NOT NULL,
+    Field TEXT NOT NULL,
+    Value TEXT NOT NULL,
+    PRIMARY KEY (CampaignId, EmailId, Field),
+    FOREIGN KEY (CampaignId, Field)
+        REFERENCES CampaignFields (CampaignId, Name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE TABLE CouponCodes(
+    CampaignId TEXT NOT NULL,
+    Field TEXT NOT NULL,
+    Code TEXT NOT NULL,
+    EmailId TEXT,
+    AssignedAt INTEGER,
+    PRIMARY KEY (CampaignId, Field, Code),
+    UNIQUE (CampaignId, Field, EmailId),
+    FOREIGN KEY (CampaignId, Field)
+        REFERENCES CampaignFields (CampaignId, Name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
 CREATE TABLE Members(
     EmailId TEXT NOT NULL,
     FullName TEXT NOT NULL,
     ListId TEXT NOT NULL,
+    Active INTEGER NOT NULL DEFAULT 1,
+    UNIQUE (EmailId, ListId),
     FOREIGN KEY (ListId)
         REFERENCES Lists (Id)
             ON UPDATE CASCADE
             ON DELETE CASCADE
-);
\ No newline at end of file
+);
+
+CREATE TABLE WebhookEvents(
+    Id INTEGER PRIMARY KEY,
+    ListId TEXT NOT NULL,
+    Type TEXT NOT NULL,
+    Payload TEXT NOT NULL,
+    Hash TEXT NOT NULL,
+    Status TEXT NOT NULL,
+    Error TEXT,
+    ReceivedAt INTEGER NOT NULL,
+    ProcessedAt INTEGER,
+    FOREIGN KEY (ListId)
+        REFERENCES Lists (Id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX WebhookEventHashes ON WebhookEvents (Hash, ReceivedAt);
+
+CREATE TABLE MergeFields(
+    ListId TEXT NOT NULL,
+    MergeId INTEGER NOT NULL,
+    Tag TEXT NOT NULL,
+    Name TEXT NOT NULL,
+    CampaignId TEXT,
+    PRIMARY KEY (ListId, MergeId),
+    FOREIGN KEY (ListId)
+        REFERENCES Lists (Id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE TABLE Links(
+    Id TEXT PRIMARY KEY,
+    CampaignId TEXT NOT NULL,
+    EmailId TEXT NOT NULL,
+    Field TEXT NOT NULL,
+    Url TEXT NOT NULL,
+    CreatedAt INTEGER NOT NULL,
+    UNIQUE (CampaignId, EmailId, Field),
+    FOREIGN KEY (CampaignId, Field)
+        REFERENCES CampaignFields (CampaignId, Name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE TABLE RoutingRules(
+    CampaignId TEXT NOT NULL,
+    Field TEXT NOT NULL,
+    Variant TEXT NOT NULL,
+    Countries TEXT NOT NULL DEFAULT '',
+    Devices TEXT NOT NULL DEFAULT '',
+    Languages TEXT NOT NULL DEFAULT '',
+    Url TEXT NOT NULL,
+    Position INTEGER NOT NULL,
+    PRIMARY KEY (CampaignId, Variant),
+    FOREIGN KEY (CampaignId, Field)
+        REFERENCES CampaignFields (CampaignId, Name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE TABLE Hits(
+    Id INTEGER PRIMARY KEY,
+    LinkId TEXT NOT NULL,
+    Kind TEXT NOT NULL,
+    HitAt INTEGER NOT NULL,
+    UserAgent TEXT,
+    IpHash TEXT,
+    Country TEXT,
+    Machine INTEGER NOT NULL DEFAULT 0,
+    Reason TEXT,
+    Variant TEXT,
+    FOREIGN KEY (LinkId)
+        REFERENCES Links (Id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
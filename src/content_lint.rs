@@ -0,0 +1,386 @@
+use std::collections::HashSet;
+
+use crate::{
+    mailchimp::lists::MergeFields,
+    merge_tag::{self, Occurrence},
+    session::{CampaignField, FieldType},
+};
+
+/// Tags mailchimp fills in itself rather than from a merge field
+const SYSTEM_TAGS: &[&str] = &[
+    "ABOUT_LIST",
+    "ARCHIVE",
+    "ARCHIVE_LINK_SHORT",
+    "CURRENT_YEAR",
+    "DATE",
+    "EMAIL",
+    "EMAIL_TYPE",
+    "FORWARD",
+    "LIST_ADDRESS",
+    "LIST_ADDRESS_HTML",
+    "LIST_ADDRESSLINE_TEXT",
+    "LIST_COMPANY",
+    "LIST_DESCRIPTION",
+    "LIST_NAME",
+    "LIST_PHONE",
+    "LIST_URL",
+    "MC_LANGUAGE",
+    "MC_LANGUAGE_LABEL",
+    "MC_PREVIEW_TEXT",
+    "MC_SUBJECT",
+    "REWARDS",
+    "REWARDS_TEXT",
+    "UNSUB",
+    "UPDATE_PROFILE",
+    "ELSE",
+    "END",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Members would get a broken or wrong campaign
+    Error,
+    /// The campaign works, but likely not as intended
+    Warning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// A field of the campaign whose tag is nowhere in the content
+    MissingTag,
+    /// A field of the campaign that holds no merge field
+    UnpopulatedField,
+    /// A tag that is no merge field of the list
+    UnknownTag,
+    /// The tag of a merge field that belongs to another campaign or was released
+    StaleTag,
+    /// A `*|` that is not closed, or a tag with characters mailchimp does not accept
+    MalformedTag,
+    /// A url field used without the scheme its values are stored without
+    MalformedUrl,
+    /// A url field that is not the `href` or `src` of an element
+    UnwrappedLink,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Issue {
+    pub severity: Severity,
+    pub kind: IssueKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    pub message: String,
+}
+
+/// Checks the html of a campaign against the campaign's fields and the merge
+/// fields of its list, see [`IssueKind`] for what is reported
+pub fn lint(html: &str, fields: &[CampaignField], list_fields: &MergeFields) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let mut used = HashSet::new();
+    let mut reported = HashSet::new();
+
+    for occurrence in merge_tag::scan(html) {
+        let occurrence = match occurrence {
+            Ok(occurrence) => occurrence,
+            Err(start) => {
+                issues.push(Issue {
+                    severity: Severity::Error,
+                    kind: IssueKind::MalformedTag,
+                    tag: None,
+                    field: None,
+                    message: format!(
+                        "'{}' opens a merge tag that is never closed",
+                        excerpt(html, start)
+                    ),
+                });
+                continue;
+            }
+        };
+        let Some(tag) = occurrence.merge_field() else {
+            continue;
+        };
+        let tag = tag.to_ascii_uppercase();
+        used.insert(tag.clone());
+
+        // Each tag is only reported once however often it is used
+        if !merge_tag::is_valid(&tag) {
+            if reported.insert(tag.clone()) {
+                issues.push(Issue {
+                    severity: Severity::Error,
+                    kind: IssueKind::MalformedTag,
+                    tag: Some(tag.clone()),
+                    field: None,
+                    message: format!(
+                        "*|{}|* is not a valid merge tag, tags are uppercase letters, digits and underscores",
+                        occurrence.inner
+                    ),
+                });
+            }
+            continue;
+        }
+
+        if let Some(field) = fields
+            .iter()
+            .find(|field| field.tag.as_deref() == Some(tag.as_str()))
+        {
+            if field.ty == FieldType::Url && occurrence.inner.trim().eq_ignore_ascii_case(&tag) {
+                issues.extend(lint_url(html, &occurrence, field, &tag));
+            }
+            continue;
+        }
+
+        if !reported.insert(tag.clone()) {
+            continue;
+        }
+        match list_fields.by_tag(&tag) {
+            Some(merge_field) if merge_field.name.contains('/') => issues.push(Issue {
+                severity: Severity::Error,
+                kind: IssueKind::StaleTag,
+                tag: Some(tag.clone()),
+                field: None,
+                message: format!(
+                    "*|{tag}|* is the merge field '{}' of another campaign or a released field",
+                    merge_field.name
+                ),
+            }),
+            Some(_) => {}
+            None if SYSTEM_TAGS.contains(&tag.as_str()) => {}
+            None => issues.push(Issue {
+                severity: Severity::Error,
+                kind: IssueKind::UnknownTag,
+                tag: Some(tag.clone()),
+                field: None,
+                message: format!("*|{tag}|* is no merge field of the list"),
+            }),
+        }
+    }
+
+    for field in fields {
+        let Some(tag) = &field.tag else {
+            issues.push(Issue {
+                severity: Severity::Warning,
+                kind: IssueKind::UnpopulatedField,
+                tag: None,
+                field: Some(field.name.clone()),
+                message: format!(
+                    "The field '{}' has no merge field, populate the campaign first",
+                    field.name
+                ),
+            });
+            continue;
+        };
+
+        if !used.contains(tag) {
+            issues.push(Issue {
+                severity: Severity::Error,
+                kind: IssueKind::MissingTag,
+                tag: Some(tag.clone()),
+                field: Some(field.name.clone()),
+                message: format!(
+                    "The field '{}' is not used, its tag *|{tag}|* is not in the content",
+                    field.name
+                ),
+            });
+        }
+    }
+
+    issues
+}
+
+/// Checks that a url field is used as `href="https://*|TAG|*"` or
+/// `src="https://*|TAG|*"`
+fn lint_url(
+    html: &str,
+    occurrence: &Occurrence,
+    field: &CampaignField,
+    tag: &str,
+) -> Option<Issue> {
+    let before = html[..occurrence.start].to_ascii_lowercase();
+    let Some(before) = before
+        .strip_suffix("https://")
+        .or_else(|| before.strip_suffix("http://"))
+    else {
+        return Some(Issue {
+            severity: Severity::Error,
+            kind: IssueKind::MalformedUrl,
+            tag: Some(tag.into()),
+            field: Some(field.name.clone()),
+            message: format!(
+                "'{}' is missing the scheme, url fields are stored without it so the link must be https://*|{tag}|*",
+                excerpt(html, occurrence.start)
+            ),
+        });
+    };
+
+    let attribute = before
+        .strip_suffix(['"', '\''])
+        .and_then(|before| before.trim_end().strip_suffix('='))
+        .map(str::trim_end);
+    if attribute.is_some_and(|before| before.ends_with("href") || before.ends_with("src")) {
+        return None;
+    }
+
+    Some(Issue {
+        severity: Severity::Warning,
+        kind: IssueKind::UnwrappedLink,
+        tag: Some(tag.into()),
+        field: Some(field.name.clone()),
+        message: format!(
+            "https://*|{tag}|* is not the href of a link or the src of an image, members will see it as text"
+        ),
+    })
+}
+
+/// The content around a byte offset, to point users at an issue
+fn excerpt(html: &str, at: usize) -> String {
+    html[at..].chars().take(24).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str, ty: FieldType, tag: Option<&str>) -> CampaignField {
+        let mut field = CampaignField::defaults().remove(0);
+        field.name = name.into();
+        field.ty = ty;
+        field.tag = tag.map(String::from);
+        field
+    }
+
+    fn list_fields() -> MergeFields {
+        serde_json::from_value(serde_json::json!({
+            "merge_fields": [
+                { "merge_id": 1, "tag": "FNAME", "name": "First Name", "type": "text" },
+                { "merge_id": 5, "tag": "VABC", "name": "video/c1", "type": "text" },
+                { "merge_id": 6, "tag": "NTEXT", "name": "note/c1", "type": "text" },
+                { "merge_id": 7, "tag": "VOLD", "name": "video/c0", "type": "text" },
+            ],
+            "total_items": 4,
+        }))
+        .expect("Failed to parse merge fields")
+    }
+
+    fn kinds(html: &str, fields: &[CampaignField]) -> Vec<(IssueKind, Option<String>)> {
+        lint(html, fields, &list_fields())
+            .into_iter()
+            .map(|issue| (issue.kind, issue.tag))
+            .collect()
+    }
+
+    fn video() -> CampaignField {
+        field("video", FieldType::Url, Some("VABC"))
+    }
+
+    #[test]
+    fn accepts_wrapped_links() {
+        assert_eq!(
+            kinds(
+                "<a href=\"https://*|VABC|*\">*|FNAME|* *|UNSUB|*</a>",
+                &[video()]
+            ),
+            vec![]
+        );
+        assert_eq!(kinds("<img src = 'http://*|vabc|*'>", &[video()]), vec![]);
+    }
+
+    #[test]
+    fn reports_missing_and_unpopulated_fields() {
+        let fields = [video(), field("note", FieldType::Text, None)];
+
+        assert_eq!(
+            kinds("<p>*|FNAME|*</p>", &fields),
+            vec![
+                (IssueKind::MissingTag, Some("VABC".into())),
+                (IssueKind::UnpopulatedField, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_unknown_and_stale_tags_once() {
+        assert_eq!(
+            kinds("*|VABC|* *|NOPE|* *|VOLD|* *|NOPE|* *|IF:VOLD|*", &[]),
+            vec![
+                (IssueKind::StaleTag, Some("VABC".into())),
+                (IssueKind::UnknownTag, Some("NOPE".into())),
+                (IssueKind::StaleTag, Some("VOLD".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_malformed_tags() {
+        let issues = kinds(
+            "<a href=\"https://*|VABC|*\">*|FNAME</a> *|FIRST NAME|*",
+            &[video()],
+        );
+
+        assert_eq!(
+            issues,
+            vec![
+                (IssueKind::MalformedTag, None),
+                (IssueKind::MalformedTag, Some("FIRST NAME".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn understands_conditions() {
+        let html = "*|IF:VABC|*<a href=\"https://*|VABC|*\">Watch</a>*|ELSE:|*Soon*|END:IF|*";
+
+        assert_eq!(kinds(html, &[video()]), vec![]);
+        // Conditions and html tags use a field without being links
+        assert_eq!(
+            kinds("*|IF:VABC|**|HTML:VABC|**|END:IF|*", &[video()]),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn reports_urls_without_scheme() {
+        assert_eq!(
+            kinds("<a href=\"*|VABC|*\">Watch</a>", &[video()]),
+            vec![(IssueKind::MalformedUrl, Some("VABC".into()))]
+        );
+        assert_eq!(
+            kinds("<a href=\"www.*|VABC|*\">Watch</a>", &[video()]),
+            vec![(IssueKind::MalformedUrl, Some("VABC".into()))]
+        );
+    }
+
+    #[test]
+    fn reports_links_shown_as_text() {
+        assert_eq!(
+            kinds("<p>Watch at https://*|VABC|*</p>", &[video()]),
+            vec![(IssueKind::UnwrappedLink, Some("VABC".into()))]
+        );
+        assert_eq!(
+            kinds(
+                "<a title=\"https://*|VABC|*\" href=\"#\">Watch</a>",
+                &[video()]
+            ),
+            vec![(IssueKind::UnwrappedLink, Some("VABC".into()))]
+        );
+        // Text fields are not links
+        assert_eq!(
+            kinds(
+                "<p>*|NTEXT|*</p>",
+                &[field("note", FieldType::Text, Some("NTEXT"))]
+            ),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn reports_severities() {
+        let issues = lint("<p>https://*|VABC|*</p>", &[video()], &list_fields());
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].severity, Severity::Warning);
+        assert_eq!(issues[0].field.as_deref(), Some("video"));
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::session::{CampaignField, FieldType, Personalization};
+
+/// The columns of a personalization csv that are read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Column {
+    Email,
+    /// The column of the campaign field at the index
+    Field(usize),
+}
+
+impl Column {
+    /// Maps a header to a column, ignoring case and separators so that headers
+    /// like `Email Address`, `video_url` or `Thumbnail-URL` are understood.
+    /// Coupon fields have no column as their codes come from the pool.
+    fn from_header(header: &str, fields: &[CampaignField]) -> Option<Self> {
+        let header = Self::normalize(header);
+
+        if matches!(
+            header.as_str(),
+            "email" | "emailaddress" | "mail" | "emailid"
+        ) {
+            return Some(Column::Email);
+        }
+
+        fields
+            .iter()
+            .position(|field| {
+                let name = Self::normalize(&field.name);
+
+                field.ty != FieldType::Coupon
+                    && (header == name
+                        || header == format!("{name}url")
+                        || header == format!("{name}link")
+                        || Self::aliases(&field.name).contains(&header.as_str()))
+            })
+            .map(Column::Field)
+    }
+
+    fn normalize(header: &str) -> String {
+        header
+            .chars()
+            .filter(|c| c.is_alphanumeric())
+            .collect::<String>()
+            .to_lowercase()
+    }
+
+    /// Other headers understood for the default fields
+    fn aliases(field: &str) -> &'static [&'static str] {
+        match field {
+            "video" => &["url", "link"],
+            "image" => &["thumbnail", "thumbnailurl", "thumb"],
+            _ => &[],
+        }
+    }
+}
+
+/// The outcome of a single csv row
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RowResult {
+    /// The line of the row in the csv, the header being line 1
+    pub line: u64,
+    pub email: Option<String>,
+    pub accepted: bool,
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct CsvImport {
+    pub rows: Vec<RowResult>,
+    pub accepted: Vec<Personalization>,
+}
+
+impl CsvImport {
+    /// The largest csv accepted. Csvs are read whole into the worker's memory
+    /// rather than streamed, 5 MiB holding far more rows than a list has members.
+    pub const MAX_BYTES: usize = 5 * 1024 * 1024;
+
+    /// Reads a csv of member emails with their values of the campaign's
+    /// `fields`, each field being an optional column. Rows are validated one at
+    /// a time, `members` being the normalized emails of the campaign's list.
+    /// Only a missing or unreadable header fails the import.
+    pub fn parse(
+        body: &[u8],
+        members: &HashSet<String>,
+        fields: &[CampaignField],
+    ) -> Result<Self, String> {
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(body);
+
+        let headers = reader
+            .headers()
+            .map_err(|err| format!("Failed to read the csv header: {err}"))?;
+        let mut columns = HashMap::new();
+        for (index, header) in headers.iter().enumerate() {
+            if let Some(column) = Column::from_header(header, fields) {
+                columns.entry(column).or_insert(index);
+            }
+        }
+        if !columns.contains_key(&Column::Email) {
+            return Err("The csv is missing the email column".into());
+        }
+        if columns.len() == 1 {
+            let names = fields
+                .iter()
+                .filter(|field| field.ty != FieldType::Coupon)
+                .map(|field| field.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            return Err(format!(
+                "The csv has no column for any of the campaign's fields: {names}"
+            ));
+        }
+
+        let mut import = CsvImport::default();
+        let mut seen = HashMap::new();
+
+        for record in reader.records() {
+            let record = match record {
+                Ok(record) => record,
+                Err(err) => {
+                    import.rows.push(RowResult {
+                        line: err.position().map_or(0, |position| position.line()),
+                        email: None,
+                        accepted: false,
+                        errors: vec![format!("Failed to read the row: {err}")],
+                    });
+                    continue;
+                }
+            };
+            let line = record.position().map_or(0, |position| position.line());
+            let field = |column| record.get(columns[&column]).unwrap_or_default();
+
+            // Spreadsheets often end with rows that only contain separators
+            if record.iter().all(str::is_empty) {
+                continue;
+            }
+
+            let mut errors = Vec::new();
+
+            let email = Personalization::normalize_email(field(Column::Email));
+            if !Self::is_valid_email(&email) {
+                errors.push(format!("'{email}' is not a valid email"));
+            } else if let Some(first_line) = seen.get(&email) {
+                errors.push(format!("Duplicate of the row on line {first_line}"));
+            } else {
+                // A row failing for its values still takes up the email, so that
+                // a later row does not silently replace it
+                seen.insert(email.clone(), line);
+                if !members.contains(&email) {
+                    errors.push(format!("'{email}' is not a member of the list"));
+                }
+            }
+
+            // Empty cells leave the field to the template or default
+            let mut values = BTreeMap::new();
+            for (index, campaign_field) in fields.iter().enumerate() {
+                let column = Column::Field(index);
+                if !columns.contains_key(&column) || field(column).is_empty() {
+                    continue;
+                }
+                let value = field(column);
+
+                match campaign_field.ty.normalize(value) {
+                    Ok(value) => {
+                        values.insert(campaign_field.name.clone(), value);
+                    }
+                    Err(err) => errors.push(format!("Invalid {}: {err}", campaign_field.name)),
+                }
+            }
+            if values.is_empty() && errors.is_empty() {
+                errors.push("The row has no values".into());
+            }
+
+            let accepted = errors.is_empty();
+            if accepted {
+                import.accepted.push(Personalization {
+                    email: email.clone(),
+                    values,
+                });
+            }
+
+            import.rows.push(RowResult {
+                line,
+                email: Some(email).filter(|email| !email.is_empty()),
+                accepted,
+                errors,
+            });
+        }
+
+        Ok(import)
+    }
+
+    fn is_valid_email(email: &str) -> bool {
+        let Some((local, domain)) = email.split_once('@') else {
+            return false;
+        };
+
+        !local.is_empty()
+            && domain.contains('.')
+            && !domain.starts_with('.')
+            && !domain.ends_with('.')
+            && !email.contains(char::is_whitespace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members() -> HashSet<String> {
+        ["ann@example.com", "bob@example.com", "cid@example.com"]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    fn parse(csv: &str) -> Result<CsvImport, String> {
+        CsvImport::parse(csv.as_bytes(), &members(), &CampaignField::defaults())
+    }
+
+    #[test]
+    fn maps_header_aliases() {
+        let fields = CampaignField::defaults();
+        let column = |header| Column::from_header(header, &fields);
+
+        assert_eq!(column("Email Address"), Some(Column::Email));
+        assert_eq!(column("E-Mail"), Some(Column::Email));
+        assert_eq!(column("video_url"), Some(Column::Field(0)));
+        assert_eq!(column("Link"), Some(Column::Field(0)));
+        assert_eq!(column("Thumbnail-URL"), Some(Column::Field(1)));
+        assert_eq!(column("IMAGE"), Some(Column::Field(1)));
+        assert_eq!(column("name"), None);
+    }
+
+    #[test]
+    fn reads_rows() {
+        let import = parse(
+            "Email Address,Video URL,Thumbnail\n\
+             Ann@Example.com,https://vimeo.com/1,\n\
+             bob@example.com,,example.com/bob.png\n",
+        )
+        .expect("Failed to parse csv");
+
+        assert!(import.rows.iter().all(|row| row.accepted));
+        assert_eq!(import.accepted.len(), 2);
+        assert_eq!(import.accepted[0].email, "ann@example.com");
+        assert_eq!(import.accepted[0].values["video"], "vimeo.com/1");
+        assert!(!import.accepted[0].values.contains_key("image"));
+        assert_eq!(import.accepted[1].values["image"], "example.com/bob.png");
+        assert_eq!(import.rows[1].line, 3);
+    }
+
+    #[test]
+    fn reports_row_errors() {
+        let import = parse(
+            "email,video\n\
+             ann@example.com,vimeo.com/1\n\
+             ANN@example.com,vimeo.com/2\n\
+             carl@example.com,vimeo.com/3\n\
+             not an email,vimeo.com/4\n\
+             bob@example.com,ftp://example.com/video\n\
+             bob@example.com,vimeo.com/5\n\
+             cid@example.com,\n\
+             ,\n",
+        )
+        .expect("Failed to parse csv");
+
+        let errors = import
+            .rows
+            .iter()
+            .map(|row| (row.line, row.accepted, row.errors.join("; ")))
+            .collect::<Vec<_>>();
+        assert_eq!(errors.len(), 7, "{errors:?}");
+        assert_eq!(errors[0], (2, true, String::new()));
+        assert_eq!(errors[1].2, "Duplicate of the row on line 2");
+        assert_eq!(
+            errors[2].2,
+            "'carl@example.com' is not a member of the list"
+        );
+        assert_eq!(errors[3].2, "'not an email' is not a valid email");
+        assert!(
+            errors[4].2.starts_with("Invalid video: "),
+            "{}",
+            errors[4].2
+        );
+        // The row failing for its value still takes up the email
+        assert_eq!(errors[5].2, "Duplicate of the row on line 6");
+        assert_eq!(errors[6].2, "The row has no values");
+        assert_eq!(import.accepted.len(), 1);
+    }
+
+    #[test]
+    fn rejects_csvs_without_columns() {
+        assert_eq!(
+            parse("video\nvimeo.com/1\n").err().as_deref(),
+            Some("The csv is missing the email column")
+        );
+        assert_eq!(
+            parse("email,name\nann@example.com,Ann\n").err().as_deref(),
+            Some("The csv has no column for any of the campaign's fields: video, image")
+        );
+    }
+}
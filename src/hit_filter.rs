@@ -0,0 +1,234 @@
+use std::net::IpAddr;
+
+use sha2::{Digest, Sha256};
+
+/// Parts of the user agents of scanners and crawlers, see the file for the format
+const BOT_USER_AGENTS: &str = include_str!("bot_user_agents.txt");
+/// Ranges of privacy proxies like Apple Mail Privacy Protection
+const PROXY_RANGES: &str = include_str!("proxy_ranges.txt");
+
+/// Apple Mail Privacy Protection fetches with this user agent and nothing more
+const PROXY_USER_AGENT: &str = "Mozilla/5.0";
+
+/// Hits this many seconds after a campaign was sent come from scanners
+/// fetching the links as the emails are delivered, see [`send_window_secs`]
+pub const SEND_WINDOW_SECS: i64 = 10;
+/// A rough rate mailchimp delivers the emails of a campaign at
+const DELIVERIES_PER_SEC: i64 = 100;
+/// Hits from the same ip on every link of a member within this many seconds
+/// come from a scanner following every link of the email
+pub const BURST_SECS: i64 = 2;
+
+/// Why a hit on a tracked link was made by a machine rather than a member
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MachineReason {
+    ScannerUserAgent,
+    PrivacyProxy,
+    SoonAfterSend,
+    SameIpEveryLink,
+}
+
+impl MachineReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MachineReason::ScannerUserAgent => "scanner_user_agent",
+            MachineReason::PrivacyProxy => "privacy_proxy",
+            MachineReason::SoonAfterSend => "soon_after_send",
+            MachineReason::SameIpEveryLink => "same_ip_every_link",
+        }
+    }
+}
+
+/// How long after a campaign was sent to its `recipients` hits are taken for
+/// scanners. Mailchimp delivers large lists over minutes in an order that is
+/// not known, so the window covers the whole delivery. Members that follow
+/// their link while the campaign is still being delivered are counted as
+/// machines too, which keeps scanners from inflating the clicks of the
+/// members delivered to first.
+pub fn send_window_secs(recipients: i64) -> i64 {
+    SEND_WINDOW_SECS + recipients.max(0) / DELIVERIES_PER_SEC
+}
+
+/// Classifies a hit from what is known about its request. `None` if it looks
+/// like a member's.
+pub fn classify(
+    user_agent: Option<&str>,
+    ip: Option<IpAddr>,
+    seconds_since_send: Option<i64>,
+    recipients: i64,
+) -> Option<MachineReason> {
+    if user_agent.is_some_and(|user_agent| user_agent.trim() == PROXY_USER_AGENT)
+        || ip.is_some_and(|ip| entries(PROXY_RANGES).any(|range| in_range(ip, range)))
+    {
+        return Some(MachineReason::PrivacyProxy);
+    }
+
+    let Some(user_agent) = user_agent
+        .map(str::to_lowercase)
+        .filter(|ua| !ua.trim().is_empty())
+    else {
+        return Some(MachineReason::ScannerUserAgent);
+    };
+    if entries(BOT_USER_AGENTS).any(|bot| user_agent.contains(&bot.to_lowercase())) {
+        return Some(MachineReason::ScannerUserAgent);
+    }
+
+    if seconds_since_send
+        .is_some_and(|seconds| (0..send_window_secs(recipients)).contains(&seconds))
+    {
+        return Some(MachineReason::SoonAfterSend);
+    }
+
+    None
+}
+
+/// The ip of a hit is only stored hashed, to tell hits from the same ip apart
+pub fn hash_ip(ip: IpAddr) -> String {
+    Sha256::digest(ip.to_string().as_bytes())[..16]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The lines of a bundled list that are not comments
+fn entries(list: &str) -> impl Iterator<Item = &str> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Whether an ip is in a range like `17.0.0.0/8` or `2a02:26f7::/32`
+fn in_range(ip: IpAddr, range: &str) -> bool {
+    let Some((network, prefix)) = range.split_once('/') else {
+        return range.parse::<IpAddr>().is_ok_and(|network| network == ip);
+    };
+    let (Ok(network), Ok(prefix)) = (network.parse::<IpAddr>(), prefix.parse::<u32>()) else {
+        return false;
+    };
+
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) if prefix <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) if prefix <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BROWSER: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.0 Safari/605.1.15";
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().expect("Failed to parse ip"))
+    }
+
+    #[test]
+    fn matches_ipv4_ranges() {
+        let ip = "17.253.4.1".parse().unwrap();
+
+        assert!(in_range(ip, "17.0.0.0/8"));
+        assert!(in_range(ip, "17.253.4.0/24"));
+        assert!(in_range(ip, "17.253.4.1/32"));
+        assert!(in_range(ip, "17.253.4.1"));
+        assert!(in_range(ip, "0.0.0.0/0"));
+        assert!(!in_range(ip, "17.253.5.0/24"));
+        assert!(!in_range(ip, "18.0.0.0/8"));
+        assert!(!in_range(ip, "17.0.0.0/33"));
+        assert!(!in_range(ip, "17.0.0.0/x"));
+    }
+
+    #[test]
+    fn matches_ipv6_ranges() {
+        let ip = "2a02:26f7:b3c0:4000::1".parse().unwrap();
+
+        assert!(in_range(ip, "2a02:26f7::/32"));
+        assert!(in_range(ip, "2a02:26f7:b3c0:4000::/64"));
+        assert!(in_range(ip, "::/0"));
+        assert!(!in_range(ip, "2a02:26f8::/32"));
+        assert!(!in_range(ip, "2a02:26f7::/129"));
+        // Families never match each other
+        assert!(!in_range(ip, "0.0.0.0/0"));
+        assert!(!in_range("17.0.0.1".parse().unwrap(), "::/0"));
+    }
+
+    #[test]
+    fn classifies_privacy_proxies() {
+        assert_eq!(
+            classify(Some("Mozilla/5.0"), ip("203.0.113.9"), None, 0),
+            Some(MachineReason::PrivacyProxy)
+        );
+        assert_eq!(
+            classify(Some(BROWSER), ip("17.58.0.1"), None, 0),
+            Some(MachineReason::PrivacyProxy)
+        );
+    }
+
+    #[test]
+    fn classifies_scanners() {
+        assert_eq!(
+            classify(None, ip("203.0.113.9"), None, 0),
+            Some(MachineReason::ScannerUserAgent)
+        );
+        assert_eq!(
+            classify(Some(" "), None, None, 0),
+            Some(MachineReason::ScannerUserAgent)
+        );
+        assert_eq!(
+            classify(Some("Barracuda Sentinel Scanner"), None, None, 0),
+            Some(MachineReason::ScannerUserAgent)
+        );
+        assert_eq!(classify(Some(BROWSER), ip("203.0.113.9"), None, 0), None);
+    }
+
+    #[test]
+    fn classifies_hits_during_delivery() {
+        assert_eq!(
+            classify(Some(BROWSER), None, Some(0), 0),
+            Some(MachineReason::SoonAfterSend)
+        );
+        assert_eq!(
+            classify(Some(BROWSER), None, Some(SEND_WINDOW_SECS), 0),
+            None
+        );
+        // Hits before the campaign was sent come from test emails
+        assert_eq!(classify(Some(BROWSER), None, Some(-5), 0), None);
+
+        // Delivering 60000 emails takes minutes
+        assert_eq!(send_window_secs(60_000), SEND_WINDOW_SECS + 600);
+        assert_eq!(
+            classify(Some(BROWSER), None, Some(300), 60_000),
+            Some(MachineReason::SoonAfterSend)
+        );
+        assert_eq!(classify(Some(BROWSER), None, Some(700), 60_000), None);
+        assert_eq!(send_window_secs(-1), SEND_WINDOW_SECS);
+    }
+
+    #[test]
+    fn classifies_image_loads() {
+        // Mail Privacy Protection fetches every image as soon as the email
+        // arrives, which is reported as the proxy rather than the delivery
+        assert_eq!(
+            classify(Some("Mozilla/5.0"), ip("17.58.0.1"), Some(1), 0),
+            Some(MachineReason::PrivacyProxy)
+        );
+        // Gmail only fetches images through its proxy once the member opens
+        // the email, so those loads are the member's
+        assert_eq!(
+            classify(
+                Some("Mozilla/5.0 (Windows NT 5.1; rv:11.0) Gecko Firefox/11.0 (via ggpht.com GoogleImageProxy)"),
+                ip("66.249.84.1"),
+                Some(3600),
+                0
+            ),
+            None
+        );
+    }
+}
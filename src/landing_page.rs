@@ -0,0 +1,290 @@
+use crate::{preview::escape, video_link::VideoLink};
+
+/// Size of the player, and of the page when it is embedded through oEmbed
+const WIDTH: u32 = 640;
+const HEIGHT: u32 = 360;
+
+/// How a video is played on the landing page
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Player {
+    /// The player of a hosted video, e.g. `https://player.vimeo.com/video/1`
+    Embed(url::Url),
+    /// A video file played by the browser
+    File(url::Url),
+    /// A page the player can not be embedded from, linked to instead
+    Link(url::Url),
+}
+
+impl Player {
+    pub fn from_url(url: &url::Url) -> Self {
+        let start = VideoLink::start_secs(url);
+
+        match VideoLink::parse(url) {
+            Some(video @ VideoLink::File(_)) => Player::File(video.canonical(start)),
+            Some(video) => video
+                .embed_url(start)
+                .map(Player::Embed)
+                .unwrap_or_else(|| Player::Link(url.clone())),
+            None => Player::Link(url.clone()),
+        }
+    }
+
+    fn html(&self, title: &str, poster: Option<&str>) -> String {
+        match self {
+            Player::Embed(url) => format!(
+                r#"<iframe src="{}" width="{WIDTH}" height="{HEIGHT}" title="{}" allow="autoplay; fullscreen; picture-in-picture" allowfullscreen></iframe>"#,
+                escape(url.as_str()),
+                escape(title)
+            ),
+            Player::File(url) => format!(
+                r#"<video src="{}"{} width="{WIDTH}" height="{HEIGHT}" controls playsinline preload="metadata"></video>"#,
+                escape(url.as_str()),
+                poster
+                    .map(|poster| format!(r#" poster="{}""#, escape(poster)))
+                    .unwrap_or_default()
+            ),
+            Player::Link(url) => format!(
+                r#"<a class="watch" href="{}">Watch the video</a>"#,
+                escape(url.as_str())
+            ),
+        }
+    }
+}
+
+/// The button under the video
+#[derive(Debug, Clone)]
+pub struct Cta {
+    pub label: String,
+    pub url: String,
+}
+
+/// The page a member lands on from their video link
+#[derive(Debug, Clone)]
+pub struct LandingPage {
+    pub title: String,
+    /// The full name of the member, `None` if it is unknown
+    pub name: Option<String>,
+    pub video: url::Url,
+    /// The member's thumbnail, shown when the page is shared
+    pub image: Option<String>,
+    pub cta: Option<Cta>,
+    /// Where the page is served, the member's signed link
+    pub page_url: url::Url,
+    pub oembed_url: url::Url,
+}
+
+impl LandingPage {
+    pub fn html(&self) -> String {
+        let player = Player::from_url(&self.video);
+        let greeting = match &self.name {
+            Some(name) => format!("Hi {name}, this video is for you"),
+            None => "This video is for you".into(),
+        };
+        let cta = self
+            .cta
+            .as_ref()
+            .map(|cta| {
+                format!(
+                    r#"<a class="cta" href="{}">{}</a>"#,
+                    escape(&cta.url),
+                    escape(&cta.label)
+                )
+            })
+            .unwrap_or_default();
+
+        include_str!("watch.html")
+            .replace("{META}", &self.meta(&player, &greeting))
+            .replace("{TITLE}", &escape(&self.title))
+            .replace("{GREETING}", &escape(&greeting))
+            .replace("{PLAYER}", &player.html(&self.title, self.image.as_deref()))
+            .replace("{CTA}", &cta)
+    }
+
+    /// The oEmbed response for the page, which embeds the page itself so the
+    /// member's view is still recorded
+    pub fn oembed(&self, max_width: Option<u32>) -> serde_json::Value {
+        let width = max_width.unwrap_or(WIDTH).min(WIDTH);
+        let height = width * HEIGHT / WIDTH;
+
+        let mut oembed = serde_json::json!({
+            "version": "1.0",
+            "type": "video",
+            "title": self.title,
+            "provider_name": self.page_url.host_str(),
+            "width": width,
+            "height": height,
+            "html": format!(
+                r#"<iframe src="{}" width="{width}" height="{height}" title="{}" frameborder="0" allowfullscreen></iframe>"#,
+                escape(self.page_url.as_str()),
+                escape(&self.title)
+            ),
+        });
+        if let Some(image) = &self.image {
+            oembed["thumbnail_url"] = image.as_str().into();
+        }
+
+        oembed
+    }
+
+    /// Open Graph, Twitter card and oEmbed discovery tags, so shared links
+    /// preview with the video
+    fn meta(&self, player: &Player, description: &str) -> String {
+        let mut oembed_url = self.oembed_url.clone();
+        oembed_url
+            .query_pairs_mut()
+            .append_pair("url", self.page_url.as_str())
+            .append_pair("format", "json");
+
+        let mut tags = vec![
+            property("og:type", "video.other"),
+            property("og:title", &self.title),
+            property("og:description", description),
+            property("og:url", self.page_url.as_str()),
+        ];
+        if let Some(image) = &self.image {
+            tags.push(property("og:image", image));
+            tags.push(name("twitter:image", image));
+        }
+        match player {
+            Player::Embed(url) => {
+                tags.push(property("og:video", url.as_str()));
+                tags.push(property("og:video:type", "text/html"));
+                tags.push(name("twitter:card", "player"));
+                tags.push(name("twitter:player", url.as_str()));
+                tags.push(name("twitter:player:width", &WIDTH.to_string()));
+                tags.push(name("twitter:player:height", &HEIGHT.to_string()));
+            }
+            Player::File(url) => {
+                tags.push(property("og:video", url.as_str()));
+                tags.push(name("twitter:card", "summary_large_image"));
+            }
+            Player::Link(_) => tags.push(name("twitter:card", "summary_large_image")),
+        }
+        if matches!(player, Player::Embed(_) | Player::File(_)) {
+            tags.push(property("og:video:width", &WIDTH.to_string()));
+            tags.push(property("og:video:height", &HEIGHT.to_string()));
+        }
+        tags.push(format!(
+            r#"<link rel="alternate" type="application/json+oembed" href="{}" title="{}">"#,
+            escape(oembed_url.as_str()),
+            escape(&self.title)
+        ));
+
+        tags.join("\n        ")
+    }
+}
+
+fn property(property: &str, content: &str) -> String {
+    format!(
+        r#"<meta property="{property}" content="{}">"#,
+        escape(content)
+    )
+}
+
+fn name(name: &str, content: &str) -> String {
+    format!(r#"<meta name="{name}" content="{}">"#, escape(content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> url::Url {
+        url::Url::parse(url).expect("Failed to parse url")
+    }
+
+    fn page() -> LandingPage {
+        LandingPage {
+            title: "Spring & Summer".into(),
+            name: Some("<b>Ann</b>".into()),
+            video: url("https://vimeo.com/76979871"),
+            image: Some("https://cdn.example.com/ann.jpg".into()),
+            cta: Some(Cta {
+                label: "Book a \"call\"".into(),
+                url: "https://example.com/book?a=1&b=2".into(),
+            }),
+            page_url: url("https://worker.example.com/watch/token"),
+            oembed_url: url("https://worker.example.com/oembed"),
+        }
+    }
+
+    #[test]
+    fn embeds_the_players_of_providers() {
+        let player = |link: &str| Player::from_url(&url(link));
+
+        assert_eq!(
+            player("https://vimeo.com/76979871"),
+            Player::Embed(url("https://player.vimeo.com/video/76979871"))
+        );
+        assert_eq!(
+            player("https://youtu.be/dQw4w9WgXcQ"),
+            Player::Embed(url("https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ"))
+        );
+        assert_eq!(
+            player("https://www.youtube.com/watch?v=dQw4w9WgXcQ"),
+            Player::Embed(url("https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ"))
+        );
+        assert_eq!(
+            player("https://cdn.example.com/intro.mp4"),
+            Player::File(url("https://cdn.example.com/intro.mp4"))
+        );
+        assert_eq!(
+            player("https://example.com/videos/intro"),
+            Player::Link(url("https://example.com/videos/intro"))
+        );
+    }
+
+    #[test]
+    fn escapes_the_member_and_cta() {
+        let html = page().html();
+
+        assert!(html.contains("Hi &lt;b&gt;Ann&lt;/b&gt;, this video is for you"));
+        assert!(!html.contains("<b>Ann</b>"));
+        assert!(html.contains(
+            r#"<a class="cta" href="https://example.com/book?a=1&amp;b=2">Book a &quot;call&quot;</a>"#
+        ));
+        assert!(html.contains("<title>Spring &amp; Summer</title>"));
+        assert!(html.contains(r#"<iframe src="https://player.vimeo.com/video/76979871""#));
+
+        let anonymous = LandingPage {
+            name: None,
+            cta: None,
+            ..page()
+        }
+        .html();
+        assert!(anonymous.contains("This video is for you"));
+        assert!(!anonymous.contains(r#"class="cta""#));
+    }
+
+    #[test]
+    fn describes_the_page_for_previews() {
+        let html = page().html();
+
+        for tag in [
+            r#"<meta property="og:title" content="Spring &amp; Summer">"#,
+            r#"<meta property="og:url" content="https://worker.example.com/watch/token">"#,
+            r#"<meta property="og:image" content="https://cdn.example.com/ann.jpg">"#,
+            r#"<meta property="og:video" content="https://player.vimeo.com/video/76979871">"#,
+            r#"<meta name="twitter:card" content="player">"#,
+            r#"<link rel="alternate" type="application/json+oembed" href="https://worker.example.com/oembed?url=https%3A%2F%2Fworker.example.com%2Fwatch%2Ftoken&amp;format=json" title="Spring &amp; Summer">"#,
+        ] {
+            assert!(html.contains(tag), "{tag}");
+        }
+
+        let oembed = page().oembed(Some(320));
+        assert_eq!(oembed["type"], "video");
+        assert_eq!(oembed["provider_name"], "worker.example.com");
+        assert_eq!(
+            (oembed["width"].as_u64(), oembed["height"].as_u64()),
+            (Some(320), Some(180))
+        );
+        assert_eq!(oembed["thumbnail_url"], "https://cdn.example.com/ann.jpg");
+        assert_eq!(
+            oembed["html"],
+            r#"<iframe src="https://worker.example.com/watch/token" width="320" height="180" title="Spring &amp; Summer" frameborder="0" allowfullscreen></iframe>"#
+        );
+        // The page is never embedded larger than its player
+        assert_eq!(page().oembed(Some(2000))["width"], WIDTH);
+        assert_eq!(page().oembed(None)["height"], HEIGHT);
+    }
+}
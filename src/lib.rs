@@ -1,12 +1,44 @@
+mod bitmap_font;
+mod content_lint;
+mod csv_import;
+mod hit_filter;
+mod landing_page;
+mod link_signing;
 mod mailchimp;
+mod merge_tag;
+mod preview;
+mod qr_code;
+mod routing;
 mod session;
+mod snippet;
+mod thumbnail;
+mod url_template;
+mod video_link;
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
-use mailchimp::campaign::MailChimpCampaigns;
-use session::Session;
+use csv_import::CsvImport;
+use link_signing::LinkError;
+use mailchimp::{
+    campaign::{CampaignContent, MailChimpCampaigns},
+    webhook::WebhookEvent,
+};
+use routing::RoutingRule;
+use session::{CampaignField, LandingSettings, Personalization, Session, SnippetParams, Tracking};
 use worker::{Method, Request, Response};
 
+/// Headers that keep mail clients, browsers and proxies from caching a
+/// response, so that every load of it reaches the worker
+const UNCACHED_HEADERS: [(&str, &str); 3] = [
+    (
+        "Cache-Control",
+        "no-cache, no-store, must-revalidate, private, max-age=0",
+    ),
+    // For http/1.0 caches, which do not know Cache-Control
+    ("Pragma", "no-cache"),
+    ("Expires", "0"),
+];
+
 #[worker::event(fetch)]
 async fn main(req: Request, env: worker::Env, _ctx: worker::Context) -> worker::Result<Response> {
     #[cfg(feature = "console_error_panic_hook")]
@@ -50,10 +82,10 @@ async fn main(req: Request, env: worker::Env, _ctx: worker::Context) -> worker::
             }
         })
         .get_async("/lists", |req, ctx| async move {
-            let session_id = req
-                .headers()
-                .get("session-id")?
-                .expect("Each request must embed the auth code");
+            let session_id = match session_id(&req)? {
+                Ok(session_id) => session_id,
+                Err(resp) => return Ok(resp),
+            };
 
             let session = Session::try_from(&ctx.env)?;
             let token = session.access_token(session_id).await?;
@@ -61,10 +93,10 @@ async fn main(req: Request, env: worker::Env, _ctx: worker::Context) -> worker::
             token.fetch("lists", [], Method::Get, None).await
         })
         .get_async("/campaigns", |req, ctx| async move {
-            let session_id = req
-                .headers()
-                .get("session-id")?
-                .expect("Each request must embed the auth code");
+            let session_id = match session_id(&req)? {
+                Ok(session_id) => session_id,
+                Err(resp) => return Ok(resp),
+            };
 
             let session = Session::try_from(&ctx.env)?;
             let token = session.access_token(session_id).await?;
@@ -84,12 +116,7 @@ async fn main(req: Request, env: worker::Env, _ctx: worker::Context) -> worker::
             let campaigns = campaigns
                 .into_iter()
                 .map(|campaign| {
-                    let merge_tags = existing_campaigns.get(&campaign.id).map(|tags| {
-                        serde_json::json!({
-                            "video_tag": tags.0,
-                            "image_tag": tags.1,
-                        })
-                    });
+                    let merge_tags = existing_campaigns.get(&campaign.id);
                     serde_json::json!({
                         "id": campaign.id,
                         "list_id": campaign.recipients.list_id,
@@ -107,10 +134,10 @@ async fn main(req: Request, env: worker::Env, _ctx: worker::Context) -> worker::
             let Some(list_id) = ctx.param("list_id") else {
                 return Response::error("Missing list id", 400);
             };
-            let session_id = req
-                .headers()
-                .get("session-id")?
-                .expect("Each request must embed the auth code");
+            let session_id = match session_id(&req)? {
+                Ok(session_id) => session_id,
+                Err(resp) => return Ok(resp),
+            };
 
             let session = Session::try_from(&ctx.env)?;
             let token = session.access_token(session_id).await?;
@@ -124,8 +151,18 @@ async fn main(req: Request, env: worker::Env, _ctx: worker::Context) -> worker::
                 )
                 .await
         })
-        .get_async(Session::WEBHOOK_CALLBACK, |_req, _ctx| async move {
-            Response::ok("Hello")
+        .get_async(Session::WEBHOOK_CALLBACK, |req, ctx| async move {
+            let Some(secret) = webhook_secret(&req)? else {
+                return Response::error("Webhook call is missing the secret", 401);
+            };
+
+            let session = Session::try_from(&ctx.env)?;
+
+            if session.verify_webhook_secret(None, &secret).await? {
+                Response::ok("Hello")
+            } else {
+                Response::error("Invalid webhook secret", 401)
+            }
         })
         .post_async(
             "/populate_merge_fields/:campaign_id",
@@ -133,10 +170,10 @@ async fn main(req: Request, env: worker::Env, _ctx: worker::Context) -> worker::
                 let Some(campaign_id) = ctx.param("campaign_id") else {
                     return Response::error("Missing list id", 400);
                 };
-                let session_id = req
-                    .headers()
-                    .get("session-id")?
-                    .expect("Each request must embed the auth code");
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
 
                 let session = Session::try_from(&ctx.env)?;
 
@@ -145,47 +182,735 @@ async fn main(req: Request, env: worker::Env, _ctx: worker::Context) -> worker::
                     .await
             },
         )
-        .post_async(Session::WEBHOOK_CALLBACK, |mut req, ctx| async move {
-            let req = req.bytes().await?;
-            let data: Vec<_> = form_urlencoded::parse(&req).collect();
-            let data: HashMap<_, _> = data.iter().map(|(key, value)| (&**key, &**value)).collect();
-
-            let Some(email_id) = data.get("data[email]") else {
-                return Response::error("Webhook call is missing data[email]", 400);
+        .get_async("/merge_fields", |req, ctx| async move {
+            let session_id = match session_id(&req)? {
+                Ok(session_id) => session_id,
+                Err(resp) => return Ok(resp),
             };
-            let Some(list_id) = data.get("data[list_id]") else {
-                return Response::error("Webhook call is missing data[list_id]", 400);
+
+            let session = Session::try_from(&ctx.env)?;
+
+            session.merge_field_usage(&session_id).await
+        })
+        .post_async("/merge_fields/cleanup", |req, ctx| async move {
+            let session_id = match session_id(&req)? {
+                Ok(session_id) => session_id,
+                Err(resp) => return Ok(resp),
             };
-            let Some(fname) = data.get("data[merges][FNAME]") else {
-                return Response::error("Webhook call is missing data[merges][FNAME]", 400);
+            let delete_free_slots = req
+                .url()?
+                .query_pairs()
+                .any(|(key, value)| key == "delete_free_slots" && value == "true");
+
+            let session = Session::try_from(&ctx.env)?;
+
+            session
+                .cleanup_merge_fields(&session_id, delete_free_slots)
+                .await
+        })
+        .get_async(
+            "/campaigns/:campaign_id/personalizations",
+            |req, ctx| async move {
+                let Some(campaign_id) = ctx.param("campaign_id") else {
+                    return Response::error("Missing campaign id", 400);
+                };
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
+
+                let session = Session::try_from(&ctx.env)?;
+
+                session.personalizations(&session_id, campaign_id).await
+            },
+        )
+        .put_async(
+            "/campaigns/:campaign_id/personalizations",
+            |mut req, ctx| async move {
+                let Some(campaign_id) = ctx.param("campaign_id") else {
+                    return Response::error("Missing campaign id", 400);
+                };
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
+
+                #[derive(serde::Deserialize)]
+                struct Body {
+                    personalizations: Vec<Personalization>,
+                }
+                let body: Body = req.json().await?;
+
+                let session = Session::try_from(&ctx.env)?;
+
+                session
+                    .set_personalizations(&session_id, campaign_id, body.personalizations)
+                    .await
+            },
+        )
+        .put_async(
+            "/campaigns/:campaign_id/personalizations/:email",
+            |mut req, ctx| async move {
+                let Some(campaign_id) = ctx.param("campaign_id") else {
+                    return Response::error("Missing campaign id", 400);
+                };
+                let Some(email) = member_email(ctx.param("email")) else {
+                    return Response::error("Missing or invalid member email", 400);
+                };
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
+
+                let values: BTreeMap<String, String> = req.json().await?;
+
+                let session = Session::try_from(&ctx.env)?;
+
+                session
+                    .set_personalizations(
+                        &session_id,
+                        campaign_id,
+                        vec![Personalization { email, values }],
+                    )
+                    .await
+            },
+        )
+        // Imports personalizations from a csv. The csv is read whole rather than
+        // streamed, so it is capped at `CsvImport::MAX_BYTES`.
+        .post_async(
+            "/campaigns/:campaign_id/personalizations.csv",
+            |mut req, ctx| async move {
+                let Some(campaign_id) = ctx.param("campaign_id") else {
+                    return Response::error("Missing campaign id", 400);
+                };
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
+                let declared_len = req
+                    .headers()
+                    .get("content-length")?
+                    .and_then(|len| len.parse::<usize>().ok());
+                if declared_len.is_some_and(|len| len > CsvImport::MAX_BYTES) {
+                    return Response::error("The csv is larger than 5 MiB", 413);
+                }
+                let body = req.bytes().await?;
+                if body.len() > CsvImport::MAX_BYTES {
+                    return Response::error("The csv is larger than 5 MiB", 413);
+                }
+
+                let session = Session::try_from(&ctx.env)?;
+
+                session
+                    .import_personalizations_csv(&session_id, campaign_id, &body)
+                    .await
+            },
+        )
+        .get_async("/campaigns/:campaign_id/fields", |req, ctx| async move {
+            let Some(campaign_id) = ctx.param("campaign_id") else {
+                return Response::error("Missing campaign id", 400);
             };
-            let Some(lname) = data.get("data[merges][LNAME]") else {
-                return Response::error("Webhook call is missing data[merges][LNAME]", 400);
+            let session_id = match session_id(&req)? {
+                Ok(session_id) => session_id,
+                Err(resp) => return Ok(resp),
             };
 
             let session = Session::try_from(&ctx.env)?;
-            let token = session.access_token_from_list_id(*list_id).await?;
 
-            match data.get("type") {
-                // A new member subscribed
-                Some(&"subscribe") => {
-                    session
-                        .subscribe_member(&token, *email_id, format!("{fname} {lname}"), *list_id)
-                        .await?;
+            session.fields(&session_id, campaign_id).await
+        })
+        .put_async(
+            "/campaigns/:campaign_id/fields",
+            |mut req, ctx| async move {
+                let Some(campaign_id) = ctx.param("campaign_id") else {
+                    return Response::error("Missing campaign id", 400);
+                };
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
 
-                    Response::ok("added")
+                #[derive(serde::Deserialize)]
+                struct Body {
+                    fields: Vec<CampaignField>,
                 }
-                // A member's data has changed
-                Some(&"profile") => {
-                    session
-                        .update_member(&token, *email_id, &format!("{fname} {lname}"), *list_id)
-                        .await?;
+                let body: Body = req.json().await?;
+
+                let session = Session::try_from(&ctx.env)?;
 
-                    Response::ok("updated")
+                session
+                    .set_fields(&session_id, campaign_id, body.fields)
+                    .await
+            },
+        )
+        .get_async("/campaigns/:campaign_id/content", |req, ctx| async move {
+            let Some(campaign_id) = ctx.param("campaign_id") else {
+                return Response::error("Missing campaign id", 400);
+            };
+            let session_id = match session_id(&req)? {
+                Ok(session_id) => session_id,
+                Err(resp) => return Ok(resp),
+            };
+
+            let session = Session::try_from(&ctx.env)?;
+
+            session.campaign_content(&session_id, campaign_id).await
+        })
+        .put_async(
+            "/campaigns/:campaign_id/content",
+            |mut req, ctx| async move {
+                let Some(campaign_id) = ctx.param("campaign_id") else {
+                    return Response::error("Missing campaign id", 400);
+                };
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
+                let content: CampaignContent = req.json().await?;
+
+                let session = Session::try_from(&ctx.env)?;
+
+                session
+                    .set_campaign_content(&session_id, campaign_id, content)
+                    .await
+            },
+        )
+        // Reports the merge tags of the campaign's content that are missing or misused
+        .get_async(
+            "/campaigns/:campaign_id/content/lint",
+            |req, ctx| async move {
+                let Some(campaign_id) = ctx.param("campaign_id") else {
+                    return Response::error("Missing campaign id", 400);
+                };
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
+
+                let session = Session::try_from(&ctx.env)?;
+
+                session.lint_content(&session_id, campaign_id).await
+            },
+        )
+        // Renders the campaign's content the way a member gets it
+        .get_async(
+            "/campaigns/:campaign_id/preview/:email",
+            |req, ctx| async move {
+                let Some(campaign_id) = ctx.param("campaign_id") else {
+                    return Response::error("Missing campaign id", 400);
+                };
+                let Some(email) = member_email(ctx.param("email")) else {
+                    return Response::error("Missing or invalid member email", 400);
+                };
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
+
+                let session = Session::try_from(&ctx.env)?;
+
+                session.preview(&session_id, campaign_id, &email).await
+            },
+        )
+        .get_async(
+            "/campaigns/:campaign_id/members/:email/:file",
+            |req, ctx| async move {
+                let Some(campaign_id) = ctx.param("campaign_id") else {
+                    return Response::error("Missing campaign id", 400);
+                };
+                let Some(email) = member_email(ctx.param("email")) else {
+                    return Response::error("Missing or invalid member email", 400);
+                };
+                let Some(file) = ctx.param("file") else {
+                    return Response::error("Missing file", 400);
+                };
+                let (name, format) = qr_code::Format::from_file_name(file);
+                if name != "qr" || !file.contains('.') {
+                    return Response::error("Unknown file, only qr.png and qr.svg exist", 404);
                 }
-                _ => Response::error("Unsupported type of webhook call", 400),
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
+                let field = req
+                    .url()?
+                    .query_pairs()
+                    .find(|(key, _)| key == "field")
+                    .map(|(_, field)| field.into_owned());
+
+                let session = Session::try_from(&ctx.env)?;
+
+                session
+                    .member_qr_code(&session_id, campaign_id, &email, field.as_deref(), format)
+                    .await
+            },
+        )
+        // Records the click of a member on a tracked link and sends them on to its url
+        .get_async("/v/:token", |req, ctx| async move {
+            let Some(token) = ctx.param("token") else {
+                return Response::error("Missing link", 400);
+            };
+            let user_agent = req.headers().get("user-agent")?;
+            let ip = req
+                .headers()
+                .get("cf-connecting-ip")?
+                .and_then(|ip| ip.parse().ok());
+            let country = req.cf().country();
+            let accept_language = req.headers().get("accept-language")?;
+
+            let session = Session::try_from(&ctx.env)?;
+
+            match session
+                .follow_link(
+                    token,
+                    Tracking::Click,
+                    user_agent,
+                    ip,
+                    country,
+                    accept_language,
+                )
+                .await?
+            {
+                Ok(url) => Response::redirect(url),
+                Err(err) => link_error(err),
+            }
+        })
+        // Records the view of a member and serves their landing page
+        .get_async("/watch/:token", |req, ctx| async move {
+            let Some(token) = ctx.param("token") else {
+                return Response::error("Missing link", 400);
+            };
+            let user_agent = req.headers().get("user-agent")?;
+            let ip = req
+                .headers()
+                .get("cf-connecting-ip")?
+                .and_then(|ip| ip.parse().ok());
+            let country = req.cf().country();
+            let accept_language = req.headers().get("accept-language")?;
+
+            let session = Session::try_from(&ctx.env)?;
+
+            match session
+                .watch_page(token, user_agent, ip, country, accept_language)
+                .await?
+            {
+                Ok(html) => Response::from_html(html),
+                Err(err) => link_error(err),
+            }
+        })
+        .get_async(Session::OEMBED_PATH, |req, ctx| async move {
+            let url = req.url()?;
+            let Some(page_url) = url
+                .query_pairs()
+                .find(|(key, _)| key == "url")
+                .and_then(|(_, page_url)| url::Url::parse(&page_url).ok())
+            else {
+                return Response::error("Missing url query param", 400);
+            };
+            if url
+                .query_pairs()
+                .any(|(key, format)| key == "format" && format != "json")
+            {
+                return Response::error("Only the json format is supported", 501);
+            }
+            let max_width = url
+                .query_pairs()
+                .find(|(key, _)| key == "maxwidth")
+                .and_then(|(_, max_width)| max_width.parse().ok());
+
+            let session = Session::try_from(&ctx.env)?;
+
+            match session.oembed(&page_url, max_width).await? {
+                Ok(oembed) => Response::from_json(&oembed),
+                Err(err) => Response::error(err.message(), err.status()),
             }
         })
+        .get_async("/campaigns/:campaign_id/landing", |req, ctx| async move {
+            let Some(campaign_id) = ctx.param("campaign_id") else {
+                return Response::error("Missing campaign id", 400);
+            };
+            let session_id = match session_id(&req)? {
+                Ok(session_id) => session_id,
+                Err(resp) => return Ok(resp),
+            };
+
+            let session = Session::try_from(&ctx.env)?;
+
+            session.landing_settings(&session_id, campaign_id).await
+        })
+        .put_async(
+            "/campaigns/:campaign_id/landing",
+            |mut req, ctx| async move {
+                let Some(campaign_id) = ctx.param("campaign_id") else {
+                    return Response::error("Missing campaign id", 400);
+                };
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
+
+                let settings: LandingSettings = req.json().await?;
+
+                let session = Session::try_from(&ctx.env)?;
+
+                session
+                    .set_landing_settings(&session_id, campaign_id, settings)
+                    .await
+            },
+        )
+        .get_async("/campaigns/:campaign_id/routing", |req, ctx| async move {
+            let Some(campaign_id) = ctx.param("campaign_id") else {
+                return Response::error("Missing campaign id", 400);
+            };
+            let session_id = match session_id(&req)? {
+                Ok(session_id) => session_id,
+                Err(resp) => return Ok(resp),
+            };
+
+            let session = Session::try_from(&ctx.env)?;
+
+            session.routing_rules(&session_id, campaign_id).await
+        })
+        .put_async(
+            "/campaigns/:campaign_id/routing",
+            |mut req, ctx| async move {
+                let Some(campaign_id) = ctx.param("campaign_id") else {
+                    return Response::error("Missing campaign id", 400);
+                };
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
+
+                let rules: Vec<RoutingRule> = req.json().await?;
+
+                let session = Session::try_from(&ctx.env)?;
+
+                session
+                    .set_routing_rules(&session_id, campaign_id, rules)
+                    .await
+            },
+        )
+        // Records the load of a member's image and redirects to it. The redirect
+        // is never cached, so every load of the image reaches the worker.
+        .get_async("/i/:token", |req, ctx| async move {
+            let Some(token) = ctx.param("token") else {
+                return Response::error("Missing image", 400);
+            };
+            let user_agent = req.headers().get("user-agent")?;
+            let ip = req
+                .headers()
+                .get("cf-connecting-ip")?
+                .and_then(|ip| ip.parse().ok());
+            let country = req.cf().country();
+
+            let session = Session::try_from(&ctx.env)?;
+
+            let url = match session
+                .follow_link(token, Tracking::Impression, user_agent, ip, country, None)
+                .await?
+            {
+                Ok(url) => url,
+                Err(err) => return Response::error(err.message(), err.status()),
+            };
+
+            let mut resp = Response::redirect(url)?;
+            for (name, value) in UNCACHED_HEADERS {
+                resp.headers_mut().set(name, value)?;
+            }
+
+            Ok(resp)
+        })
+        // Serves a member's image with its overlay. The rendering only changes
+        // with the member's link, so it is cached for as long as the link lives.
+        .get_async("/thumbnail/:file", |_req, ctx| async move {
+            let Some(file) = ctx.param("file") else {
+                return Response::error("Missing image", 400);
+            };
+            let (token, format) = thumbnail::Format::from_file_name(file);
+
+            let session = Session::try_from(&ctx.env)?;
+
+            let image = match session.thumbnail(token, format).await? {
+                Ok(image) => image,
+                Err(err) => return Response::error(err.message(), err.status()),
+            };
+
+            let mut resp = Response::from_bytes(image)?;
+            let headers = resp.headers_mut();
+            headers.set("Content-Type", format.content_type())?;
+            headers.set("Cache-Control", "public, max-age=604800, immutable")?;
+
+            Ok(resp)
+        })
+        // Serves the QR code of a member's link, which is as lasting as the link
+        .get_async("/qr/:file", |_req, ctx| async move {
+            let Some(file) = ctx.param("file") else {
+                return Response::error("Missing QR code", 400);
+            };
+            let (token, format) = qr_code::Format::from_file_name(file);
+
+            let session = Session::try_from(&ctx.env)?;
+
+            let image = match session.qr_code(token, format).await? {
+                Ok(image) => image,
+                Err(err) => return Response::error(err.message(), err.status()),
+            };
+
+            let mut resp = Response::from_bytes(image)?;
+            let headers = resp.headers_mut();
+            headers.set("Content-Type", format.content_type())?;
+            headers.set("Cache-Control", "public, max-age=604800, immutable")?;
+
+            Ok(resp)
+        })
+        .get_async("/campaigns/:campaign_id/views", |req, ctx| async move {
+            let Some(campaign_id) = ctx.param("campaign_id") else {
+                return Response::error("Missing campaign id", 400);
+            };
+            let session_id = match session_id(&req)? {
+                Ok(session_id) => session_id,
+                Err(resp) => return Ok(resp),
+            };
+
+            let session = Session::try_from(&ctx.env)?;
+
+            session.views(&session_id, campaign_id).await
+        })
+        .get_async(
+            "/campaigns/:campaign_id/views/:email",
+            |req, ctx| async move {
+                let Some(campaign_id) = ctx.param("campaign_id") else {
+                    return Response::error("Missing campaign id", 400);
+                };
+                let Some(email) = member_email(ctx.param("email")) else {
+                    return Response::error("Missing or invalid member email", 400);
+                };
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
+
+                let session = Session::try_from(&ctx.env)?;
+
+                session.member_views(&session_id, campaign_id, &email).await
+            },
+        )
+        // Inserts the linked thumbnail in the campaign's content
+        .post_async(
+            "/campaigns/:campaign_id/content/snippet",
+            |mut req, ctx| async move {
+                let Some(campaign_id) = ctx.param("campaign_id") else {
+                    return Response::error("Missing campaign id", 400);
+                };
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
+                let params: SnippetParams = req.json().await?;
+
+                let session = Session::try_from(&ctx.env)?;
+
+                session
+                    .insert_snippet(&session_id, campaign_id, params)
+                    .await
+            },
+        )
+        .get_async("/campaigns/:campaign_id/coupons", |req, ctx| async move {
+            let Some(campaign_id) = ctx.param("campaign_id") else {
+                return Response::error("Missing campaign id", 400);
+            };
+            let session_id = match session_id(&req)? {
+                Ok(session_id) => session_id,
+                Err(resp) => return Ok(resp),
+            };
+
+            let session = Session::try_from(&ctx.env)?;
+
+            session.coupons(&session_id, campaign_id).await
+        })
+        .post_async(
+            "/campaigns/:campaign_id/coupons/:field",
+            |mut req, ctx| async move {
+                let (Some(campaign_id), Some(field)) =
+                    (ctx.param("campaign_id"), ctx.param("field"))
+                else {
+                    return Response::error("Missing campaign id or field", 400);
+                };
+                let session_id = match session_id(&req)? {
+                    Ok(session_id) => session_id,
+                    Err(resp) => return Ok(resp),
+                };
+                let body = req.text().await?;
+
+                let session = Session::try_from(&ctx.env)?;
+
+                session
+                    .add_coupon_codes(&session_id, campaign_id, field, &body)
+                    .await
+            },
+        )
+        .post_async(Session::WEBHOOK_CALLBACK, |mut req, ctx| async move {
+            let Some(secret) = webhook_secret(&req)? else {
+                return Response::error("Webhook call is missing the secret", 401);
+            };
+            let body = req.bytes().await?;
+
+            let Some(list_id) = WebhookEvent::list_id_of(&body) else {
+                return Response::error("Webhook call is missing data[list_id]", 400);
+            };
+
+            let session = Session::try_from(&ctx.env)?;
+
+            if !session
+                .verify_webhook_secret(Some(&list_id), &secret)
+                .await?
+            {
+                return Response::error("Invalid webhook secret", 401);
+            }
+
+            let record = session.log_webhook_event(&list_id, &body).await?;
+            let Some(record) = session.claim_webhook_event(record.id).await? else {
+                return Response::ok("duplicate");
+            };
+
+            session.run_webhook_event(&record).await
+        })
+        .post_async("/webhooks/check", |req, ctx| async move {
+            let session_id = match session_id(&req)? {
+                Ok(session_id) => session_id,
+                Err(resp) => return Ok(resp),
+            };
+
+            let session = Session::try_from(&ctx.env)?;
+
+            session.check_webhooks(&session_id).await
+        })
+        .get_async("/webhook_events", |req, ctx| async move {
+            let session_id = match session_id(&req)? {
+                Ok(session_id) => session_id,
+                Err(resp) => return Ok(resp),
+            };
+            let status = req
+                .url()?
+                .query_pairs()
+                .find(|(key, _)| key == "status")
+                .map(|(_, status)| status.into_owned());
+
+            let session = Session::try_from(&ctx.env)?;
+            let events = session
+                .webhook_events(session_id, status.as_deref())
+                .await?
+                .into_iter()
+                .map(|event| {
+                    serde_json::json!({
+                        "id": event.id,
+                        "list_id": event.list_id,
+                        "type": event.ty,
+                        "payload": event.payload,
+                        "status": event.status,
+                        "error": event.error,
+                        "received_at": event.received_at,
+                        "processed_at": event.processed_at,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            Response::from_json(&serde_json::json!({
+                "events": events,
+            }))
+        })
+        .post_async("/webhook_events/:event_id/replay", |req, ctx| async move {
+            let Some(Ok(event_id)) = ctx.param("event_id").map(|id| id.parse::<i64>()) else {
+                return Response::error("Missing or invalid event id", 400);
+            };
+            let session_id = match session_id(&req)? {
+                Ok(session_id) => session_id,
+                Err(resp) => return Ok(resp),
+            };
+
+            let session = Session::try_from(&ctx.env)?;
+
+            let Some(record) = session.webhook_event(session_id, event_id).await? else {
+                return Response::error("Webhook event not found", 404);
+            };
+            let Some(record) = session.claim_webhook_event(record.id).await? else {
+                return Response::error("Webhook event is processed already", 409);
+            };
+
+            session.run_webhook_event(&record).await
+        })
         .run(req, env)
         .await
 }
+
+/// Tells members what is wrong with their link
+fn link_error(err: LinkError) -> worker::Result<Response> {
+    Ok(
+        Response::from_html(include_str!("link_error.html").replace("{MESSAGE}", err.message()))?
+            .with_status(err.status()),
+    )
+}
+
+/// The session id each request of the page embeds, or the response to a
+/// request without one
+fn session_id(req: &Request) -> worker::Result<Result<String, Response>> {
+    match req.headers().get("session-id")? {
+        Some(session_id) => Ok(Ok(session_id)),
+        None => Response::error("Each request must embed the session id", 401).map(Err),
+    }
+}
+
+/// Decodes the email of a member in the path, as the page url encodes it
+fn member_email(param: Option<&String>) -> Option<String> {
+    let email = urlencoding::decode(param?).ok()?;
+    Some(email.trim().to_owned()).filter(|email| !email.is_empty())
+}
+
+/// Extracts the secret mailchimp sends back from the registered webhook url
+fn webhook_secret(req: &Request) -> worker::Result<Option<String>> {
+    Ok(req
+        .url()?
+        .query_pairs()
+        .find(|(key, _)| key == "secret")
+        .map(|(_, secret)| secret.into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_member_emails() {
+        let email = |param: &str| member_email(Some(&param.to_owned()));
+
+        // The page sends emails through encodeURIComponent
+        assert_eq!(
+            email("ann%2Bnews%40example.com").as_deref(),
+            Some("ann+news@example.com")
+        );
+        assert_eq!(email("ann@example.com").as_deref(), Some("ann@example.com"));
+        assert_eq!(email("%20"), None);
+        assert_eq!(email("ann%FF%40example.com"), None);
+        assert_eq!(member_email(None), None);
+    }
+
+    #[test]
+    fn keeps_impressions_uncached() {
+        let headers = BTreeMap::from(UNCACHED_HEADERS);
+        let cache_control = headers["Cache-Control"].split(", ").collect::<Vec<_>>();
+
+        // Repeat loads of an image only count when nothing on the way stores it
+        for directive in [
+            "no-cache",
+            "no-store",
+            "must-revalidate",
+            "private",
+            "max-age=0",
+        ] {
+            assert!(cache_control.contains(&directive), "{directive}");
+        }
+        assert_eq!(headers["Pragma"], "no-cache");
+        assert_eq!(headers["Expires"], "0");
+    }
+}
@@ -0,0 +1,257 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// How long a personalized link keeps working after the campaign was populated
+pub const LIFETIME_SECS: i64 = 180 * 24 * 60 * 60;
+
+/// Bytes of the HMAC kept in a link, enough to not be guessed while keeping
+/// the link short
+const SIGNATURE_BYTES: usize = 16;
+
+/// Why a personalized link does not lead anywhere
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkError {
+    /// The link is not one of ours, e.g. cut off when copied
+    Malformed,
+    /// The link was signed, but is no longer known
+    NotFound,
+    /// The link was changed, e.g. to reach another member's video
+    Tampered,
+    Expired,
+}
+
+impl LinkError {
+    pub fn status(&self) -> u16 {
+        match self {
+            LinkError::Malformed | LinkError::NotFound => 404,
+            LinkError::Tampered => 403,
+            LinkError::Expired => 410,
+        }
+    }
+
+    /// What the member is told on the landing page
+    pub fn message(&self) -> &'static str {
+        match self {
+            LinkError::Malformed | LinkError::NotFound => {
+                "This link is incomplete or no longer exists. Please open it again from the email you received."
+            }
+            LinkError::Tampered => {
+                "This link is not valid. Please open it again from the email you received."
+            }
+            LinkError::Expired => {
+                "This link has expired. Please reach out to us and we will gladly send you a new one."
+            }
+        }
+    }
+}
+
+/// A link as `{link id}.{expiry}.{signature}`, where the signature covers the
+/// campaign and member of the link along with its id and expiry
+#[derive(Debug, Clone, Copy)]
+pub struct SignedLink<'a> {
+    pub link_id: &'a str,
+    pub expires_at: i64,
+    signature: &'a str,
+}
+
+impl<'a> SignedLink<'a> {
+    /// Splits a link into its parts, it still has to be verified against its
+    /// campaign and member
+    pub fn parse(token: &'a str) -> Result<Self, LinkError> {
+        let mut parts = token.split('.');
+        let (Some(link_id), Some(expires_at), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(LinkError::Malformed);
+        };
+        let Ok(expires_at) = expires_at.parse() else {
+            return Err(LinkError::Malformed);
+        };
+        if link_id.is_empty() || signature.len() != SIGNATURE_BYTES * 2 {
+            return Err(LinkError::Malformed);
+        }
+
+        Ok(Self {
+            link_id,
+            expires_at,
+            signature,
+        })
+    }
+
+    /// Checks the signature before the expiry, so a changed expiry is reported
+    /// as tampered rather than expired
+    pub fn verify(
+        &self,
+        key: &[u8],
+        campaign_id: &str,
+        email: &str,
+        now: i64,
+    ) -> Result<(), LinkError> {
+        let Some(signature) = decode_hex(self.signature) else {
+            return Err(LinkError::Tampered);
+        };
+        mac(key, campaign_id, email, self.link_id, self.expires_at)
+            .verify_truncated_left(&signature)
+            .map_err(|_| LinkError::Tampered)?;
+
+        if now >= self.expires_at {
+            return Err(LinkError::Expired);
+        }
+
+        Ok(())
+    }
+}
+
+/// The link of a member to put in a merge field, see [`SignedLink`]
+pub fn sign(key: &[u8], campaign_id: &str, email: &str, link_id: &str, expires_at: i64) -> String {
+    let signature = mac(key, campaign_id, email, link_id, expires_at)
+        .finalize()
+        .into_bytes();
+    let signature = signature[..SIGNATURE_BYTES]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+
+    format!("{link_id}.{expires_at}.{signature}")
+}
+
+fn mac(key: &[u8], campaign_id: &str, email: &str, link_id: &str, expires_at: i64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    // Separated so that shifting characters between the parts changes the signature
+    for part in [campaign_id, email, link_id, &expires_at.to_string()] {
+        mac.update(part.as_bytes());
+        mac.update(b"\n");
+    }
+
+    mac
+}
+
+/// `None` for odd lengths as well, as the last pair is cut off
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"test key";
+    const EXPIRES_AT: i64 = 1_700_000_000;
+
+    fn token() -> String {
+        sign(KEY, "c1", "ann@example.com", "l1", EXPIRES_AT)
+    }
+
+    fn verify(token: &str, email: &str, now: i64) -> Result<(), LinkError> {
+        SignedLink::parse(token)?.verify(KEY, "c1", email, now)
+    }
+
+    #[test]
+    fn verifies_signed_links() {
+        let token = token();
+        let signed = SignedLink::parse(&token).expect("Failed to parse token");
+
+        assert_eq!(signed.link_id, "l1");
+        assert_eq!(signed.expires_at, EXPIRES_AT);
+        assert_eq!(verify(&token, "ann@example.com", EXPIRES_AT - 1), Ok(()));
+    }
+
+    #[test]
+    fn rejects_tampered_links() {
+        let token = token();
+        let (_, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let now = EXPIRES_AT - 1;
+
+        // Another link, a later expiry or another member with the same signature
+        assert_eq!(
+            verify(
+                &format!("l2.{EXPIRES_AT}.{signature}"),
+                "ann@example.com",
+                now
+            ),
+            Err(LinkError::Tampered)
+        );
+        assert_eq!(
+            verify(
+                &format!("l1.{}.{signature}", EXPIRES_AT + 1),
+                "ann@example.com",
+                now
+            ),
+            Err(LinkError::Tampered)
+        );
+        assert_eq!(
+            verify(&token, "bob@example.com", now),
+            Err(LinkError::Tampered)
+        );
+        assert_eq!(
+            SignedLink::parse(&token)
+                .unwrap()
+                .verify(b"other key", "c1", "ann@example.com", now),
+            Err(LinkError::Tampered)
+        );
+
+        let mut flipped = token.clone();
+        let last = if token.ends_with('0') { "1" } else { "0" };
+        flipped.replace_range(token.len() - 1.., last);
+        assert_eq!(
+            verify(&flipped, "ann@example.com", now),
+            Err(LinkError::Tampered)
+        );
+        // A signature of the right length that is no hex
+        let not_hex = format!("l1.{EXPIRES_AT}.{}", "zz".repeat(SIGNATURE_BYTES));
+        assert_eq!(
+            verify(&not_hex, "ann@example.com", now),
+            Err(LinkError::Tampered)
+        );
+    }
+
+    #[test]
+    fn rejects_expired_links() {
+        assert_eq!(
+            verify(&token(), "ann@example.com", EXPIRES_AT),
+            Err(LinkError::Expired)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_links() {
+        let signature = "ab".repeat(SIGNATURE_BYTES);
+
+        for token in [
+            "".to_owned(),
+            "l1".to_owned(),
+            format!("l1.{EXPIRES_AT}"),
+            format!(".{EXPIRES_AT}.{signature}"),
+            format!("l1.soon.{signature}"),
+            format!("l1.{EXPIRES_AT}.{signature}.extra"),
+            format!("l1.{EXPIRES_AT}.{}", &signature[1..]),
+        ] {
+            assert_eq!(
+                SignedLink::parse(&token).map(|_| ()),
+                Err(LinkError::Malformed),
+                "{token}"
+            );
+        }
+    }
+
+    #[test]
+    fn does_not_panic_on_odd_input() {
+        // 32 bytes, but multibyte characters do not split into hex pairs
+        let non_ascii = format!("l1.{EXPIRES_AT}.{}", "é".repeat(SIGNATURE_BYTES));
+        assert_eq!(
+            verify(&non_ascii, "ann@example.com", 0),
+            Err(LinkError::Tampered)
+        );
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("éa"), None);
+        assert_eq!(decode_hex("0aff"), Some(vec![0x0a, 0xff]));
+        assert_eq!(
+            verify("l1.99999999999999999999.x", "ann@example.com", 0),
+            Err(LinkError::Malformed)
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use worker::Method;
 
-use super::Token;
+use super::{Paginated, Token};
 
 pub const BASE_URL: &'static str = "campaigns";
 
@@ -17,6 +17,11 @@ pub struct MailChimpSettings {
 #[derive(Debug, serde::Deserialize)]
 pub struct MailChimpCampaign {
     pub id: String,
+    pub status: String,
+    /// How the content was made, `html` for code your own campaigns and
+    /// `template` or `multichannel` for campaigns built from blocks
+    #[serde(default)]
+    pub content_type: String,
     pub recipients: MailChimpRecipients,
     pub settings: MailChimpSettings,
 }
@@ -34,54 +39,122 @@ impl MailChimpCampaign {
             .json()
             .await
     }
+
+    /// Fetches a campaign, `None` if it was deleted
+    pub async fn find(token: &Token, campaign_id: impl AsRef<str>) -> worker::Result<Option<Self>> {
+        let mut resp = token
+            .fetch(
+                format!("{BASE_URL}/{}", campaign_id.as_ref()).as_str(),
+                [],
+                Method::Get,
+                None,
+            )
+            .await?;
+
+        if resp.status_code() == 404 {
+            return Ok(None);
+        }
+
+        resp.json().await.map(Some)
+    }
+
+    pub async fn content(
+        token: &Token,
+        campaign_id: impl AsRef<str>,
+    ) -> worker::Result<CampaignContent> {
+        token
+            .fetch(
+                format!("{BASE_URL}/{}/content", campaign_id.as_ref()).as_str(),
+                [],
+                Method::Get,
+                None,
+            )
+            .await?
+            .json()
+            .await
+    }
+
+    /// Replaces the content of a campaign. Mailchimp refuses to edit campaigns
+    /// that were sent, which is reported as an error.
+    pub async fn set_content(
+        token: &Token,
+        campaign_id: impl AsRef<str>,
+        content: &CampaignContent,
+    ) -> worker::Result<CampaignContent> {
+        #[derive(serde::Deserialize)]
+        struct Problem {
+            detail: String,
+        }
+
+        let body = serde_json::to_string(content)?;
+
+        let mut resp = token
+            .fetch(
+                format!("{BASE_URL}/{}/content", campaign_id.as_ref()).as_str(),
+                [],
+                Method::Put,
+                Some(body.into()),
+            )
+            .await?;
+
+        if !(200..300).contains(&resp.status_code()) {
+            let detail = resp
+                .json::<Problem>()
+                .await
+                .map(|problem| problem.detail)
+                .unwrap_or_default();
+
+            return Err(worker::Error::RustError(format!(
+                "Failed to update the campaign content: {detail}"
+            )));
+        }
+
+        resp.json().await
+    }
 }
 
-#[derive(Debug, serde::Deserialize)]
+/// The html and plain-text versions of a campaign. Mailchimp generates the
+/// plain text from the html when it is left out.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CampaignContent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plain_text: Option<String>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
 pub struct MailChimpCampaigns {
     pub campaigns: Vec<MailChimpCampaign>,
     pub total_items: usize,
 }
 
+impl Paginated for MailChimpCampaigns {
+    fn item_count(&self) -> usize {
+        self.campaigns.len()
+    }
+
+    fn total_items(&self) -> usize {
+        self.total_items
+    }
+
+    fn append(&mut self, page: Self) {
+        self.campaigns.extend(page.campaigns);
+        self.total_items = page.total_items;
+    }
+}
+
 impl MailChimpCampaigns {
     pub async fn get_all(
         token: &Token,
         after_time: Option<impl AsRef<str>>,
     ) -> worker::Result<Self> {
-        let mut campaigns = MailChimpCampaigns {
-            campaigns: Vec::default(),
-            total_items: 0,
-        };
-
-        loop {
-            let resp = token
-                .fetch(
-                    BASE_URL,
-                    after_time
-                        .as_ref()
-                        .map(|t| ("since_create_time", t.as_ref()))
-                        .into_iter()
-                        .chain(
-                            [
-                                ("count", "1000"),
-                                ("offset", campaigns.campaigns.len().to_string().as_str()),
-                            ]
-                            .into_iter(),
-                        ),
-                    Method::Get,
-                    None,
-                )
-                .await?
-                .json::<MailChimpCampaigns>()
-                .await?;
-
-            campaigns.campaigns.extend(resp.campaigns);
-            campaigns.total_items = resp.total_items;
-
-            if campaigns.campaigns.len() == resp.total_items {
-                break;
-            }
-        }
+        let params = after_time
+            .as_ref()
+            .map(|t| ("since_create_time", t.as_ref()))
+            .into_iter()
+            .collect::<Vec<_>>();
 
-        Ok(campaigns)
+        token.fetch_all(BASE_URL, &params).await
     }
 }
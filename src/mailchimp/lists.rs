@@ -1,20 +1,87 @@
+use std::collections::BTreeMap;
+
 use serde_json::Value;
 use worker::Method;
 
-use super::Token;
+use super::{Paginated, Token};
+use crate::url_template::UrlTemplate;
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Member {
     pub email_address: String,
     pub full_name: String,
+    pub status: String,
+    #[serde(default)]
+    pub merge_fields: serde_json::Map<String, Value>,
 }
 
-#[derive(Debug, serde::Deserialize)]
+impl Member {
+    /// Unsubscribed and cleaned members should not be personalized
+    pub fn is_active(&self) -> bool {
+        !matches!(self.status.as_str(), "unsubscribed" | "cleaned")
+    }
+
+    /// The member's merge fields as strings. Nested fields like addresses are
+    /// left out.
+    pub fn merge_values(&self) -> BTreeMap<String, String> {
+        self.merge_fields
+            .iter()
+            .filter_map(|(tag, value)| {
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    Value::Number(value) => value.to_string(),
+                    Value::Bool(value) => value.to_string(),
+                    _ => return None,
+                };
+
+                Some((tag.clone(), value))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
 pub struct Members {
     pub members: Vec<Member>,
     pub total_items: usize,
 }
 
+impl Paginated for Members {
+    fn item_count(&self) -> usize {
+        self.members.len()
+    }
+
+    fn total_items(&self) -> usize {
+        self.total_items
+    }
+
+    fn append(&mut self, page: Self) {
+        self.members.extend(page.members);
+        self.total_items = page.total_items;
+    }
+}
+
+/// The problem details mailchimp answers a failed request with
+#[derive(Debug, Default, serde::Deserialize)]
+struct Problem {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    detail: String,
+    /// The params the request was refused for, if any
+    #[serde(default)]
+    errors: Vec<Value>,
+}
+
+impl Problem {
+    /// Whether mailchimp refused to add a merge field because the list has as
+    /// many as its plan allows. Invalid params are refused with the same status
+    /// and title, but name the params in `errors`.
+    fn is_merge_field_limit(&self, status: u16) -> bool {
+        status == 400 && self.title == "Invalid Resource" && self.errors.is_empty()
+    }
+}
+
 pub struct List(pub String);
 
 impl List {
@@ -23,90 +90,142 @@ impl List {
         token: &Token,
         after_time: Option<impl AsRef<str>>,
     ) -> worker::Result<Members> {
-        let endpoint = format!("lists/{}/members", self.0);
-
-        let mut members = Members {
-            members: Vec::default(),
-            total_items: 0,
-        };
-
-        loop {
-            let resp = token
-                .fetch(
-                    &endpoint,
-                    after_time
-                        .as_ref()
-                        .map(|t| ("since_last_changed", t.as_ref()))
-                        .into_iter()
-                        .chain(
-                            [
-                                ("count", "1000"),
-                                ("offset", members.members.len().to_string().as_str()),
-                            ]
-                            .into_iter(),
-                        ),
-                    Method::Get,
-                    None,
-                )
-                .await?
-                .json::<Members>()
-                .await?;
+        let params = after_time
+            .as_ref()
+            .map(|t| ("since_last_changed", t.as_ref()))
+            .into_iter()
+            .collect::<Vec<_>>();
 
-            members.members.extend(resp.members);
+        token
+            .fetch_all(format!("lists/{}/members", self.0).as_str(), &params)
+            .await
+    }
 
-            if members.members.len() == resp.total_items {
-                break;
-            }
+    /// Fetches a member of the list, `None` if the email is not on it
+    pub async fn member(&self, token: &Token, email: &str) -> worker::Result<Option<Member>> {
+        let mut resp = token
+            .fetch(
+                format!(
+                    "lists/{}/members/{}",
+                    self.0,
+                    UrlTemplate::subscriber_hash(email)
+                )
+                .as_str(),
+                [],
+                Method::Get,
+                None,
+            )
+            .await?;
+
+        if resp.status_code() == 404 {
+            return Ok(None);
         }
 
-        Ok(members)
+        resp.json().await.map(Some)
     }
 
-    pub async fn get_or_add_merge_field(
+    pub async fn merge_fields(&self, token: &Token) -> worker::Result<MergeFields> {
+        token
+            .fetch_all(format!("lists/{}/merge-fields", self.0).as_str(), &[])
+            .await
+    }
+
+    /// Fetches a merge field, `None` if it does not exist anymore
+    pub async fn merge_field(
         &self,
         token: &Token,
-        name: impl AsRef<str>,
-    ) -> worker::Result<MergeField> {
-        #[derive(Debug, serde::Deserialize)]
-        struct MergeFields {
-            merge_fields: Vec<MergeField>,
-        }
-
-        let fields = token
+        merge_id: u64,
+    ) -> worker::Result<Option<MergeField>> {
+        let mut resp = token
             .fetch(
-                format!("lists/{}/merge-fields", self.0).as_str(),
+                format!("lists/{}/merge-fields/{merge_id}", self.0).as_str(),
                 [],
                 Method::Get,
                 None,
             )
-            .await?
-            .json::<MergeFields>()
-            .await?
-            .merge_fields;
+            .await?;
 
-        if let Some(field) = fields.into_iter().find(|field| field.name == name.as_ref()) {
-            return Ok(field);
+        if resp.status_code() == 404 {
+            return Ok(None);
         }
 
-        let body = serde_json::json!({
-            "name": name.as_ref(),
-            "type": "text",
-            "tag": name.as_ref(),
-            "required": false,
-            "public": false,
-        })
-        .to_string();
+        resp.json().await.map(Some)
+    }
 
-        token
+    /// Adds a merge field to the list, `None` if the list has reached the
+    /// number of merge fields its plan allows
+    pub async fn add_merge_field(
+        &self,
+        token: &Token,
+        field: &MergeFieldParams,
+    ) -> worker::Result<Option<MergeField>> {
+        let body = serde_json::to_string(field)?;
+
+        let mut resp = token
             .fetch(
                 format!("lists/{}/merge-fields", self.0).as_str(),
                 [],
                 Method::Post,
                 Some(body.into()),
             )
-            .await?
-            .json()
-            .await
+            .await?;
+
+        let status = resp.status_code();
+        if !(200..300).contains(&status) {
+            let problem = resp.json::<Problem>().await.unwrap_or_default();
+
+            // The limit differs between plans, so it is only known once reached
+            if problem.is_merge_field_limit(status) {
+                return Ok(None);
+            }
+
+            return Err(worker::Error::RustError(format!(
+                "Failed to add the merge field: {} {}",
+                problem.detail,
+                Value::from(problem.errors)
+            )));
+        }
+
+        resp.json().await.map(Some)
+    }
+
+    /// Updates the given params of a merge field, `None` if it does not exist
+    /// anymore
+    pub async fn update_merge_field(
+        &self,
+        token: &Token,
+        merge_id: u64,
+        field: &MergeFieldParams,
+    ) -> worker::Result<Option<MergeField>> {
+        let body = serde_json::to_string(field)?;
+
+        let mut resp = token
+            .fetch(
+                format!("lists/{}/merge-fields/{merge_id}", self.0).as_str(),
+                [],
+                Method::Patch,
+                Some(body.into()),
+            )
+            .await?;
+
+        if resp.status_code() == 404 {
+            return Ok(None);
+        }
+
+        resp.json().await.map(Some)
+    }
+
+    pub async fn delete_merge_field(&self, token: &Token, merge_id: u64) -> worker::Result<()> {
+        token
+            .fetch(
+                format!("lists/{}/merge-fields/{merge_id}", self.0).as_str(),
+                [],
+                Method::Delete,
+                None,
+            )
+            .await?;
+
+        Ok(())
     }
 
     pub async fn set_member_merge_field_batch(
@@ -153,28 +272,31 @@ impl List {
         Ok(())
     }
 
+    /// The url a webhook of this list is registered with. The `secret` is
+    /// embedded in the url so that incoming calls can be verified.
+    pub fn webhook_url(url: &url::Url, secret: impl AsRef<str>) -> url::Url {
+        let mut url = url.clone();
+        url.query_pairs_mut().append_pair("secret", secret.as_ref());
+
+        url
+    }
+
+    fn webhook_body(url: &url::Url, secret: impl AsRef<str>) -> Value {
+        serde_json::json!({
+            "url": Self::webhook_url(url, secret).as_str(),
+            "events": WebhookEvents::ALL,
+            "sources": WebhookSources::ALL,
+        })
+    }
+
+    /// Installs a webhook on the list pointing at `url` with the `secret`
     pub async fn install_webhook(
         &self,
         token: &Token,
-        url: impl AsRef<str>,
+        url: &url::Url,
+        secret: impl AsRef<str>,
     ) -> worker::Result<String> {
-        let body = serde_json::json!({
-            "url": url.as_ref(),
-            "events": {
-                "subscribe": true,
-                "profile": true
-            },
-            "sources": {
-                "user": true,
-                "admin": true,
-                "api": true
-            },
-        });
-
-        #[derive(serde::Deserialize)]
-        struct Webhook {
-            id: String,
-        }
+        let body = Self::webhook_body(url, secret);
 
         let webhook: Webhook = token
             .fetch(
@@ -189,10 +311,349 @@ impl List {
 
         Ok(webhook.id)
     }
+
+    pub async fn webhooks(&self, token: &Token) -> worker::Result<Vec<Webhook>> {
+        #[derive(Debug, serde::Deserialize)]
+        struct Webhooks {
+            webhooks: Vec<Webhook>,
+        }
+
+        Ok(token
+            .fetch(
+                format!("lists/{}/webhooks", self.0).as_str(),
+                [],
+                Method::Get,
+                None,
+            )
+            .await?
+            .json::<Webhooks>()
+            .await?
+            .webhooks)
+    }
+
+    /// Fetches a webhook of the list, `None` if it does not exist anymore
+    pub async fn webhook(
+        &self,
+        token: &Token,
+        webhook_id: impl AsRef<str>,
+    ) -> worker::Result<Option<Webhook>> {
+        let mut resp = token
+            .fetch(
+                format!("lists/{}/webhooks/{}", self.0, webhook_id.as_ref()).as_str(),
+                [],
+                Method::Get,
+                None,
+            )
+            .await?;
+
+        if resp.status_code() == 404 {
+            return Ok(None);
+        }
+
+        resp.json().await.map(Some)
+    }
+
+    /// Points an existing webhook at `url` with the `secret`
+    pub async fn update_webhook(
+        &self,
+        token: &Token,
+        webhook_id: impl AsRef<str>,
+        url: &url::Url,
+        secret: impl AsRef<str>,
+    ) -> worker::Result<Webhook> {
+        let body = Self::webhook_body(url, secret);
+
+        token
+            .fetch(
+                format!("lists/{}/webhooks/{}", self.0, webhook_id.as_ref()).as_str(),
+                [],
+                Method::Patch,
+                Some(body.to_string().into()),
+            )
+            .await?
+            .json()
+            .await
+    }
+
+    pub async fn delete_webhook(
+        &self,
+        token: &Token,
+        webhook_id: impl AsRef<str>,
+    ) -> worker::Result<()> {
+        token
+            .fetch(
+                format!("lists/{}/webhooks/{}", self.0, webhook_id.as_ref()).as_str(),
+                [],
+                Method::Delete,
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    #[serde(default)]
+    pub events: WebhookEvents,
+    #[serde(default)]
+    pub sources: WebhookSources,
+}
+
+impl Webhook {
+    /// Whether the webhook calls `url` on every event the worker handles
+    pub fn is_current(&self, url: &url::Url) -> bool {
+        self.url == url.as_str()
+            && self.events == WebhookEvents::ALL
+            && self.sources == WebhookSources::ALL
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct WebhookEvents {
+    pub subscribe: bool,
+    pub unsubscribe: bool,
+    pub profile: bool,
+    pub cleaned: bool,
+    pub upemail: bool,
+    pub campaign: bool,
+}
+
+impl WebhookEvents {
+    pub const ALL: Self = WebhookEvents {
+        subscribe: true,
+        unsubscribe: true,
+        profile: true,
+        cleaned: true,
+        upemail: true,
+        campaign: true,
+    };
+}
+
+#[derive(Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct WebhookSources {
+    pub user: bool,
+    pub admin: bool,
+    pub api: bool,
+}
+
+impl WebhookSources {
+    pub const ALL: Self = WebhookSources {
+        user: true,
+        admin: true,
+        api: true,
+    };
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct MergeFieldOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_country: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone_format: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date_format: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub choices: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct MergeField {
+    pub merge_id: u64,
     pub tag: String,
     pub name: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    #[serde(default)]
+    pub default_value: String,
+    #[serde(default)]
+    pub public: bool,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub options: MergeFieldOptions,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct MergeFields {
+    pub merge_fields: Vec<MergeField>,
+    pub total_items: usize,
+}
+
+impl MergeFields {
+    pub fn by_name(&self, name: &str) -> Option<&MergeField> {
+        self.merge_fields.iter().find(|field| field.name == name)
+    }
+
+    /// Looks up a field by its tag, which mailchimp treats case insensitively
+    pub fn by_tag(&self, tag: &str) -> Option<&MergeField> {
+        self.merge_fields
+            .iter()
+            .find(|field| field.tag.eq_ignore_ascii_case(tag))
+    }
+}
+
+impl Paginated for MergeFields {
+    fn item_count(&self) -> usize {
+        self.merge_fields.len()
+    }
+
+    fn total_items(&self) -> usize {
+        self.total_items
+    }
+
+    fn append(&mut self, page: Self) {
+        self.merge_fields.extend(page.merge_fields);
+        self.total_items = page.total_items;
+    }
+}
+
+/// The params of a merge field to create or update. Params left as `None` are
+/// not sent, so an update only changes the given ones.
+#[derive(Debug, Default, serde::Serialize)]
+pub struct MergeFieldParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub ty: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<MergeFieldOptions>,
+}
+
+impl MergeFieldParams {
+    /// An optional text field hidden from the signup form
+    pub fn hidden_text(name: impl Into<String>, tag: impl Into<String>) -> Self {
+        MergeFieldParams {
+            name: Some(name.into()),
+            tag: Some(tag.into()),
+            ty: Some("text".into()),
+            public: Some(false),
+            required: Some(false),
+            ..Default::default()
+        }
+    }
+
+    pub fn rename(name: impl Into<String>) -> Self {
+        MergeFieldParams {
+            name: Some(name.into()),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problem(json: &str) -> Problem {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn merge_fields(json: serde_json::Value) -> MergeFields {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn looks_up_merge_fields() {
+        let fields = merge_fields(serde_json::json!({
+            "merge_fields": [
+                { "merge_id": 1, "tag": "FNAME", "name": "First Name", "type": "text" },
+                {
+                    "merge_id": 5,
+                    "tag": "MCL_VIDEO",
+                    "name": "Video",
+                    "type": "text",
+                    "default_value": "",
+                    "public": false,
+                    "required": false,
+                    "options": { "size": 25 }
+                }
+            ],
+            "total_items": 2
+        }));
+
+        assert_eq!(fields.by_name("Video").map(|field| field.merge_id), Some(5));
+        assert!(fields.by_name("video").is_none());
+        assert_eq!(
+            fields.by_tag("mcl_video").map(|field| field.merge_id),
+            Some(5)
+        );
+        assert_eq!(
+            fields.by_tag("FNAME").map(|field| field.name.as_str()),
+            Some("First Name")
+        );
+        assert!(fields.by_tag("LNAME").is_none());
+        assert_eq!(fields.merge_fields[1].options.size, Some(25));
+    }
+
+    #[test]
+    fn appends_merge_field_pages() {
+        let field = |merge_id: u64| serde_json::json!({ "merge_id": merge_id, "tag": format!("F{merge_id}"), "name": "", "type": "text" });
+        let mut fields = MergeFields::default();
+
+        fields.append(merge_fields(serde_json::json!({
+            "merge_fields": [field(1), field(2)],
+            "total_items": 3
+        })));
+        assert_eq!((fields.item_count(), fields.total_items()), (2, 3));
+        fields.append(merge_fields(serde_json::json!({
+            "merge_fields": [field(3)],
+            "total_items": 3
+        })));
+        assert_eq!((fields.item_count(), fields.total_items()), (3, 3));
+        assert!(fields.by_tag("F3").is_some());
+    }
+
+    #[test]
+    fn sends_only_the_given_merge_field_params() {
+        assert_eq!(
+            serde_json::to_value(MergeFieldParams::rename("Unused/MCL_VIDEO")).unwrap(),
+            serde_json::json!({ "name": "Unused/MCL_VIDEO" })
+        );
+        assert_eq!(
+            serde_json::to_value(MergeFieldParams::hidden_text("Video", "MCL_VIDEO")).unwrap(),
+            serde_json::json!({
+                "name": "Video",
+                "tag": "MCL_VIDEO",
+                "type": "text",
+                "public": false,
+                "required": false
+            })
+        );
+    }
+
+    #[test]
+    fn tells_merge_field_limits_from_invalid_fields() {
+        let limit = problem(
+            r#"{"type":"https://mailchimp.com/developer/marketing/docs/errors/","title":"Invalid Resource","status":400,"detail":"Whatever the wording, no field is named","instance":"1"}"#,
+        );
+        assert!(limit.is_merge_field_limit(400));
+
+        let invalid = problem(
+            r#"{"title":"Invalid Resource","status":400,"detail":"The resource submitted could not be validated.","errors":[{"field":"tag","message":"Tag is too long"}]}"#,
+        );
+        assert!(!invalid.is_merge_field_limit(400));
+
+        let forbidden =
+            problem(r#"{"title":"Forbidden","status":403,"detail":"You don't have permission"}"#);
+        assert!(!forbidden.is_merge_field_limit(403));
+        assert!(!limit.is_merge_field_limit(500));
+        // A body that is no problem at all
+        assert!(!Problem::default().is_merge_field_limit(400));
+    }
 }
@@ -1,8 +1,18 @@
 pub mod campaign;
 pub mod lists;
+pub mod webhook;
 
 use worker::{wasm_bindgen::JsValue, Fetch, Headers, Method, Request, RequestInit};
 
+/// A collection mailchimp returns one page at a time
+pub trait Paginated: Default + serde::de::DeserializeOwned {
+    /// The number of items fetched so far
+    fn item_count(&self) -> usize;
+    fn total_items(&self) -> usize;
+    /// Adds the items of the next page
+    fn append(&mut self, page: Self);
+}
+
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct Token {
     #[serde(rename = "AccessToken")]
@@ -13,6 +23,8 @@ pub struct Token {
 
 impl Token {
     const API_URL: &'static str = "https://<dc>.api.mailchimp.com/3.0/";
+    /// The largest page mailchimp returns
+    const PAGE_SIZE: &'static str = "1000";
 
     fn endpoint(&self, uri: &str) -> url::Url {
         Self::API_URL
@@ -54,4 +66,40 @@ impl Token {
             .send()
             .await
     }
+
+    /// Fetches every page of a collection, sending `params` with each request
+    pub async fn fetch_all<P: Paginated>(
+        &self,
+        uri: &str,
+        params: &[(&str, &str)],
+    ) -> worker::Result<P> {
+        let mut collection = P::default();
+
+        loop {
+            let offset = collection.item_count().to_string();
+            let page = self
+                .fetch(
+                    uri,
+                    params
+                        .iter()
+                        .copied()
+                        .chain([("count", Self::PAGE_SIZE), ("offset", offset.as_str())]),
+                    Method::Get,
+                    None,
+                )
+                .await?
+                .json::<P>()
+                .await?;
+
+            // An empty page ends the loop even if items were removed in between
+            let fetched = page.item_count();
+            collection.append(page);
+
+            if fetched == 0 || collection.item_count() >= collection.total_items() {
+                break;
+            }
+        }
+
+        Ok(collection)
+    }
 }
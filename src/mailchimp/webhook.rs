@@ -0,0 +1,578 @@
+use std::collections::BTreeMap;
+
+use sha2::{Digest, Sha256};
+
+/// A form value decoded from mailchimp's bracketed keys, e.g.
+/// `data[merges][GROUPINGS][0][name]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FormValue {
+    Value(String),
+    Map(BTreeMap<String, FormValue>),
+}
+
+impl FormValue {
+    fn parse(body: &[u8]) -> Self {
+        let mut root = FormValue::Map(BTreeMap::default());
+
+        for (key, value) in form_urlencoded::parse(body) {
+            let mut node = &mut root;
+            for segment in Self::key_segments(&key) {
+                let FormValue::Map(map) = node else {
+                    // A key was used both as a value and as a map. Mailchimp never
+                    // does that, so the nested value is dropped.
+                    break;
+                };
+                node = map
+                    .entry(segment.to_owned())
+                    .or_insert_with(|| FormValue::Map(BTreeMap::default()));
+            }
+
+            if let FormValue::Map(map) = node {
+                if map.is_empty() {
+                    *node = FormValue::Value(value.into_owned());
+                }
+            }
+        }
+
+        root
+    }
+
+    /// Splits `data[merges][FNAME]` into `["data", "merges", "FNAME"]`
+    fn key_segments(key: &str) -> impl Iterator<Item = &str> {
+        let (head, rest) = key.split_once('[').unwrap_or((key, ""));
+
+        std::iter::once(head).chain(
+            rest.split('[')
+                .map(|segment| segment.trim_end_matches(']'))
+                .filter(|_| !rest.is_empty()),
+        )
+    }
+
+    fn get(&self, key: &str) -> Option<&FormValue> {
+        match self {
+            FormValue::Map(map) => map.get(key),
+            FormValue::Value(_) => None,
+        }
+    }
+
+    fn path(&self, path: &[&str]) -> Option<&FormValue> {
+        path.iter().try_fold(self, |node, key| node.get(key))
+    }
+
+    fn str(&self, path: &[&str]) -> Option<&str> {
+        match self.path(path)? {
+            FormValue::Value(value) => Some(value.as_str()),
+            FormValue::Map(_) => None,
+        }
+    }
+
+    fn required(&self, path: &[&str]) -> Result<String, WebhookError> {
+        self.str(path)
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| WebhookError::Missing(Self::key_name(path)))
+    }
+
+    /// Like [`FormValue::str`] but treats empty values as missing, as mailchimp
+    /// sends optional fields with an empty value
+    fn optional(&self, path: &[&str]) -> Option<String> {
+        self.str(path)
+            .filter(|value| !value.is_empty())
+            .map(ToOwned::to_owned)
+    }
+
+    fn key_name(path: &[&str]) -> String {
+        let (head, rest) = path.split_first().expect("Path can not be empty");
+
+        rest.iter().fold(head.to_string(), |mut name, segment| {
+            name.push('[');
+            name.push_str(segment);
+            name.push(']');
+            name
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebhookError {
+    /// A required key was not part of the call
+    Missing(String),
+    /// The `type` of the call is not one mailchimp documents
+    UnsupportedType(String),
+}
+
+impl std::fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebhookError::Missing(key) => write!(f, "Webhook call is missing {key}"),
+            WebhookError::UnsupportedType(ty) => {
+                write!(f, "Unsupported type of webhook call: {ty}")
+            }
+        }
+    }
+}
+
+impl From<WebhookError> for worker::Error {
+    fn from(err: WebhookError) -> Self {
+        worker::Error::RustError(err.to_string())
+    }
+}
+
+/// An interest grouping sent as `data[merges][GROUPINGS][n][...]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grouping {
+    pub id: Option<String>,
+    pub unique_id: Option<String>,
+    pub name: String,
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Merges {
+    /// The plain merge fields keyed by their tag, e.g. `FNAME`
+    pub fields: BTreeMap<String, String>,
+    /// The interests from the `INTERESTS` merge
+    pub interests: Vec<String>,
+    pub groupings: Vec<Grouping>,
+}
+
+impl Merges {
+    fn parse(merges: Option<&FormValue>) -> Self {
+        let Some(FormValue::Map(merges)) = merges else {
+            return Merges::default();
+        };
+
+        let mut parsed = Merges::default();
+        for (tag, value) in merges {
+            match (tag.as_str(), value) {
+                ("INTERESTS", FormValue::Value(interests)) => {
+                    parsed.interests = Self::split_groups(interests);
+                }
+                ("GROUPINGS", FormValue::Map(groupings)) => {
+                    let mut groupings = groupings.iter().collect::<Vec<_>>();
+                    // Indices are strings, so "10" would sort before "2"
+                    groupings
+                        .sort_by_key(|(index, _)| index.parse::<usize>().unwrap_or(usize::MAX));
+
+                    parsed.groupings = groupings
+                        .into_iter()
+                        .map(|(_, grouping)| Grouping {
+                            id: grouping.optional(&["id"]),
+                            unique_id: grouping.optional(&["unique_id"]),
+                            name: grouping.str(&["name"]).unwrap_or_default().to_owned(),
+                            groups: Self::split_groups(
+                                grouping.str(&["groups"]).unwrap_or_default(),
+                            ),
+                        })
+                        .collect();
+                }
+                (_, FormValue::Value(value)) => {
+                    parsed.fields.insert(tag.clone(), value.clone());
+                }
+                // Address merges and the like are nested but unused
+                (_, FormValue::Map(_)) => {}
+            }
+        }
+
+        parsed
+    }
+
+    fn split_groups(groups: &str) -> Vec<String> {
+        groups
+            .split(',')
+            .map(str::trim)
+            .filter(|group| !group.is_empty())
+            .map(ToOwned::to_owned)
+            .collect()
+    }
+
+    pub fn get(&self, tag: &str) -> Option<&str> {
+        self.fields
+            .get(tag)
+            .map(String::as_str)
+            .filter(|value| !value.is_empty())
+    }
+
+    /// The member's name built from whichever of `FNAME` and `LNAME` are set
+    pub fn full_name(&self) -> String {
+        [self.get("FNAME"), self.get("LNAME")]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// The member data sent with `subscribe`, `unsubscribe` and `profile` calls
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemberData {
+    pub id: String,
+    pub list_id: String,
+    pub email: String,
+    pub email_type: Option<String>,
+    pub merges: Merges,
+    pub ip_opt: Option<String>,
+    pub ip_signup: Option<String>,
+}
+
+impl MemberData {
+    fn parse(form: &FormValue) -> Result<Self, WebhookError> {
+        Ok(MemberData {
+            id: form.required(&["data", "id"])?,
+            list_id: form.required(&["data", "list_id"])?,
+            email: form.required(&["data", "email"])?,
+            email_type: form.optional(&["data", "email_type"]),
+            merges: Merges::parse(form.path(&["data", "merges"])),
+            ip_opt: form.optional(&["data", "ip_opt"]),
+            ip_signup: form.optional(&["data", "ip_signup"]),
+        })
+    }
+}
+
+/// A call mailchimp made to the list webhook
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebhookEvent {
+    /// A new member subscribed
+    Subscribe {
+        fired_at: String,
+        member: MemberData,
+    },
+    /// A member unsubscribed or was deleted
+    Unsubscribe {
+        fired_at: String,
+        member: MemberData,
+        action: String,
+        reason: String,
+        campaign_id: Option<String>,
+    },
+    /// A member's data has changed
+    Profile {
+        fired_at: String,
+        member: MemberData,
+    },
+    /// A member changed their email address
+    UpEmail {
+        fired_at: String,
+        list_id: String,
+        new_id: String,
+        new_email: String,
+        old_email: String,
+    },
+    /// Mailchimp cleaned a bouncing or abusive address
+    Cleaned {
+        fired_at: String,
+        list_id: String,
+        campaign_id: Option<String>,
+        reason: String,
+        email: String,
+    },
+    /// A campaign was sent or cancelled
+    Campaign {
+        fired_at: String,
+        id: String,
+        list_id: String,
+        subject: String,
+        status: String,
+        reason: Option<String>,
+    },
+}
+
+impl WebhookEvent {
+    /// Parses the form encoded body of a webhook call
+    pub fn parse(body: &[u8]) -> Result<Self, WebhookError> {
+        let form = FormValue::parse(body);
+
+        let ty = form.required(&["type"])?;
+        let fired_at = form.required(&["fired_at"])?;
+
+        Ok(match ty.as_str() {
+            "subscribe" => WebhookEvent::Subscribe {
+                fired_at,
+                member: MemberData::parse(&form)?,
+            },
+            "unsubscribe" => WebhookEvent::Unsubscribe {
+                fired_at,
+                member: MemberData::parse(&form)?,
+                action: form.required(&["data", "action"])?,
+                reason: form.str(&["data", "reason"]).unwrap_or_default().to_owned(),
+                campaign_id: form.optional(&["data", "campaign_id"]),
+            },
+            "profile" => WebhookEvent::Profile {
+                fired_at,
+                member: MemberData::parse(&form)?,
+            },
+            "upemail" => WebhookEvent::UpEmail {
+                fired_at,
+                list_id: form.required(&["data", "list_id"])?,
+                new_id: form.required(&["data", "new_id"])?,
+                new_email: form.required(&["data", "new_email"])?,
+                old_email: form.required(&["data", "old_email"])?,
+            },
+            "cleaned" => WebhookEvent::Cleaned {
+                fired_at,
+                list_id: form.required(&["data", "list_id"])?,
+                campaign_id: form.optional(&["data", "campaign_id"]),
+                reason: form.required(&["data", "reason"])?,
+                email: form.required(&["data", "email"])?,
+            },
+            "campaign" => WebhookEvent::Campaign {
+                fired_at,
+                id: form.required(&["data", "id"])?,
+                list_id: form.required(&["data", "list_id"])?,
+                subject: form
+                    .str(&["data", "subject"])
+                    .unwrap_or_default()
+                    .to_owned(),
+                status: form.required(&["data", "status"])?,
+                reason: form.optional(&["data", "reason"]),
+            },
+            _ => return Err(WebhookError::UnsupportedType(ty)),
+        })
+    }
+
+    /// Reads only the list id of a webhook call, so the call can be
+    /// authenticated before it is fully parsed
+    pub fn list_id_of(body: &[u8]) -> Option<String> {
+        form_urlencoded::parse(body)
+            .find(|(key, _)| key == "data[list_id]")
+            .map(|(_, list_id)| list_id.into_owned())
+    }
+
+    /// Reads only the `type` of a webhook call
+    pub fn type_of(body: &[u8]) -> Option<String> {
+        form_urlencoded::parse(body)
+            .find(|(key, _)| key == "type")
+            .map(|(_, ty)| ty.into_owned())
+    }
+
+    /// Hashes the content of a webhook call to detect duplicates. `fired_at`
+    /// is left out, as an edit in the admin fires the same `profile` event
+    /// more than once, seconds apart.
+    pub fn content_hash(body: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        for (key, value) in form_urlencoded::parse(body).filter(|(key, _)| key != "fired_at") {
+            hasher.update(key.as_bytes());
+            hasher.update([0]);
+            hasher.update(value.as_bytes());
+            hasher.update([0]);
+        }
+
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_fixture(fixture: &str) -> WebhookEvent {
+        WebhookEvent::parse(fixture.trim().as_bytes()).expect("Failed to parse fixture")
+    }
+
+    #[test]
+    fn parses_subscribe() {
+        let event = parse_fixture(include_str!("fixtures/webhook_subscribe.txt"));
+
+        let WebhookEvent::Subscribe { fired_at, member } = event else {
+            panic!("Expected a subscribe event, got {event:?}");
+        };
+        assert_eq!(fired_at, "2009-03-26 21:35:57");
+        assert_eq!(member.id, "8a25ff1d98");
+        assert_eq!(member.list_id, "a6b5da1054");
+        assert_eq!(member.email, "api@mailchimp.com");
+        assert_eq!(member.email_type.as_deref(), Some("html"));
+        assert_eq!(member.merges.get("FNAME"), Some("Mailchimp"));
+        assert_eq!(member.merges.get("LNAME"), Some("API"));
+        assert_eq!(member.merges.full_name(), "Mailchimp API");
+        assert_eq!(member.merges.interests, ["Group1", "Group2"]);
+        assert_eq!(member.ip_opt.as_deref(), Some("10.20.10.30"));
+        assert_eq!(member.ip_signup.as_deref(), Some("10.20.10.30"));
+    }
+
+    #[test]
+    fn parses_subscribe_with_groupings() {
+        let event = parse_fixture(include_str!("fixtures/webhook_subscribe_groupings.txt"));
+
+        let WebhookEvent::Subscribe { member, .. } = event else {
+            panic!("Expected a subscribe event, got {event:?}");
+        };
+        assert_eq!(
+            member.merges.groupings,
+            [
+                Grouping {
+                    id: Some("1".into()),
+                    unique_id: Some("8a25ff1d98".into()),
+                    name: "Interests".into(),
+                    groups: vec!["Video".into(), "Podcasts".into()],
+                },
+                Grouping {
+                    id: Some("2".into()),
+                    unique_id: Some("2f3d4e5a6b".into()),
+                    name: "Region".into(),
+                    groups: vec!["Europe".into()],
+                },
+            ]
+        );
+        assert!(!member.merges.fields.contains_key("GROUPINGS"));
+        assert!(!member.merges.fields.contains_key("ADDRESS"));
+    }
+
+    #[test]
+    fn subscribe_without_name_merges_is_accepted() {
+        let event = parse_fixture(include_str!("fixtures/webhook_subscribe_no_name.txt"));
+
+        let WebhookEvent::Subscribe { member, .. } = event else {
+            panic!("Expected a subscribe event, got {event:?}");
+        };
+        assert_eq!(member.merges.get("FNAME"), None);
+        assert_eq!(member.merges.get("LNAME"), None);
+        assert_eq!(member.merges.full_name(), "");
+        assert_eq!(member.ip_signup, None);
+    }
+
+    #[test]
+    fn parses_unsubscribe() {
+        let event = parse_fixture(include_str!("fixtures/webhook_unsubscribe.txt"));
+
+        let WebhookEvent::Unsubscribe {
+            member,
+            action,
+            reason,
+            campaign_id,
+            ..
+        } = event
+        else {
+            panic!("Expected an unsubscribe event, got {event:?}");
+        };
+        assert_eq!(member.email, "api+unsub@mailchimp.com");
+        assert_eq!(action, "unsub");
+        assert_eq!(reason, "manual");
+        assert_eq!(campaign_id.as_deref(), Some("cb398d21d2"));
+    }
+
+    #[test]
+    fn parses_profile() {
+        let event = parse_fixture(include_str!("fixtures/webhook_profile.txt"));
+
+        let WebhookEvent::Profile { member, .. } = event else {
+            panic!("Expected a profile event, got {event:?}");
+        };
+        assert_eq!(member.email, "api@mailchimp.com");
+        assert_eq!(member.merges.full_name(), "Mailchimp API");
+        assert_eq!(member.merges.interests, ["Group1", "Group2"]);
+    }
+
+    #[test]
+    fn parses_upemail() {
+        let event = parse_fixture(include_str!("fixtures/webhook_upemail.txt"));
+
+        assert_eq!(
+            event,
+            WebhookEvent::UpEmail {
+                fired_at: "2009-03-26 22:15:09".into(),
+                list_id: "a6b5da1054".into(),
+                new_id: "51da8c3259".into(),
+                new_email: "api+new@mailchimp.com".into(),
+                old_email: "api+old@mailchimp.com".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_cleaned() {
+        let event = parse_fixture(include_str!("fixtures/webhook_cleaned.txt"));
+
+        assert_eq!(
+            event,
+            WebhookEvent::Cleaned {
+                fired_at: "2009-03-26 22:01:00".into(),
+                list_id: "a6b5da1054".into(),
+                campaign_id: Some("4fjk2ma9xd".into()),
+                reason: "hard".into(),
+                email: "api+cleaned@mailchimp.com".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_campaign() {
+        let event = parse_fixture(include_str!("fixtures/webhook_campaign.txt"));
+
+        assert_eq!(
+            event,
+            WebhookEvent::Campaign {
+                fired_at: "2009-03-26 21:31:21".into(),
+                id: "5aa2102003".into(),
+                list_id: "a6b5da1054".into(),
+                subject: "Test Campaign Subject".into(),
+                status: "sent".into(),
+                reason: None,
+            }
+        );
+    }
+
+    #[test]
+    fn list_id_is_read_without_parsing() {
+        let body = include_str!("fixtures/webhook_upemail.txt").trim();
+
+        assert_eq!(
+            WebhookEvent::list_id_of(body.as_bytes()).as_deref(),
+            Some("a6b5da1054")
+        );
+    }
+
+    #[test]
+    fn content_hash_ignores_fired_at() {
+        let body = include_str!("fixtures/webhook_profile.txt").trim();
+        // The same edit fired again a few seconds later
+        let refired = body.replace(
+            "fired_at=2009-03-26+21%3A31%3A21",
+            "fired_at=2009-03-26+21%3A31%3A24",
+        );
+        assert_ne!(body, refired);
+        let edited = body.replace("LNAME%5D=API", "LNAME%5D=APIs");
+        assert_ne!(body, edited);
+
+        assert_eq!(
+            WebhookEvent::content_hash(body.as_bytes()),
+            WebhookEvent::content_hash(refired.as_bytes())
+        );
+        assert_ne!(
+            WebhookEvent::content_hash(body.as_bytes()),
+            WebhookEvent::content_hash(edited.as_bytes())
+        );
+        assert_ne!(
+            WebhookEvent::content_hash(body.as_bytes()),
+            WebhookEvent::content_hash(
+                include_str!("fixtures/webhook_subscribe.txt")
+                    .trim()
+                    .as_bytes()
+            )
+        );
+        assert_eq!(
+            WebhookEvent::type_of(body.as_bytes()).as_deref(),
+            Some("profile")
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_types() {
+        assert_eq!(
+            WebhookEvent::parse(b"type=unknown&fired_at=2009-03-26+21%3A31%3A21"),
+            Err(WebhookError::UnsupportedType("unknown".into()))
+        );
+    }
+
+    #[test]
+    fn reports_missing_keys() {
+        assert_eq!(
+            WebhookEvent::parse(
+                b"type=subscribe&fired_at=2009-03-26+21%3A35%3A57&data%5Bid%5D=8a25ff1d98&data%5Blist_id%5D=a6b5da1054"
+            ),
+            Err(WebhookError::Missing("data[email]".into()))
+        );
+    }
+}
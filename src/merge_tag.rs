@@ -0,0 +1,216 @@
+use sha2::{Digest, Sha256};
+
+/// Mailchimp tags are at most 10 characters long
+pub const MAX_LEN: usize = 10;
+/// The number of tags tried for a field before giving up
+pub const ATTEMPTS: u32 = 16;
+
+const ALPHABET: &[u8; 36] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Generates the tag of a merge field from its name. Mailchimp only accepts
+/// short tags of uppercase letters and digits, so the tag is the first letter
+/// of the name, keeping `Video/…` and `Image/…` fields recognizable, followed
+/// by a hash of the name. The same name and `attempt` always give the same
+/// tag, and the next `attempt` is used when the tag is already taken.
+pub fn generate(name: &str, attempt: u32) -> String {
+    let prefix = name
+        .chars()
+        .find(char::is_ascii_alphabetic)
+        .unwrap_or('F')
+        .to_ascii_uppercase();
+
+    let mut hasher = Sha256::new();
+    hasher.update(name.as_bytes());
+    hasher.update(attempt.to_be_bytes());
+    let digest = hasher.finalize();
+
+    let mut hash = u64::from_be_bytes(digest[..8].try_into().expect("digest has 32 bytes"));
+    let mut tag = String::with_capacity(MAX_LEN);
+    tag.push(prefix);
+    while tag.len() < MAX_LEN {
+        tag.push(ALPHABET[(hash % 36) as usize] as char);
+        hash /= 36;
+    }
+
+    tag
+}
+
+/// The first tag generated for `name` from the attempt `from` on that is not
+/// `taken`, along with its attempt. `None` once the attempts run out.
+pub fn first_free(name: &str, from: u32, taken: impl Fn(&str) -> bool) -> Option<(u32, String)> {
+    (from..ATTEMPTS)
+        .map(|attempt| (attempt, generate(name, attempt)))
+        .find(|(_, tag)| !taken(tag))
+}
+
+/// A `*|…|*` merge tag in campaign content
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Occurrence<'a> {
+    /// The byte offset of the opening `*|`
+    pub start: usize,
+    /// The byte offset right after the closing `|*`
+    pub end: usize,
+    /// The text between the delimiters, e.g. `VIDEO` or `IF:FNAME`
+    pub inner: &'a str,
+}
+
+impl<'a> Occurrence<'a> {
+    /// The merge field the tag refers to, also for conditions and html
+    /// tags like `*|IF:FNAME|*` or `*|HTML:BIO|*`. Other tags containing a
+    /// colon, like `*|END:IF|*` or `*|DATE:Y|*`, refer to none.
+    pub fn merge_field(&self) -> Option<&'a str> {
+        let Some((kind, rest)) = self.inner.split_once(':') else {
+            return Some(self.inner.trim());
+        };
+
+        match kind {
+            "IF" | "IFNOT" | "ELSEIF" | "HTML" => rest
+                .split(|c: char| matches!(c, '=' | '!' | '<' | '>') || c.is_whitespace())
+                .next()
+                .filter(|tag| !tag.is_empty()),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a tag only holds the characters mailchimp accepts in tags
+pub fn is_valid(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Finds the merge tags of campaign content, in order. An opening `*|` that
+/// is not closed before the next one is reported by its byte offset.
+pub fn scan(content: &str) -> Vec<Result<Occurrence<'_>, usize>> {
+    let mut occurrences = Vec::new();
+    let mut offset = 0;
+
+    while let Some(found) = content[offset..].find("*|") {
+        let start = offset + found;
+        let rest = &content[start + 2..];
+        let next_open = rest.find("*|").unwrap_or(rest.len());
+
+        match rest.find("|*").filter(|close| *close <= next_open) {
+            Some(close) => {
+                let end = start + 2 + close + 2;
+                occurrences.push(Ok(Occurrence {
+                    start,
+                    end,
+                    inner: &rest[..close],
+                }));
+                offset = end;
+            }
+            None => {
+                occurrences.push(Err(start));
+                offset = start + 2;
+            }
+        }
+    }
+
+    occurrences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge_fields(content: &str) -> Vec<Option<&str>> {
+        scan(content)
+            .into_iter()
+            .map(|occurrence| occurrence.expect("Failed to scan tag").merge_field())
+            .collect()
+    }
+
+    #[test]
+    fn generates_stable_tags() {
+        let tag = generate("video/abc123", 0);
+
+        assert_eq!(tag.len(), MAX_LEN);
+        assert!(tag.starts_with('V'));
+        assert!(tag
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()));
+        assert_eq!(tag, generate("video/abc123", 0));
+        assert_ne!(tag, generate("video/abc123", 1));
+        assert!(generate("42/abc", 0).starts_with('A'));
+        assert!(generate("?", 0).starts_with('F'));
+    }
+
+    #[test]
+    fn moves_on_from_taken_tags() {
+        let first = generate("Video", 0);
+        let second = generate("Video", 1);
+
+        assert_eq!(first_free("Video", 0, |_| false), Some((0, first.clone())));
+        assert_eq!(
+            first_free("Video", 0, |tag| tag == first),
+            Some((1, second.clone()))
+        );
+        // A tag mailchimp refused is skipped by going on from the next attempt
+        assert_eq!(first_free("Video", 1, |_| false), Some((1, second)));
+        assert_eq!(first_free("Video", 0, |_| true), None);
+        assert_eq!(first_free("Video", ATTEMPTS, |_| false), None);
+    }
+
+    #[test]
+    fn scans_tags() {
+        let content = "<a href=\"https://*|VIDEO|*\">*|FNAME|*</a>";
+        let occurrences = scan(content);
+
+        assert_eq!(
+            occurrences,
+            vec![
+                Ok(Occurrence {
+                    start: 17,
+                    end: 26,
+                    inner: "VIDEO",
+                }),
+                Ok(Occurrence {
+                    start: 28,
+                    end: 37,
+                    inner: "FNAME",
+                }),
+            ]
+        );
+        assert_eq!(&content[17..26], "*|VIDEO|*");
+    }
+
+    #[test]
+    fn reports_unclosed_tags() {
+        assert_eq!(
+            scan("Hi *|FNAME, *|LNAME|*"),
+            vec![
+                Err(3),
+                Ok(Occurrence {
+                    start: 12,
+                    end: 21,
+                    inner: "LNAME",
+                }),
+            ]
+        );
+        assert_eq!(scan("*|FNAME"), vec![Err(0)]);
+        assert_eq!(scan("no tags | here *"), vec![]);
+    }
+
+    #[test]
+    fn finds_merge_fields_of_tags() {
+        assert_eq!(
+            merge_fields("*|IF:FNAME|**|ELSEIF:LNAME != Doe|**|ELSE:|**|END:IF|**|IFNOT:CITY>3|*"),
+            vec![Some("FNAME"), Some("LNAME"), None, None, Some("CITY")]
+        );
+        assert_eq!(
+            merge_fields("*|HTML:BIO|**|DATE:Y|**| VIDEO |**|IF:|*"),
+            vec![Some("BIO"), None, Some("VIDEO"), None]
+        );
+    }
+
+    #[test]
+    fn validates_tags() {
+        assert!(is_valid("VIDEO_2"));
+        assert!(!is_valid("video"));
+        assert!(!is_valid("VIDEO URL"));
+        assert!(!is_valid(""));
+    }
+}
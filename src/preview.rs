@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+
+use crate::merge_tag::{self, Occurrence};
+
+/// A `*|IF:…|*` block being rendered
+struct Condition {
+    /// Whether the block's parent is rendered
+    parent: bool,
+    /// Whether a branch of the block was rendered already
+    taken: bool,
+    /// Whether the current branch is rendered
+    active: bool,
+}
+
+/// Renders campaign content the way a member would get it. `values` holds
+/// the member's merge field values keyed by uppercase tag. Tags without a
+/// value, like `*|UNSUB|*`, are left as they are since mailchimp fills them
+/// in when sending.
+///
+/// `*|IF:TAG|*`, `*|IFNOT:TAG|*`, `*|ELSEIF:TAG|*`, `*|ELSE:|*` and
+/// `*|END:IF|*` blocks are evaluated, comparing with `=`, `!=`, `>`, `<`,
+/// `>=` and `<=` as well.
+pub fn render(html: &str, values: &BTreeMap<String, String>) -> String {
+    let mut rendered = String::with_capacity(html.len());
+    let mut conditions: Vec<Condition> = Vec::new();
+    let mut offset = 0;
+
+    for occurrence in merge_tag::scan(html) {
+        let active = conditions.last().is_none_or(|condition| condition.active);
+        // An unclosed `*|` is rendered as text along with what follows
+        let Ok(occurrence) = occurrence else {
+            continue;
+        };
+        if active {
+            rendered.push_str(&html[offset..occurrence.start]);
+        }
+        offset = occurrence.end;
+
+        let inner = occurrence.inner.trim();
+        let (kind, rest) = inner.split_once(':').unwrap_or(("", inner));
+        match kind {
+            "IF" | "IFNOT" => {
+                let matched = evaluate(rest, values) == (kind == "IF");
+                conditions.push(Condition {
+                    parent: active,
+                    taken: matched,
+                    active: active && matched,
+                });
+            }
+            "ELSEIF" => {
+                if let Some(condition) = conditions.last_mut() {
+                    let matched = !condition.taken && evaluate(rest, values);
+                    condition.taken |= matched;
+                    condition.active = condition.parent && matched;
+                }
+            }
+            "ELSE" => {
+                if let Some(condition) = conditions.last_mut() {
+                    condition.active = condition.parent && !condition.taken;
+                    condition.taken = true;
+                }
+            }
+            "END" if rest == "IF" => {
+                conditions.pop();
+            }
+            _ if active => rendered.push_str(&substitute(html, &occurrence, values)),
+            _ => {}
+        }
+    }
+
+    if conditions.last().is_none_or(|condition| condition.active) {
+        rendered.push_str(&html[offset..]);
+    }
+
+    rendered
+}
+
+/// The value of a merge tag, html escaped unless it is a `*|HTML:TAG|*`
+fn substitute(html: &str, occurrence: &Occurrence, values: &BTreeMap<String, String>) -> String {
+    let original = &html[occurrence.start..occurrence.end];
+    let Some(tag) = occurrence.merge_field() else {
+        return original.to_owned();
+    };
+
+    match values.get(&tag.to_ascii_uppercase()) {
+        Some(value) if occurrence.inner.starts_with("HTML:") => value.clone(),
+        Some(value) => escape(value),
+        None => original.to_owned(),
+    }
+}
+
+/// Evaluates the condition of an `IF` tag, e.g. `FNAME`, `PLAN=gold` or `AGE>=18`
+fn evaluate(condition: &str, values: &BTreeMap<String, String>) -> bool {
+    let operators = ["!=", ">=", "<=", "=", ">", "<"];
+    let Some((tag, operator, expected)) = operators.iter().find_map(|operator| {
+        let (tag, expected) = condition.split_once(operator)?;
+        Some((tag, *operator, expected))
+    }) else {
+        return values
+            .get(&condition.trim().to_ascii_uppercase())
+            .is_some_and(|value| !value.is_empty());
+    };
+
+    let value = values
+        .get(&tag.trim().to_ascii_uppercase())
+        .map(String::as_str)
+        .unwrap_or_default();
+    let expected = expected.trim();
+
+    match operator {
+        "=" => value.eq_ignore_ascii_case(expected),
+        "!=" => !value.eq_ignore_ascii_case(expected),
+        _ => {
+            let (Ok(value), Ok(expected)) = (value.trim().parse::<f64>(), expected.parse::<f64>())
+            else {
+                return false;
+            };
+
+            match operator {
+                ">=" => value >= expected,
+                "<=" => value <= expected,
+                ">" => value > expected,
+                _ => value < expected,
+            }
+        }
+    }
+}
+
+/// Escapes a value for html text and attribute values, quoted either way
+pub fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(values: &[(&str, &str)]) -> BTreeMap<String, String> {
+        values
+            .iter()
+            .map(|(tag, value)| (tag.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn substitutes_tags() {
+        let values = values(&[("FNAME", "Ann"), ("VIDEO", "vimeo.com/1")]);
+
+        assert_eq!(
+            render(
+                "<a href=\"https://*|VIDEO|*\">Hi *|fname|*</a> *|UNSUB|* *|DATE:Y|*",
+                &values
+            ),
+            "<a href=\"https://vimeo.com/1\">Hi Ann</a> *|UNSUB|* *|DATE:Y|*"
+        );
+        // An unclosed tag is left as text
+        assert_eq!(render("Hi *|FNAME", &values), "Hi *|FNAME");
+    }
+
+    #[test]
+    fn escapes_values() {
+        let values = values(&[("BIO", "<b>Tom & \"Jerry's\"</b>")]);
+
+        assert_eq!(
+            render("<p title='*|BIO|*'>*|BIO|*</p>", &values),
+            "<p title='&lt;b&gt;Tom &amp; &quot;Jerry&#39;s&quot;&lt;/b&gt;'>&lt;b&gt;Tom &amp; &quot;Jerry&#39;s&quot;&lt;/b&gt;</p>"
+        );
+    }
+
+    #[test]
+    fn inserts_html_values_raw() {
+        // Like mailchimp, which is why the preview is shown in a sandbox
+        let values = values(&[("BIO", "<b>Tom</b><script>alert(1)</script>")]);
+
+        assert_eq!(
+            render("<p>*|HTML:BIO|*</p>", &values),
+            "<p><b>Tom</b><script>alert(1)</script></p>"
+        );
+    }
+
+    #[test]
+    fn renders_conditions() {
+        let html = "*|IF:PLAN=gold|*Gold*|ELSEIF:PLAN=silver|*Silver*|ELSE:|*None*|END:IF|*!";
+
+        assert_eq!(render(html, &values(&[("PLAN", "GOLD")])), "Gold!");
+        assert_eq!(render(html, &values(&[("PLAN", "silver")])), "Silver!");
+        assert_eq!(render(html, &values(&[])), "None!");
+        assert_eq!(
+            render(
+                "*|IFNOT:FNAME|*friend*|ELSE:|**|FNAME|**|END:IF|*",
+                &values(&[("FNAME", "")])
+            ),
+            "friend"
+        );
+    }
+
+    #[test]
+    fn renders_nested_conditions() {
+        let html = "*|IF:A|*a*|IF:B|*b*|ELSE:|*!b*|END:IF|**|ELSEIF:B|*B*|ELSE:|*-*|END:IF|*.";
+
+        assert_eq!(render(html, &values(&[("A", "1"), ("B", "1")])), "ab.");
+        assert_eq!(render(html, &values(&[("A", "1")])), "a!b.");
+        assert_eq!(render(html, &values(&[("B", "1")])), "B.");
+        assert_eq!(render(html, &values(&[])), "-.");
+    }
+
+    #[test]
+    fn skips_blocks_of_inactive_parents() {
+        // The inner ELSE would be taken if the outer block were rendered
+        let html = "*|IF:A|**|IF:B|*b*|ELSE:|*!b*|END:IF|**|END:IF|*.";
+
+        assert_eq!(render(html, &values(&[])), ".");
+        assert_eq!(render(html, &values(&[("B", "1")])), ".");
+    }
+
+    #[test]
+    fn evaluates_comparisons() {
+        let values = values(&[("AGE", " 18 "), ("PLAN", "Gold")]);
+
+        assert!(evaluate("AGE>=18", &values));
+        assert!(evaluate("AGE <= 18", &values));
+        assert!(!evaluate("AGE>18", &values));
+        assert!(evaluate("AGE<19.5", &values));
+        assert!(evaluate("PLAN = gold", &values));
+        assert!(evaluate("PLAN!=silver", &values));
+        assert!(!evaluate("PLAN>1", &values));
+        assert!(!evaluate("CITY", &values));
+        assert!(evaluate("CITY!=Paris", &values));
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+
+use crate::url_template::{TemplateMember, UrlTemplate};
+
+/// The kind of device a link is followed on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Device {
+    Mobile,
+    Tablet,
+    Desktop,
+}
+
+impl Device {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Device::Mobile => "mobile",
+            Device::Tablet => "tablet",
+            Device::Desktop => "desktop",
+        }
+    }
+
+    pub fn parse(device: &str) -> Option<Self> {
+        match device {
+            "mobile" => Some(Device::Mobile),
+            "tablet" => Some(Device::Tablet),
+            "desktop" => Some(Device::Desktop),
+            _ => None,
+        }
+    }
+
+    /// Tells the device from a user agent. Tablets are checked first, as
+    /// their user agents often claim to be mobile too, and anything unknown
+    /// is taken for a desktop.
+    pub fn from_user_agent(user_agent: Option<&str>) -> Self {
+        let Some(user_agent) = user_agent.map(str::to_lowercase) else {
+            return Device::Desktop;
+        };
+        let has = |part: &str| user_agent.contains(part);
+
+        // Android phones put `Mobile` in their user agent, Android tablets do not
+        if has("ipad")
+            || has("tablet")
+            || has("kindle")
+            || has("silk/")
+            || (has("android") && !has("mobile"))
+        {
+            Device::Tablet
+        } else if has("mobi") || has("iphone") || has("ipod") || has("windows phone") {
+            Device::Mobile
+        } else {
+            Device::Desktop
+        }
+    }
+}
+
+/// What is known of a member at the moment they follow a link
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Visitor {
+    /// The ISO 3166 code of the country, as told by Cloudflare
+    pub country: Option<String>,
+    pub device: Device,
+    /// The primary subtag of the language the member prefers, e.g. `de`
+    pub language: Option<String>,
+}
+
+impl Visitor {
+    pub fn new(
+        country: Option<&str>,
+        user_agent: Option<&str>,
+        accept_language: Option<&str>,
+    ) -> Self {
+        Visitor {
+            country: country.map(str::to_ascii_uppercase),
+            device: Device::from_user_agent(user_agent),
+            language: accept_language.and_then(preferred_language),
+        }
+    }
+}
+
+/// A destination of a tracked link, which members are sent to when they meet
+/// every condition of the rule. Conditions left empty are always met.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RoutingRule {
+    /// The tracked link field whose clicks the rule routes
+    pub field: String,
+    /// The name of the destination, recorded with the clicks sent there
+    pub variant: String,
+    /// ISO 3166 country codes like `DE`
+    #[serde(default)]
+    pub countries: Vec<String>,
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    /// Primary language subtags like `de`, matched against the language the
+    /// member prefers
+    #[serde(default)]
+    pub languages: Vec<String>,
+    /// Where members are sent, rendered for each member as a [`UrlTemplate`]
+    /// that can hold `{url}` for the url of their own link
+    pub url: String,
+}
+
+impl RoutingRule {
+    const MAX_VARIANT_LEN: usize = 32;
+
+    /// Checks the rule and normalizes its conditions. A rule without any
+    /// condition would route every click, leaving the rules after it unused.
+    pub fn validate(&mut self) -> Result<(), String> {
+        self.variant = self.variant.trim().to_owned();
+        if self.variant.is_empty() || self.variant.len() > Self::MAX_VARIANT_LEN {
+            return Err(format!(
+                "'{}' is not a valid variant name, names are up to {} characters",
+                self.variant,
+                Self::MAX_VARIANT_LEN
+            ));
+        }
+
+        for country in &mut self.countries {
+            *country = country.trim().to_ascii_uppercase();
+            if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+                return Err(format!(
+                    "'{country}' of the variant '{}' is no two letter country code",
+                    self.variant
+                ));
+            }
+        }
+        for language in &mut self.languages {
+            *language = language.trim().to_ascii_lowercase();
+            if !(2..=3).contains(&language.len())
+                || !language.chars().all(|c| c.is_ascii_alphabetic())
+            {
+                return Err(format!(
+                    "'{language}' of the variant '{}' is no language code like 'en'",
+                    self.variant
+                ));
+            }
+        }
+
+        if self.countries.is_empty() && self.devices.is_empty() && self.languages.is_empty() {
+            return Err(format!(
+                "The variant '{}' has no countries, devices or languages to route by",
+                self.variant
+            ));
+        }
+
+        self.url = self.url.trim().to_owned();
+        // The url of the link already has its own query
+        if let Some(pairs) = self.url.strip_prefix("{url}&") {
+            self.url = format!("{{url}}?{pairs}");
+        }
+        let template = UrlTemplate(&self.url);
+        template.validate_route()?;
+        // Rendered for a made up member, as the real ones are only known later
+        let example = template.render_route(
+            "campaign",
+            "list",
+            &TemplateMember {
+                email: "member@example.com",
+                name: "Member",
+                merges: &BTreeMap::new(),
+            },
+            "https://example.com/video",
+        );
+        match url::Url::parse(&example) {
+            Ok(url) if url.scheme() == "https" || url.scheme() == "http" => Ok(()),
+            _ => Err(format!(
+                "The url '{}' of the variant '{}' is not a web address",
+                self.url, self.variant
+            )),
+        }
+    }
+
+    pub fn matches(&self, visitor: &Visitor) -> bool {
+        let met = |values: &[String], value: &Option<String>| {
+            values.is_empty() || value.as_ref().is_some_and(|value| values.contains(value))
+        };
+
+        met(&self.countries, &visitor.country)
+            && (self.devices.is_empty() || self.devices.contains(&visitor.device))
+            && met(&self.languages, &visitor.language)
+    }
+}
+
+/// The first of the `rules` the visitor meets, as rules are ordered from the
+/// most to the least specific
+pub fn route<'a>(rules: &'a [RoutingRule], visitor: &Visitor) -> Option<&'a RoutingRule> {
+    rules.iter().find(|rule| rule.matches(visitor))
+}
+
+/// The language of the highest quality in an `Accept-Language` header like
+/// `de-CH, de;q=0.9, en;q=0.8, *;q=0.5`, the first of them on a tie
+fn preferred_language(accept_language: &str) -> Option<String> {
+    accept_language
+        .split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let language = params.next()?.trim().split('-').next()?;
+            let quality = match params.find_map(|param| param.trim().strip_prefix("q=")) {
+                Some(quality) => quality.trim().parse::<f32>().ok()?,
+                None => 1.0,
+            };
+
+            (quality > 0.0 && !language.is_empty() && language != "*")
+                .then(|| (language.to_ascii_lowercase(), quality))
+        })
+        .fold(
+            None,
+            |best: Option<(String, f32)>, (language, quality)| match best {
+                Some(best) if best.1 >= quality => Some(best),
+                _ => Some((language, quality)),
+            },
+        )
+        .map(|(language, _)| language)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(variant: &str, countries: &[&str], url: &str) -> RoutingRule {
+        RoutingRule {
+            field: "video".into(),
+            variant: variant.into(),
+            countries: countries
+                .iter()
+                .map(|country| country.to_string())
+                .collect(),
+            devices: Vec::new(),
+            languages: Vec::new(),
+            url: url.into(),
+        }
+    }
+
+    #[test]
+    fn validates_rules() {
+        let mut german = rule(" de ", &["de"], " {url}?lang=de ");
+        assert_eq!(german.validate(), Ok(()));
+        assert_eq!(german.variant, "de");
+        assert_eq!(german.countries, ["DE"]);
+        assert_eq!(german.url, "{url}?lang=de");
+        let mut appended = rule("de", &["DE"], "{url}&lang=de");
+        assert_eq!(appended.validate(), Ok(()));
+        assert_eq!(appended.url, "{url}?lang=de");
+        assert!(rule("de", &["DE"], "{url}?lang=de?src=mail")
+            .validate()
+            .is_err());
+
+        assert!(rule("de", &[], "https://example.com/de")
+            .validate()
+            .is_err());
+        assert!(rule("de", &["DEU"], "https://example.com/de")
+            .validate()
+            .is_err());
+        assert!(rule("de", &["DE"], "example.com/de").validate().is_err());
+        assert!(rule("de", &["DE"], "https://example.com/{merge:FNAME}")
+            .validate()
+            .is_err());
+        assert!(rule("de", &["DE"], "https://example.com/{nope}")
+            .validate()
+            .is_err());
+        assert_eq!(
+            rule("de", &["DE"], "https://example.com/de/{subscriber_hash}").validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn routes_by_the_first_rule_met() {
+        let rules = [
+            RoutingRule {
+                devices: vec![Device::Mobile],
+                ..rule("de-mobile", &["DE"], "https://example.com/m")
+            },
+            rule("de", &["DE", "AT"], "https://example.com/de"),
+        ];
+        let visitor = |country: &str, user_agent: &str| {
+            Visitor::new(Some(country), Some(user_agent), Some("de-CH, en;q=0.8"))
+        };
+        let variant = |visitor: &Visitor| route(&rules, visitor).map(|rule| rule.variant.as_str());
+
+        assert_eq!(
+            variant(&visitor("de", "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0)")),
+            Some("de-mobile")
+        );
+        assert_eq!(
+            variant(&visitor("AT", "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0)")),
+            Some("de")
+        );
+        assert_eq!(
+            variant(&visitor("DE", "Mozilla/5.0 (iPad; CPU OS 17_0)")),
+            Some("de")
+        );
+        assert_eq!(
+            variant(&visitor("FR", "Mozilla/5.0 (X11; Linux x86_64)")),
+            None
+        );
+        assert_eq!(visitor("FR", "").language.as_deref(), Some("de"));
+    }
+
+    #[test]
+    fn prefers_the_best_language() {
+        assert_eq!(
+            preferred_language("fr;q=0.5, de-CH, de;q=0.9").as_deref(),
+            Some("de")
+        );
+        assert_eq!(preferred_language("*, en;q=0").as_deref(), None);
+        assert_eq!(
+            preferred_language("en;q=x, it;q=0.1").as_deref(),
+            Some("it")
+        );
+    }
+}
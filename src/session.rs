@@ -1,9 +1,31 @@
-use std::collections::{HashMap, HashSet};
+mod content;
+mod coupons;
+mod fields;
+mod landing;
+mod merge_fields;
+mod personalization;
+mod qr_codes;
+mod routing_rules;
+mod thumbnails;
+mod tracking;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use serde_json::Value;
 use worker::{wasm_bindgen::JsValue, Env, Fetch, Headers, Method, Request, RequestInit, Response};
 
-use crate::mailchimp::{campaign::MailChimpCampaign, lists::List, Token};
+use crate::mailchimp::{
+    campaign::MailChimpCampaign,
+    lists::List,
+    webhook::{WebhookError, WebhookEvent},
+    Token,
+};
+
+pub use content::SnippetParams;
+pub use fields::{CampaignField, FieldType};
+pub use landing::LandingSettings;
+pub use personalization::Personalization;
+pub use tracking::Tracking;
 
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct User {
@@ -17,21 +39,73 @@ pub struct User {
     pub last_synced: Option<i64>,
 }
 
+/// A webhook call as it was logged in the WebhookEvents table
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct WebhookEventRecord {
+    #[serde(rename = "Id")]
+    pub id: i64,
+    #[serde(rename = "ListId")]
+    pub list_id: String,
+    #[serde(rename = "Type")]
+    pub ty: String,
+    #[serde(rename = "Payload")]
+    pub payload: String,
+    #[serde(rename = "Status")]
+    pub status: String,
+    #[serde(rename = "Error")]
+    pub error: Option<String>,
+    #[serde(rename = "ReceivedAt")]
+    pub received_at: i64,
+    #[serde(rename = "ProcessedAt")]
+    pub processed_at: Option<i64>,
+}
+
+impl WebhookEventRecord {
+    pub const PENDING: &'static str = "pending";
+    pub const PROCESSING: &'static str = "processing";
+    pub const PROCESSED: &'static str = "processed";
+    pub const FAILED: &'static str = "failed";
+    pub const IGNORED: &'static str = "ignored";
+
+    /// How long after a call the same content is taken for a duplicate
+    const DEDUP_WINDOW_SECS: i64 = 2 * 60;
+    /// How long a call is being processed before it can be claimed again, in
+    /// case the worker processing it was stopped
+    const CLAIM_TIMEOUT_SECS: i64 = 5 * 60;
+}
+
 pub struct Session {
     db: worker::D1Database,
     client_id: String,
     client_secret: String,
     webhook_uri: url::Url,
     redirect_uri: url::Url,
+    link_uri: url::Url,
+    image_uri: url::Url,
+    watch_uri: url::Url,
+    oembed_uri: url::Url,
+    thumbnail_uri: url::Url,
+    qr_code_uri: url::Url,
+    /// `None` when the secret is not set, which only fails the routes that
+    /// sign or verify links
+    link_key: Option<String>,
 }
 
 impl Session {
     pub const BINDING: &'static str = "MailchimpDB";
     pub const AUTH_CALLBACK: &'static str = "/auth/token";
     pub const WEBHOOK_CALLBACK: &'static str = "/webhook";
+    pub const LINK_PATH: &'static str = "/v/";
+    pub const IMAGE_PATH: &'static str = "/i/";
+    pub const WATCH_PATH: &'static str = "/watch/";
+    pub const OEMBED_PATH: &'static str = "/oembed";
+    pub const THUMBNAIL_PATH: &'static str = "/thumbnail/";
+    pub const QR_CODE_PATH: &'static str = "/qr/";
     const AUTH_URL: &'static str = "https://login.mailchimp.com/oauth2/";
     const TOKEN_URL: &'static str = "https://login.mailchimp.com/oauth2/token";
     const METADATA_URL: &'static str = "https://login.mailchimp.com/oauth2/metadata";
+    /// D1 only allows 100 bound parameters per query, so members are inserted in chunks
+    const MEMBERS_PER_INSERT: usize = 25;
 
     pub fn login_url(env: &Env) -> url::Url {
         let mut url = url::Url::parse(Self::AUTH_URL)
@@ -169,6 +243,30 @@ impl Session {
         }
     }
 
+    pub async fn user_id(&self, session_id: impl Into<JsValue>) -> worker::Result<u64> {
+        #[derive(serde::Deserialize)]
+        struct DbSession {
+            #[serde(rename = "UserId")]
+            user_id: u64,
+        }
+
+        let sessions = self
+            .db
+            .prepare("SELECT UserId FROM UserSessions WHERE Id = ?;")
+            .bind(&[session_id.into()])?
+            .all()
+            .await?
+            .results::<DbSession>()?;
+
+        if let Some(session) = sessions.first() {
+            Ok(session.user_id)
+        } else {
+            Err(worker::Error::RustError(
+                "Failed to find a user for this session".into(),
+            ))
+        }
+    }
+
     pub async fn access_token_from_list_id(
         &self,
         list_id: impl Into<JsValue>,
@@ -190,18 +288,40 @@ impl Session {
         }
     }
 
+    /// Checks the secret embedded in a webhook url. When `list_id` is `None` the
+    /// secret only has to belong to some list, which is the case for the GET
+    /// validation request mailchimp sends while installing the webhook.
+    pub async fn verify_webhook_secret(
+        &self,
+        list_id: Option<&str>,
+        secret: &str,
+    ) -> worker::Result<bool> {
+        let statement = if let Some(list_id) = list_id {
+            self.db
+                .prepare("SELECT Id FROM Lists WHERE Id = ? AND WebhookSecret = ?;")
+                .bind(&[list_id.into(), secret.into()])?
+        } else {
+            self.db
+                .prepare("SELECT Id FROM Lists WHERE WebhookSecret = ?;")
+                .bind(&[secret.into()])?
+        };
+        let lists = statement.all().await?.results::<Value>()?;
+
+        Ok(!lists.is_empty())
+    }
+
     pub async fn get_existing_campaign_merge_fields_in(
         &self,
         campaigns: HashSet<String>,
-    ) -> worker::Result<HashMap<String, (String, String)>> {
+    ) -> worker::Result<HashMap<String, BTreeMap<String, String>>> {
         #[derive(serde::Deserialize)]
-        struct DbCampaign {
-            #[serde(rename = "Id")]
-            id: String,
-            #[serde(rename = "VideoTag")]
-            video_tag: String,
-            #[serde(rename = "ImageTag")]
-            image_tag: String,
+        struct DbField {
+            #[serde(rename = "CampaignId")]
+            campaign_id: String,
+            #[serde(rename = "Name")]
+            name: String,
+            #[serde(rename = "Tag")]
+            tag: String,
         }
 
         let campaigns = campaigns
@@ -210,106 +330,134 @@ impl Session {
             .collect::<Vec<_>>()
             .join(",");
 
-        Ok(self
+        let fields = self
             .db
             .prepare(format!(
-                "SELECT Id, VideoTag, ImageTag FROM Campaigns WHERE Id in ({});",
+                "SELECT CampaignId, Name, Tag FROM CampaignFields WHERE CampaignId in ({}) AND Tag IS NOT NULL;",
                 campaigns
             ))
             .bind(&[])?
             .all()
             .await?
-            .results::<DbCampaign>()?
-            .into_iter()
-            .map(|campaign| (campaign.id, (campaign.video_tag, campaign.image_tag)))
-            .collect())
+            .results::<DbField>()?;
+
+        let mut merge_fields = HashMap::<_, BTreeMap<_, _>>::new();
+        for field in fields {
+            merge_fields
+                .entry(field.campaign_id)
+                .or_default()
+                .insert(field.name, field.tag);
+        }
+
+        Ok(merge_fields)
     }
 
-    /// Adds a campaign to the
-    pub async fn add_campaign_to_table(
+    /// Adds a list to the table with its members and installs its webhook if
+    /// it was not added yet
+    pub async fn add_list_to_table(
         &self,
-        campaign: &MailChimpCampaign,
         session_id: impl Into<JsValue> + Copy,
-        video_tag: impl Into<JsValue>,
-        image_tag: impl Into<JsValue>,
+        list_id: &str,
     ) -> worker::Result<()> {
-        #[derive(serde::Deserialize)]
-        struct DbSession {
-            #[serde(rename = "UserId")]
-            user_id: u64,
-        }
-
-        let sessions = self
-            .db
-            .prepare("SELECT UserId FROM UserSessions WHERE Id = ?;")
-            .bind(&[session_id.into()])?
-            .all()
-            .await?
-            .results::<DbSession>()?;
-        let session = sessions.first().expect("Failed to find session");
-
+        let user_id = self.user_id(session_id).await?;
         let token = self.access_token(session_id).await?;
 
-        // Populate the lists table if it did not exist
         if self
             .db
             .prepare("SELECT * FROM Lists WHERE Id = ?;")
-            .bind(&[campaign.recipients.list_id.as_str().into()])?
+            .bind(&[list_id.into()])?
             .all()
             .await?
             .results::<Value>()?
             .first()
             .is_none()
         {
-            let list = List(campaign.recipients.list_id.clone());
-            let webhook_id = list
-                .install_webhook(&token, self.webhook_uri.as_str())
-                .await?;
+            let list = List(list_id.to_owned());
+            let webhook_secret = uuid::Uuid::new_v4().simple().to_string();
 
+            // The list has to be stored before the webhook is installed as mailchimp
+            // validates the webhook url with a GET request which checks the secret
             self.db
                 .prepare(format!(
-                    "INSERT INTO Lists VALUES (?, {}, ?);",
-                    session.user_id
+                    "INSERT INTO Lists (Id, UserId, WebhookId, WebhookSecret) VALUES (?, {}, '', ?);",
+                    user_id
                 ))
-                .bind(&[
-                    campaign.recipients.list_id.as_str().into(),
-                    webhook_id.as_str().into(),
-                ])?
+                .bind(&[list_id.into(), webhook_secret.as_str().into()])?
+                .all()
+                .await?;
+
+            let webhook_id = match list
+                .install_webhook(&token, &self.webhook_uri, &webhook_secret)
+                .await
+            {
+                Ok(webhook_id) => webhook_id,
+                Err(err) => {
+                    self.db
+                        .prepare("DELETE FROM Lists WHERE Id = ?;")
+                        .bind(&[list_id.into()])?
+                        .all()
+                        .await?;
+
+                    return Err(err);
+                }
+            };
+
+            self.db
+                .prepare("UPDATE Lists SET WebhookId = ? WHERE Id = ?;")
+                .bind(&[webhook_id.as_str().into(), list_id.into()])?
                 .all()
                 .await?;
             let members = list
                 .fetch_members(&token, Option::<&str>::None)
                 .await?
-                .members
-                .into_iter()
-                .map(|member| {
-                    format!(
-                        "('{}', '{}', '{}')",
-                        member.email_address, member.full_name, campaign.recipients.list_id
-                    )
-                })
-                .collect::<Vec<_>>()
-                .join(",");
+                .members;
+
+            for chunk in members.chunks(Self::MEMBERS_PER_INSERT) {
+                let values = chunk
+                    .iter()
+                    .flat_map(|member| {
+                        [
+                            member.email_address.as_str().into(),
+                            member.full_name.as_str().into(),
+                            list_id.into(),
+                            (member.is_active() as i32).into(),
+                        ]
+                    })
+                    .collect::<Vec<JsValue>>();
 
-            if !members.is_empty() {
                 self.db
-                    .exec(format!("INSERT INTO Members VALUES {};", members).as_str())
+                    .prepare(format!(
+                        "INSERT INTO Members (EmailId, FullName, ListId, Active) VALUES {};",
+                        vec!["(?, ?, ?, ?)"; chunk.len()].join(",")
+                    ))
+                    .bind(&values)?
+                    .all()
                     .await?;
             }
         }
 
-        // Populate the campaign table if it did not exist
+        Ok(())
+    }
+
+    /// Adds a campaign to the campaigns table, its list has to be added first
+    pub async fn add_campaign_to_table(
+        &self,
+        campaign: &MailChimpCampaign,
+        session_id: impl Into<JsValue> + Copy,
+    ) -> worker::Result<()> {
+        let user_id = self.user_id(session_id).await?;
+
+        // Populate the campaign table if it did not exist, keeping the fields of
+        // a campaign that is populated again
         self.db
             .prepare(format!(
-                "INSERT INTO Campaigns VALUES (?, ?, ?, {}, ?, ?);",
-                session.user_id
+                "INSERT INTO Campaigns (Id, Title, ListId, UserId) VALUES (?, ?, ?, {}) ON CONFLICT (Id) DO UPDATE SET Title = excluded.Title, Released = 0;",
+                user_id
             ))
             .bind(&[
                 campaign.id.as_str().into(),
                 campaign.settings.title.as_str().into(),
                 campaign.recipients.list_id.as_str().into(),
-                video_tag.into(),
-                image_tag.into(),
             ])?
             .all()
             .await?;
@@ -327,34 +475,30 @@ impl Session {
         let campaign = MailChimpCampaign::get(&token, campaign_id).await?;
         let list = List(campaign.recipients.list_id.clone());
 
-        let video_field = list
-            .get_or_add_merge_field(&token, &format!("Video/{}", campaign.id))
-            .await?;
-        let image_field = list
-            .get_or_add_merge_field(&token, &format!("Image/{}", campaign.id))
-            .await?;
-        self.add_campaign_to_table(&campaign, session_id, &video_field.tag, &image_field.tag)
-            .await?;
+        self.add_list_to_table(session_id, &list.0).await?;
+        self.add_campaign_to_table(&campaign, session_id).await?;
 
-        let values = list
-            .fetch_members(&token, Option::<&str>::None)
-            .await?
-            .members
-            .into_iter()
-            .map(|member| {
-                (
-                    member.email_address,
-                    vec![
-                        (&video_field.tag, "vimeo.com/226053498"),
-                        (&image_field.tag, "s3.amazonaws.com/creare-websites-wpms-legacy/wp-content/uploads/sites/32/2016/03/01200959/canstockphoto22402523-arcos-creator.com_-1024x1024.jpg"),
-                    ],
-                )
-            });
-        list.set_member_merge_field_batch(&token, values).await?;
+        let Some(mut db_campaign) = self.db_campaign(&campaign.id).await? else {
+            return Err(worker::Error::RustError(
+                "Failed to find the campaign that was just added".into(),
+            ));
+        };
+        if db_campaign.fields.is_empty() {
+            db_campaign.fields = CampaignField::defaults();
+            self.store_campaign_fields(&db_campaign.id, &db_campaign.fields)
+                .await?;
+        }
+
+        if !self.acquire_field_tags(&token, &mut db_campaign).await? {
+            return Self::merge_fields_exhausted(&list);
+        }
+        self.assign_coupon_codes(&db_campaign).await?;
+        self.push_campaign_values(&token, &db_campaign, None)
+            .await?;
 
         Response::from_json(&serde_json::json!({
-            "video_tag": video_field.tag,
-            "image_tag": image_field.tag,
+            "merge_tags": db_campaign.merge_tags(),
+            "coupons": self.coupon_report(&db_campaign).await?,
         }))
     }
 
@@ -362,46 +506,20 @@ impl Session {
         &self,
         token: &Token,
         email: &str,
-        name: impl Into<JsValue>,
+        name: &str,
+        merges: &BTreeMap<String, String>,
         list_id: &str,
     ) -> worker::Result<()> {
         self.db
-            .prepare("INSERT INTO Members VALUES (?, ?, ?);")
+            .prepare("INSERT INTO Members (EmailId, FullName, ListId) VALUES (?, ?, ?) ON CONFLICT (EmailId, ListId) DO UPDATE SET FullName = excluded.FullName, Active = 1;")
             .bind(&[email.into(), name.into(), list_id.into()])?
             .all()
             .await?;
 
-        #[derive(serde::Deserialize)]
-        struct DbCampaign {
-            #[serde(rename = "VideoTag")]
-            video_tag: String,
-            #[serde(rename = "ImageTag")]
-            image_tag: String,
-        }
-
-        let list = List(list_id.to_owned());
-
-        let values = self
-            .db
-            .prepare("SELECT VideoTag, ImageTag FROM Campaigns WHERE ListId = ?;")
-            .bind(&[list_id.into()])?
-            .all()
-            .await?
-            .results::<DbCampaign>()?
-            .into_iter()
-            .map(|campaign| {
-                (
-                    email,
-                    vec![
-                        (campaign.video_tag, "vimeo.com/226053498"),
-                        (campaign.image_tag, "s3.amazonaws.com/creare-websites-wpms-legacy/wp-content/uploads/sites/32/2016/03/01200959/canstockphoto22402523-arcos-creator.com_-1024x1024.jpg"),
-                    ],
-                )
-            });
-
-        list.set_member_merge_field_batch(&token, values).await?;
-
-        Ok(())
+        let campaigns = self.campaigns_of_list(list_id).await?;
+        self.assign_member_coupon_codes(&campaigns, email).await?;
+        self.push_member_values(token, &campaigns, email, name, merges, list_id)
+            .await
     }
 
     pub async fn update_member(
@@ -409,67 +527,572 @@ impl Session {
         token: &Token,
         email: &str,
         name: &str,
+        merges: &BTreeMap<String, String>,
         list_id: &str,
     ) -> worker::Result<()> {
         #[derive(serde::Deserialize)]
         struct DbMember {
             #[serde(rename = "FullName")]
             name: String,
+            #[serde(rename = "Active")]
+            active: u8,
         }
 
         let members = self
             .db
-            .prepare("SELECT FullName FROM Members WHERE EmailId = ?;")
-            .bind(&[email.into()])?
+            .prepare("SELECT FullName, Active FROM Members WHERE EmailId = ? AND ListId = ?;")
+            .bind(&[email.into(), list_id.into()])?
             .all()
             .await?
             .results::<DbMember>()?;
 
         let Some(member) = members.first() else {
-            return Err(worker::Error::RustError("Failed to find the user will email id".into()));
+            return Err(worker::Error::RustError(
+                "Failed to find the user will email id".into(),
+            ));
         };
 
-        let list = List(list_id.to_owned());
+        // Members that unsubscribed or were cleaned are no longer personalized
+        if member.active == 0 {
+            return Ok(());
+        }
 
+        let name_changed = member.name != name;
+        if name_changed {
+            self.db
+                .prepare("UPDATE Members SET FullName = ? WHERE EmailId = ? AND ListId = ?;")
+                .bind(&[name.into(), email.into(), list_id.into()])?
+                .all()
+                .await?;
+        }
+
+        // Templates can use any merge field, so their values are rendered again
+        let campaigns = self.campaigns_of_list(list_id).await?;
+        if name_changed || campaigns.iter().any(|campaign| campaign.has_templates()) {
+            self.push_member_values(token, &campaigns, email, name, merges, list_id)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Verifies that the webhook of every list of the session's user still exists
+    /// and points at the current `MAILCHIMP_BASE_URI`, reinstalling it otherwise
+    pub async fn check_webhooks(
+        &self,
+        session_id: impl Into<JsValue> + Copy,
+    ) -> worker::Result<Response> {
         #[derive(serde::Deserialize)]
-        struct DbCampaign {
-            #[serde(rename = "VideoTag")]
-            video_tag: String,
-            #[serde(rename = "ImageTag")]
-            image_tag: String,
+        struct DbList {
+            #[serde(rename = "Id")]
+            id: String,
+            #[serde(rename = "WebhookId")]
+            webhook_id: String,
+            #[serde(rename = "WebhookSecret")]
+            webhook_secret: String,
+        }
+
+        let user_id = self.user_id(session_id).await?;
+        let token = self.access_token(session_id).await?;
+
+        let lists = self
+            .db
+            .prepare(format!(
+                "SELECT Id, WebhookId, WebhookSecret FROM Lists WHERE UserId = {};",
+                user_id
+            ))
+            .bind(&[])?
+            .all()
+            .await?
+            .results::<DbList>()?;
+
+        let mut report = Vec::with_capacity(lists.len());
+        for db_list in lists {
+            // A list failing to be checked, e.g. as its account was closed, is
+            // reported without keeping the others from being checked
+            let checked = async {
+                let list = List(db_list.id.clone());
+                let url = List::webhook_url(&self.webhook_uri, &db_list.webhook_secret);
+
+                let webhook = if db_list.webhook_id.is_empty() {
+                    None
+                } else {
+                    list.webhook(&token, &db_list.webhook_id).await?
+                };
+
+                let (webhook_id, status) = match webhook {
+                    Some(webhook) if webhook.is_current(&url) => (webhook.id, "ok"),
+                    // The webhook still points at a previous base uri or had some
+                    // of its events or sources turned off
+                    Some(webhook) => {
+                        let webhook = list
+                            .update_webhook(
+                                &token,
+                                &webhook.id,
+                                &self.webhook_uri,
+                                &db_list.webhook_secret,
+                            )
+                            .await?;
+
+                        (webhook.id, "updated")
+                    }
+                    None => {
+                        // Reuse a webhook with our url if its id was never stored
+                        let existing = list
+                            .webhooks(&token)
+                            .await?
+                            .into_iter()
+                            .find(|webhook| webhook.url == url.as_str());
+
+                        match existing {
+                            Some(webhook) if webhook.is_current(&url) => (webhook.id, "relinked"),
+                            Some(webhook) => {
+                                let webhook = list
+                                    .update_webhook(
+                                        &token,
+                                        &webhook.id,
+                                        &self.webhook_uri,
+                                        &db_list.webhook_secret,
+                                    )
+                                    .await?;
+
+                                (webhook.id, "relinked")
+                            }
+                            None => {
+                                let webhook_id = list
+                                    .install_webhook(
+                                        &token,
+                                        &self.webhook_uri,
+                                        &db_list.webhook_secret,
+                                    )
+                                    .await?;
+
+                                (webhook_id, "reinstalled")
+                            }
+                        }
+                    }
+                };
+
+                // Drop other webhooks calling this worker, e.g. left behind by an
+                // install whose id was never stored
+                for webhook in list.webhooks(&token).await? {
+                    if webhook.id != webhook_id
+                        && webhook.url.starts_with(self.webhook_uri.as_str())
+                    {
+                        list.delete_webhook(&token, &webhook.id).await?;
+                    }
+                }
+
+                if webhook_id != db_list.webhook_id {
+                    self.db
+                        .prepare("UPDATE Lists SET WebhookId = ? WHERE Id = ?;")
+                        .bind(&[webhook_id.as_str().into(), db_list.id.as_str().into()])?
+                        .all()
+                        .await?;
+                }
+
+                worker::Result::Ok((webhook_id, status))
+            }
+            .await;
+
+            report.push(match checked {
+                Ok((webhook_id, status)) => serde_json::json!({
+                    "list_id": db_list.id,
+                    "webhook_id": webhook_id,
+                    "status": status,
+                }),
+                Err(err) => serde_json::json!({
+                    "list_id": db_list.id,
+                    "status": "error",
+                    "error": err.to_string(),
+                }),
+            });
         }
 
-        if member.name != name {
+        Response::from_json(&serde_json::json!({
+            "lists": report,
+        }))
+    }
+
+    /// Logs a webhook call. A call with the same content hash as a call logged
+    /// within the dedup window is not logged again, the earlier record is
+    /// returned instead.
+    pub async fn log_webhook_event(
+        &self,
+        list_id: &str,
+        body: &[u8],
+    ) -> worker::Result<WebhookEventRecord> {
+        let hash = WebhookEvent::content_hash(body);
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+        self.db
+            .prepare(format!(
+                "INSERT INTO WebhookEvents (ListId, Type, Payload, Hash, Status, ReceivedAt) SELECT ?1, ?2, ?3, ?4, ?5, {} WHERE NOT EXISTS (SELECT 1 FROM WebhookEvents WHERE Hash = ?4 AND ReceivedAt >= {});",
+                now,
+                now - WebhookEventRecord::DEDUP_WINDOW_SECS
+            ))
+            .bind(&[
+                list_id.into(),
+                WebhookEvent::type_of(body).unwrap_or_default().into(),
+                String::from_utf8_lossy(body).into_owned().into(),
+                hash.as_str().into(),
+                WebhookEventRecord::PENDING.into(),
+            ])?
+            .all()
+            .await?;
+
+        let mut events = self
+            .db
+            .prepare("SELECT * FROM WebhookEvents WHERE Hash = ? ORDER BY Id DESC LIMIT 1;")
+            .bind(&[hash.as_str().into()])?
+            .all()
+            .await?
+            .results::<WebhookEventRecord>()?;
+
+        events.pop().ok_or(worker::Error::RustError(
+            "Failed to log the webhook call".into(),
+        ))
+    }
+
+    /// Marks a logged webhook call as being processed, unless it is already
+    /// handled or another request is processing it. Only the request the
+    /// claimed record is returned to may run it.
+    pub async fn claim_webhook_event(
+        &self,
+        event_id: i64,
+    ) -> worker::Result<Option<WebhookEventRecord>> {
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+        Ok(self
+            .db
+            .prepare(format!(
+                "UPDATE WebhookEvents SET Status = ?1, ProcessedAt = {} WHERE Id = {} AND (Status IN (?2, ?3) OR (Status = ?1 AND ProcessedAt < {})) RETURNING *;",
+                now,
+                event_id,
+                now - WebhookEventRecord::CLAIM_TIMEOUT_SECS
+            ))
+            .bind(&[
+                WebhookEventRecord::PROCESSING.into(),
+                WebhookEventRecord::PENDING.into(),
+                WebhookEventRecord::FAILED.into(),
+            ])?
+            .all()
+            .await?
+            .results::<WebhookEventRecord>()?
+            .pop())
+    }
+
+    /// Lists the logged webhook calls of the lists owned by the session's user
+    pub async fn webhook_events(
+        &self,
+        session_id: impl Into<JsValue>,
+        status: Option<&str>,
+    ) -> worker::Result<Vec<WebhookEventRecord>> {
+        let user_id = self.user_id(session_id).await?;
+
+        let statement = if let Some(status) = status {
             self.db
-                .prepare("UPDATE Members SET FullName = ? WHERE  EmailId = ?;")
-                .bind(&[name.into(), email.into()])?
-                .all()
+                .prepare(format!(
+                    "SELECT * FROM WebhookEvents WHERE ListId IN (SELECT Id FROM Lists WHERE UserId = {}) AND Status = ? ORDER BY Id DESC LIMIT 100;",
+                    user_id
+                ))
+                .bind(&[status.into()])?
+        } else {
+            self.db
+                .prepare(format!(
+                    "SELECT * FROM WebhookEvents WHERE ListId IN (SELECT Id FROM Lists WHERE UserId = {}) ORDER BY Id DESC LIMIT 100;",
+                    user_id
+                ))
+                .bind(&[])?
+        };
+
+        statement.all().await?.results()
+    }
+
+    /// Fetches a logged webhook call if it belongs to one of the session user's lists
+    pub async fn webhook_event(
+        &self,
+        session_id: impl Into<JsValue>,
+        event_id: i64,
+    ) -> worker::Result<Option<WebhookEventRecord>> {
+        let user_id = self.user_id(session_id).await?;
+
+        Ok(self
+            .db
+            .prepare(format!(
+                "SELECT * FROM WebhookEvents WHERE Id = {} AND ListId IN (SELECT Id FROM Lists WHERE UserId = {});",
+                event_id, user_id
+            ))
+            .bind(&[])?
+            .all()
+            .await?
+            .results::<WebhookEventRecord>()?
+            .pop())
+    }
+
+    async fn set_webhook_event_status(
+        &self,
+        event_id: i64,
+        status: &str,
+        error: Option<String>,
+    ) -> worker::Result<()> {
+        self.db
+            .prepare(format!(
+                "UPDATE WebhookEvents SET Status = ?, Error = ?, ProcessedAt = {} WHERE Id = {};",
+                time::OffsetDateTime::now_utc().unix_timestamp(),
+                event_id
+            ))
+            .bind(&[
+                status.into(),
+                error.map(JsValue::from).unwrap_or(JsValue::NULL),
+            ])?
+            .all()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Processes a claimed webhook call and records the outcome on the log. This
+    /// is used both for incoming calls and for replaying failed ones.
+    pub async fn run_webhook_event(&self, record: &WebhookEventRecord) -> worker::Result<Response> {
+        let event = match WebhookEvent::parse(record.payload.as_bytes()) {
+            Ok(event) => event,
+            // Mailchimp retries failed calls, so unknown types are acknowledged
+            Err(WebhookError::UnsupportedType(_)) => {
+                self.set_webhook_event_status(record.id, WebhookEventRecord::IGNORED, None)
+                    .await?;
+
+                return Response::ok("ignored");
+            }
+            Err(err) => {
+                self.set_webhook_event_status(
+                    record.id,
+                    WebhookEventRecord::FAILED,
+                    Some(err.to_string()),
+                )
                 .await?;
 
-            let values = self
-                .db
-                .prepare("SELECT VideoTag, ImageTag FROM Campaigns WHERE ListId = ?;")
-                .bind(&[list_id.into()])?
-                .all()
-                .await?
-                .results::<DbCampaign>()?
-                .into_iter()
-                .map(|campaign| {
-                    (
-                        email,
-                        vec![
-                            (campaign.video_tag, "vimeo.com/226053498"),
-                            (campaign.image_tag, "s3.amazonaws.com/creare-websites-wpms-legacy/wp-content/uploads/sites/32/2016/03/01200959/canstockphoto22402523-arcos-creator.com_-1024x1024.jpg"),
-                        ],
+                return Response::error(err.to_string(), 400);
+            }
+        };
+
+        match self.process_webhook_event(&event).await {
+            Ok(message) => {
+                self.set_webhook_event_status(record.id, WebhookEventRecord::PROCESSED, None)
+                    .await?;
+
+                Response::ok(message)
+            }
+            Err(err) => {
+                self.set_webhook_event_status(
+                    record.id,
+                    WebhookEventRecord::FAILED,
+                    Some(err.to_string()),
+                )
+                .await?;
+
+                Response::error(err.to_string(), 500)
+            }
+        }
+    }
+
+    async fn process_webhook_event(&self, event: &WebhookEvent) -> worker::Result<&'static str> {
+        match event {
+            WebhookEvent::Subscribe { member, .. } => {
+                let token = self
+                    .access_token_from_list_id(member.list_id.as_str())
+                    .await?;
+                self.subscribe_member(
+                    &token,
+                    &member.email,
+                    &member.merges.full_name(),
+                    &member.merges.fields,
+                    &member.list_id,
+                )
+                .await?;
+
+                Ok("added")
+            }
+            WebhookEvent::Profile { member, .. } => {
+                let token = self
+                    .access_token_from_list_id(member.list_id.as_str())
+                    .await?;
+                self.update_member(
+                    &token,
+                    &member.email,
+                    &member.merges.full_name(),
+                    &member.merges.fields,
+                    &member.list_id,
+                )
+                .await?;
+
+                Ok("updated")
+            }
+            WebhookEvent::Unsubscribe { member, .. } => {
+                self.deactivate_member(&member.email, &member.list_id)
+                    .await?;
+
+                Ok("deactivated")
+            }
+            WebhookEvent::Cleaned { email, list_id, .. } => {
+                self.deactivate_member(email, list_id).await?;
+
+                Ok("deactivated")
+            }
+            WebhookEvent::UpEmail {
+                list_id,
+                new_email,
+                old_email,
+                ..
+            } => {
+                self.change_member_email(old_email, new_email, list_id)
+                    .await?;
+
+                Ok("email changed")
+            }
+            WebhookEvent::Campaign {
+                fired_at,
+                id,
+                list_id,
+                status,
+                reason,
+                ..
+            } => {
+                let known = self
+                    .update_campaign_status(
+                        id,
+                        list_id,
+                        status,
+                        reason.as_deref().unwrap_or_default(),
+                        fired_at,
                     )
-                });
+                    .await?;
+                if !known {
+                    return Ok("campaign is not on the list");
+                }
+
+                // The links of a sent campaign are part of the sent emails, so its
+                // merge fields can be used by the next campaign
+                if status == "sent" {
+                    let token = self.access_token_from_list_id(list_id.as_str()).await?;
+                    self.release_merge_fields(&token, id).await?;
+
+                    return Ok("campaign sent, merge fields released");
+                }
 
-            list.set_member_merge_field_batch(&token, values).await?;
+                Ok("campaign status recorded")
+            }
         }
+    }
+
+    /// Marks a member as inactive after they unsubscribed or were cleaned so that
+    /// their merge fields stop being populated
+    pub async fn deactivate_member(&self, email: &str, list_id: &str) -> worker::Result<()> {
+        self.db
+            .prepare("UPDATE Members SET Active = 0 WHERE EmailId = ? AND ListId = ?;")
+            .bind(&[email.into(), list_id.into()])?
+            .all()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Re-keys a member and their personalizations after they changed their email
+    /// address. Mailchimp keeps the merge values on the member, so only our copy
+    /// has to be updated.
+    pub async fn change_member_email(
+        &self,
+        old_email: &str,
+        new_email: &str,
+        list_id: &str,
+    ) -> worker::Result<()> {
+        self.db
+            .prepare("UPDATE OR REPLACE Members SET EmailId = ? WHERE EmailId = ? AND ListId = ?;")
+            .bind(&[new_email.into(), old_email.into(), list_id.into()])?
+            .all()
+            .await?;
+        self.db
+            .prepare("UPDATE OR REPLACE Personalizations SET EmailId = ? WHERE EmailId = ? AND CampaignId IN (SELECT Id FROM Campaigns WHERE ListId = ?);")
+            .bind(&[
+                Personalization::normalize_email(new_email).into(),
+                Personalization::normalize_email(old_email).into(),
+                list_id.into(),
+            ])?
+            .all()
+            .await?;
+        // A member keeps its codes and links unless the new email already holds its own
+        self.db
+            .prepare("UPDATE OR IGNORE CouponCodes SET EmailId = ? WHERE EmailId = ? AND CampaignId IN (SELECT Id FROM Campaigns WHERE ListId = ?);")
+            .bind(&[
+                Personalization::normalize_email(new_email).into(),
+                Personalization::normalize_email(old_email).into(),
+                list_id.into(),
+            ])?
+            .all()
+            .await?;
+        self.db
+            .prepare("UPDATE OR IGNORE Links SET EmailId = ? WHERE EmailId = ? AND CampaignId IN (SELECT Id FROM Campaigns WHERE ListId = ?);")
+            .bind(&[
+                Personalization::normalize_email(new_email).into(),
+                Personalization::normalize_email(old_email).into(),
+                list_id.into(),
+            ])?
+            .all()
+            .await?;
 
         Ok(())
     }
 
+    /// Records the send status mailchimp reported for a campaign. Only
+    /// campaigns of the list the webhook call was authenticated for are
+    /// updated, returns `false` if the campaign is not one of them.
+    pub async fn update_campaign_status(
+        &self,
+        campaign_id: &str,
+        list_id: &str,
+        status: &str,
+        reason: &str,
+        fired_at: &str,
+    ) -> worker::Result<bool> {
+        #[derive(serde::Deserialize)]
+        struct DbCampaignId {
+            #[serde(rename = "Id")]
+            _id: String,
+        }
+
+        let updated = self
+            .db
+            .prepare("UPDATE Campaigns SET Status = ?, StatusReason = ?, StatusChangedAt = ? WHERE Id = ? AND ListId = ? RETURNING Id;")
+            .bind(&[
+                status.into(),
+                reason.into(),
+                fired_at.into(),
+                campaign_id.into(),
+                list_id.into(),
+            ])?
+            .all()
+            .await?
+            .results::<DbCampaignId>()?;
+        if updated.is_empty() {
+            return Ok(false);
+        }
+
+        // Hits on tracked links while the emails are delivered come from
+        // scanners, delivery taking longer the more members the list has
+        if status == "sent" {
+            self.db
+                .prepare(format!(
+                    "UPDATE Campaigns SET SentAt = {}, Recipients = (SELECT COUNT(*) FROM Members WHERE Members.ListId = Campaigns.ListId AND Members.Active = 1) WHERE Id = ? AND SentAt IS NULL;",
+                    time::OffsetDateTime::now_utc().unix_timestamp()
+                ))
+                .bind(&[campaign_id.into()])?
+                .all()
+                .await?;
+        }
+
+        Ok(true)
+    }
+
     async fn get_user(&self, user_id: impl std::fmt::Display) -> worker::Result<User> {
         // BUGFIX: Binding the query normall was causing issues
         let query = format!("SELECT * FROM Users WHERE Id = {};", user_id);
@@ -512,18 +1135,54 @@ impl Session {
             .join(Self::WEBHOOK_CALLBACK)
             .expect("Failed to join the token endpoint")
     }
+
+    /// The url the worker is reached at, which its endpoints are joined to
+    fn base_uri_from_env(env: &Env) -> url::Url {
+        env.secret("MAILCHIMP_BASE_URI")
+            .expect("Failed to find MAILCHIMP_BASE_URI secret")
+            .to_string()
+            .parse::<url::Url>()
+            .expect("MAILCHIMP_BASE_URI is not a valid uri")
+    }
+
+    fn link_key_from_env(env: &Env) -> Option<String> {
+        env.secret("LINK_SIGNING_KEY")
+            .ok()
+            .map(|secret| secret.to_string())
+    }
+
+    /// The key personalized links are signed with
+    fn link_key(&self) -> worker::Result<&[u8]> {
+        self.link_key.as_deref().map(str::as_bytes).ok_or_else(|| {
+            worker::Error::RustError("Failed to find LINK_SIGNING_KEY secret".into())
+        })
+    }
 }
 
 impl TryFrom<&Env> for Session {
     type Error = worker::Error;
 
     fn try_from(env: &Env) -> Result<Self, Self::Error> {
+        let base_uri = Self::base_uri_from_env(env);
+        let endpoint = |path| {
+            base_uri
+                .join(path)
+                .expect("Failed to join the endpoint to MAILCHIMP_BASE_URI")
+        };
+
         Ok(Session {
             db: env.d1(Self::BINDING)?,
             client_id: Self::client_id_from_env(&env),
             client_secret: Self::client_secret_from_env(&env),
             webhook_uri: Self::webhook_uri_from_env(&env),
             redirect_uri: Self::redirect_uri_from_env(&env),
+            link_uri: endpoint(Self::LINK_PATH),
+            image_uri: endpoint(Self::IMAGE_PATH),
+            watch_uri: endpoint(Self::WATCH_PATH),
+            oembed_uri: endpoint(Self::OEMBED_PATH),
+            thumbnail_uri: endpoint(Self::THUMBNAIL_PATH),
+            qr_code_uri: endpoint(Self::QR_CODE_PATH),
+            link_key: Self::link_key_from_env(env),
         })
     }
 }
@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+
+use worker::{wasm_bindgen::JsValue, Response};
+
+use super::{Personalization, Session};
+use crate::{
+    content_lint::{self, Severity},
+    mailchimp::{
+        campaign::{CampaignContent, MailChimpCampaign},
+        lists::List,
+        Token,
+    },
+    preview,
+    snippet::{Placement, Snippet},
+    url_template::TemplateMember,
+};
+
+/// Which fields the inserted snippet links together, and where it goes
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct SnippetParams {
+    #[serde(default)]
+    pub placement: Placement,
+    /// The url field the thumbnail links to
+    #[serde(default = "SnippetParams::default_link_field")]
+    pub link_field: String,
+    #[serde(default = "SnippetParams::default_image_field")]
+    pub image_field: String,
+}
+
+impl SnippetParams {
+    fn default_link_field() -> String {
+        "video".into()
+    }
+
+    fn default_image_field() -> String {
+        "image".into()
+    }
+}
+
+impl Session {
+    pub async fn campaign_content(
+        &self,
+        session_id: impl Into<JsValue>,
+        campaign_id: &str,
+    ) -> worker::Result<Response> {
+        let token = self.access_token(session_id).await?;
+
+        Response::from_json(&MailChimpCampaign::content(&token, campaign_id).await?)
+    }
+
+    pub async fn set_campaign_content(
+        &self,
+        session_id: impl Into<JsValue>,
+        campaign_id: &str,
+        content: CampaignContent,
+    ) -> worker::Result<Response> {
+        let token = self.access_token(session_id).await?;
+        if let Some(resp) = Self::uneditable_campaign(&token, campaign_id).await? {
+            return Ok(resp);
+        }
+
+        Response::from_json(&MailChimpCampaign::set_content(&token, campaign_id, &content).await?)
+    }
+
+    /// Inserts the linked thumbnail of the campaign's fields in its html, along
+    /// with a plain-text fallback. Inserting it again replaces the earlier
+    /// snippet, so it is safe to run after the merge tags changed.
+    pub async fn insert_snippet(
+        &self,
+        session_id: impl Into<JsValue> + Copy,
+        campaign_id: &str,
+        params: SnippetParams,
+    ) -> worker::Result<Response> {
+        let Some(campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+        let tags = campaign.merge_tags();
+        let (Some(link_tag), Some(image_tag)) = (
+            tags.get(params.link_field.as_str()),
+            tags.get(params.image_field.as_str()),
+        ) else {
+            return Response::error(
+                format!(
+                    "The campaign has no merge fields for '{}' and '{}', populate its merge fields first",
+                    params.link_field, params.image_field
+                ),
+                409,
+            );
+        };
+        let snippet = Snippet::video(link_tag, image_tag);
+
+        let token = self.access_token(session_id).await?;
+        if let Some(resp) = Self::uneditable_campaign(&token, campaign_id).await? {
+            return Ok(resp);
+        }
+
+        let content = MailChimpCampaign::content(&token, campaign_id).await?;
+        let (html, insertion) = match snippet.insert_html(
+            content.html.as_deref().unwrap_or_default(),
+            &params.placement,
+        ) {
+            Ok(inserted) => inserted,
+            Err(err) => return Response::error(err, 400),
+        };
+        let plain_text = snippet.insert_plain_text(
+            content.plain_text.as_deref().unwrap_or_default(),
+            &params.placement,
+        );
+
+        let content = MailChimpCampaign::set_content(
+            &token,
+            campaign_id,
+            &CampaignContent {
+                html: Some(html),
+                plain_text: Some(plain_text),
+            },
+        )
+        .await?;
+
+        Response::from_json(&serde_json::json!({
+            "snippet": insertion,
+            "html": content.html,
+            "plain_text": content.plain_text,
+        }))
+    }
+
+    /// Checks the html of a campaign for merge tags of its fields that are
+    /// missing or misused, and for tags that are no merge fields of its list
+    pub async fn lint_content(
+        &self,
+        session_id: impl Into<JsValue> + Copy,
+        campaign_id: &str,
+    ) -> worker::Result<Response> {
+        let Some(campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+        let token = self.access_token(session_id).await?;
+
+        let content = MailChimpCampaign::content(&token, campaign_id).await?;
+        let list_fields = List(campaign.list_id.clone()).merge_fields(&token).await?;
+        let issues = content_lint::lint(
+            content.html.as_deref().unwrap_or_default(),
+            &campaign.fields,
+            &list_fields,
+        );
+
+        Response::from_json(&serde_json::json!({
+            "ok": issues.iter().all(|issue| issue.severity != Severity::Error),
+            "issues": issues,
+        }))
+    }
+
+    /// Renders the html of a campaign for a member of its list, with the
+    /// member's merge fields and the values of the campaign's fields filled in
+    pub async fn preview(
+        &self,
+        session_id: impl Into<JsValue> + Copy,
+        campaign_id: &str,
+        email: &str,
+    ) -> worker::Result<Response> {
+        let Some(campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+        let token = self.access_token(session_id).await?;
+
+        let Some(member) = List(campaign.list_id.clone()).member(&token, email).await? else {
+            return Response::error(format!("'{email}' is not a member of the list"), 404);
+        };
+        let personalizations = self
+            .member_personalizations(&member.email_address, &campaign.list_id)
+            .await?;
+
+        let merges = member.merge_values();
+        let mut values = merges
+            .iter()
+            .map(|(tag, value)| (tag.to_ascii_uppercase(), value.clone()))
+            .collect::<BTreeMap<_, _>>();
+        values.insert("EMAIL".into(), member.email_address.clone());
+        values.extend(campaign.merge_values(
+            personalizations.get(&campaign.id),
+            &TemplateMember {
+                email: &member.email_address,
+                name: &member.full_name,
+                merges: &merges,
+            },
+        ));
+
+        let content = MailChimpCampaign::content(&token, campaign_id).await?;
+
+        Response::from_json(&serde_json::json!({
+            "email": Personalization::normalize_email(&member.email_address),
+            "html": preview::render(content.html.as_deref().unwrap_or_default(), &values),
+        }))
+    }
+
+    /// Mailchimp only lets the content of campaigns that were not sent or
+    /// scheduled be edited. Campaigns built from template blocks are refused
+    /// too, as setting their html turns them into code your own campaigns.
+    async fn uneditable_campaign(
+        token: &Token,
+        campaign_id: &str,
+    ) -> worker::Result<Option<Response>> {
+        let Some(campaign) = MailChimpCampaign::find(token, campaign_id).await? else {
+            return Response::error("Campaign not found", 404).map(Some);
+        };
+
+        if !matches!(campaign.status.as_str(), "save" | "paused") {
+            return Response::error(
+                format!(
+                    "The content of a campaign with the status '{}' can not be edited",
+                    campaign.status
+                ),
+                409,
+            )
+            .map(Some);
+        }
+        if matches!(campaign.content_type.as_str(), "template" | "multichannel") {
+            return Response::error(
+                "The campaign is built from a template, editing its html would drop the template's blocks. Insert the merge tags in the campaign builder instead.",
+                409,
+            )
+            .map(Some);
+        }
+
+        Ok(None)
+    }
+}
@@ -0,0 +1,321 @@
+use worker::{wasm_bindgen::JsValue, Response};
+
+use super::{fields::FieldType, personalization::DbCampaign, Personalization, Session};
+
+/// The codes of a coupon field's pool, and how many are left
+#[derive(serde::Deserialize)]
+struct DbPool {
+    #[serde(rename = "Total")]
+    total: u64,
+    #[serde(rename = "Assigned")]
+    assigned: u64,
+    #[serde(rename = "Waiting")]
+    waiting: u64,
+}
+
+impl DbPool {
+    /// The pool is exhausted once members are waiting for a code none is left for
+    fn report(&self, field: &str) -> serde_json::Value {
+        let available = self.total - self.assigned;
+
+        serde_json::json!({
+            "field": field,
+            "total": self.total,
+            "assigned": self.assigned,
+            "available": available,
+            "members_without_code": self.waiting,
+            "exhausted": available == 0 && self.waiting > 0,
+        })
+    }
+}
+
+/// Reads the codes of a pool upload, one per line or in the first column of
+/// a csv, along with the lines that hold no valid code
+fn parse_coupon_codes(body: &str) -> (Vec<String>, Vec<serde_json::Value>) {
+    let mut codes = Vec::new();
+    let mut rejected = Vec::new();
+    for (index, line) in body.lines().enumerate() {
+        let cell = line
+            .split(',')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .trim_matches('"');
+        if cell.is_empty() {
+            continue;
+        }
+        // Only the exact headers are skipped, as codes may contain `code`
+        if index == 0
+            && matches!(
+                cell.to_lowercase().as_str(),
+                "code" | "coupon code" | "coupon_code"
+            )
+        {
+            continue;
+        }
+
+        match FieldType::Coupon.normalize(cell) {
+            Ok(code) => codes.push(code),
+            Err(err) => rejected.push(serde_json::json!({
+                "line": index + 1,
+                "error": err,
+            })),
+        }
+    }
+
+    (codes, rejected)
+}
+
+impl Session {
+    /// D1 only allows 100 bound parameters per query, so bulk inserts are chunked
+    const CODES_PER_INSERT: usize = 30;
+
+    /// Gives every active member of a campaign's list that has no code of a
+    /// coupon field an unused code of the field's pool. Each field is assigned
+    /// in a single statement so that no code can go to two members.
+    pub(super) async fn assign_coupon_codes(&self, campaign: &DbCampaign) -> worker::Result<()> {
+        for field in campaign
+            .fields
+            .iter()
+            .filter(|field| field.ty == FieldType::Coupon)
+        {
+            self.db
+                .prepare(format!(
+                    "WITH Pending AS (SELECT DISTINCT lower(trim(EmailId)) AS EmailId FROM Members WHERE ListId = ?1 AND Active = 1 AND lower(trim(EmailId)) NOT IN (SELECT EmailId FROM CouponCodes WHERE CampaignId = ?2 AND Field = ?3 AND EmailId IS NOT NULL)), \
+                    Waiting AS (SELECT EmailId, ROW_NUMBER() OVER (ORDER BY EmailId) AS N FROM Pending), \
+                    Codes AS (SELECT Code, ROW_NUMBER() OVER (ORDER BY rowid) AS N FROM CouponCodes WHERE CampaignId = ?2 AND Field = ?3 AND EmailId IS NULL), \
+                    Assignments AS (SELECT Codes.Code, Waiting.EmailId FROM Codes JOIN Waiting ON Codes.N = Waiting.N) \
+                    UPDATE CouponCodes SET EmailId = (SELECT EmailId FROM Assignments WHERE Assignments.Code = CouponCodes.Code), AssignedAt = {} \
+                    WHERE CampaignId = ?2 AND Field = ?3 AND Code IN (SELECT Code FROM Assignments);",
+                    time::OffsetDateTime::now_utc().unix_timestamp()
+                ))
+                .bind(&[
+                    campaign.list_id.as_str().into(),
+                    campaign.id.as_str().into(),
+                    field.name.as_str().into(),
+                ])?
+                .all()
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Gives a member that has no code of a coupon field an unused one, for
+    /// each coupon field of the `campaigns`
+    pub(super) async fn assign_member_coupon_codes(
+        &self,
+        campaigns: &[DbCampaign],
+        email: &str,
+    ) -> worker::Result<()> {
+        let email = Personalization::normalize_email(email);
+
+        for campaign in campaigns {
+            for field in campaign
+                .fields
+                .iter()
+                .filter(|field| field.ty == FieldType::Coupon)
+            {
+                self.db
+                    .prepare(format!(
+                        "UPDATE CouponCodes SET EmailId = ?1, AssignedAt = {} \
+                        WHERE CampaignId = ?2 AND Field = ?3 \
+                        AND Code = (SELECT Code FROM CouponCodes WHERE CampaignId = ?2 AND Field = ?3 AND EmailId IS NULL ORDER BY rowid LIMIT 1) \
+                        AND NOT EXISTS (SELECT 1 FROM CouponCodes WHERE CampaignId = ?2 AND Field = ?3 AND EmailId = ?1);",
+                        time::OffsetDateTime::now_utc().unix_timestamp()
+                    ))
+                    .bind(&[
+                        email.as_str().into(),
+                        campaign.id.as_str().into(),
+                        field.name.as_str().into(),
+                    ])?
+                    .all()
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How many codes of each coupon field of a campaign are left, and how many
+    /// active members are waiting for one
+    pub(super) async fn coupon_report(
+        &self,
+        campaign: &DbCampaign,
+    ) -> worker::Result<Vec<serde_json::Value>> {
+        let mut report = Vec::new();
+        for field in campaign
+            .fields
+            .iter()
+            .filter(|field| field.ty == FieldType::Coupon)
+        {
+            let Some(pool) = self
+                .db
+                .prepare(
+                    "SELECT \
+                    (SELECT COUNT(*) FROM CouponCodes WHERE CampaignId = ?2 AND Field = ?3) AS Total, \
+                    (SELECT COUNT(*) FROM CouponCodes WHERE CampaignId = ?2 AND Field = ?3 AND EmailId IS NOT NULL) AS Assigned, \
+                    (SELECT COUNT(DISTINCT lower(trim(EmailId))) FROM Members WHERE ListId = ?1 AND Active = 1 AND lower(trim(EmailId)) NOT IN (SELECT EmailId FROM CouponCodes WHERE CampaignId = ?2 AND Field = ?3 AND EmailId IS NOT NULL)) AS Waiting;",
+                )
+                .bind(&[
+                    campaign.list_id.as_str().into(),
+                    campaign.id.as_str().into(),
+                    field.name.as_str().into(),
+                ])?
+                .all()
+                .await?
+                .results::<DbPool>()?
+                .pop()
+            else {
+                continue;
+            };
+
+            report.push(pool.report(&field.name));
+        }
+
+        Ok(report)
+    }
+
+    pub async fn coupons(
+        &self,
+        session_id: impl Into<JsValue>,
+        campaign_id: &str,
+    ) -> worker::Result<Response> {
+        let Some(campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+
+        Response::from_json(&serde_json::json!({
+            "coupons": self.coupon_report(&campaign).await?,
+        }))
+    }
+
+    /// Adds codes to the pool of a coupon field, one per line of the body or in
+    /// the first column of a csv. Codes already in the pool are skipped. The new
+    /// codes are assigned to the members still waiting for one right away.
+    pub async fn add_coupon_codes(
+        &self,
+        session_id: impl Into<JsValue> + Copy,
+        campaign_id: &str,
+        field_name: &str,
+        body: &str,
+    ) -> worker::Result<Response> {
+        let Some(campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+        let Some(field) = campaign
+            .fields
+            .iter()
+            .find(|field| field.name == field_name && field.ty == FieldType::Coupon)
+        else {
+            return Response::error(
+                format!("The campaign has no '{field_name}' coupon field"),
+                404,
+            );
+        };
+
+        let (codes, rejected) = parse_coupon_codes(body);
+
+        let before = self.coupon_code_count(&campaign.id, &field.name).await?;
+        for chunk in codes.chunks(Self::CODES_PER_INSERT) {
+            let values = chunk
+                .iter()
+                .flat_map(|code| {
+                    [
+                        campaign.id.as_str().into(),
+                        field.name.as_str().into(),
+                        code.as_str().into(),
+                    ]
+                })
+                .collect::<Vec<JsValue>>();
+
+            self.db
+                .prepare(format!(
+                    "INSERT INTO CouponCodes (CampaignId, Field, Code) VALUES {} ON CONFLICT (CampaignId, Field, Code) DO NOTHING;",
+                    vec!["(?, ?, ?)"; chunk.len()].join(",")
+                ))
+                .bind(&values)?
+                .all()
+                .await?;
+        }
+        let added = self.coupon_code_count(&campaign.id, &field.name).await? - before;
+
+        // Released campaigns get their codes once they are populated again
+        if added > 0 && campaign.released == 0 {
+            let token = self.access_token(session_id).await?;
+            self.assign_coupon_codes(&campaign).await?;
+            self.push_campaign_values(&token, &campaign, None).await?;
+        }
+
+        Response::from_json(&serde_json::json!({
+            "added": added,
+            "duplicates": codes.len() as u64 - added,
+            "rejected": rejected,
+            "coupons": self.coupon_report(&campaign).await?,
+        }))
+    }
+
+    async fn coupon_code_count(&self, campaign_id: &str, field: &str) -> worker::Result<u64> {
+        #[derive(serde::Deserialize)]
+        struct DbCount {
+            #[serde(rename = "Count")]
+            count: u64,
+        }
+
+        Ok(self
+            .db
+            .prepare(
+                "SELECT COUNT(*) AS Count FROM CouponCodes WHERE CampaignId = ? AND Field = ?;",
+            )
+            .bind(&[campaign_id.into(), field.into()])?
+            .all()
+            .await?
+            .results::<DbCount>()?
+            .pop()
+            .map_or(0, |count| count.count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_coupon_codes() {
+        let (codes, rejected) =
+            parse_coupon_codes("Coupon Code,Note\n\"SAVE-10\",first\n\n  SAVE 20\nSAVE30\r\n");
+
+        assert_eq!(codes, ["SAVE-10", "SAVE30"]);
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0]["line"], 4);
+    }
+
+    #[test]
+    fn skips_only_exact_headers() {
+        for header in ["code", "Code", "coupon code", "COUPON_CODE"] {
+            let (codes, _) = parse_coupon_codes(&format!("{header}\nSAVE10"));
+            assert_eq!(codes, ["SAVE10"], "{header}");
+        }
+
+        // Codes that merely contain the word are kept, also on the first line
+        let (codes, _) = parse_coupon_codes("CODE10\ncodes\ncode");
+        assert_eq!(codes, ["CODE10", "codes", "code"]);
+    }
+
+    #[test]
+    fn reports_exhausted_pools() {
+        let pool = |total, assigned, waiting| DbPool {
+            total,
+            assigned,
+            waiting,
+        };
+
+        let report = pool(10, 4, 0).report("coupon");
+        assert_eq!(report["available"], 6);
+        assert_eq!(report["exhausted"], false);
+        assert_eq!(pool(10, 10, 0).report("coupon")["exhausted"], false);
+        let exhausted = pool(10, 10, 3).report("coupon");
+        assert_eq!(exhausted["exhausted"], true);
+        assert_eq!(exhausted["members_without_code"], 3);
+    }
+}
@@ -0,0 +1,715 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use worker::{wasm_bindgen::JsValue, Response};
+
+use super::{personalization::DbCampaign, tracking::Tracking, Personalization, Session};
+use crate::{
+    mailchimp::{lists::List, Token},
+    url_template::{TemplateMember, UrlTemplate},
+    video_link::VideoLink,
+};
+
+/// How the values of a personalization field are validated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    /// A http(s) link, stored without its scheme as the snippet embedded in
+    /// the campaign prepends `https://`
+    Url,
+    /// Free text like alt texts
+    Text,
+    /// A landing page slug of lowercase letters, digits and dashes
+    Slug,
+    /// A code members are given from the field's pool of coupon codes
+    Coupon,
+}
+
+impl FieldType {
+    /// Mailchimp text merge fields hold at most 255 characters
+    const MAX_TEXT_LEN: usize = 255;
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            FieldType::Url => "url",
+            FieldType::Text => "text",
+            FieldType::Slug => "slug",
+            FieldType::Coupon => "coupon",
+        }
+    }
+
+    /// Validates a value of the type, returning it the way it is stored
+    pub fn normalize(&self, value: &str) -> Result<String, String> {
+        let value = value.trim();
+
+        let normalized = match self {
+            FieldType::Url => Self::normalize_url(value)?,
+            FieldType::Text => value.to_owned(),
+            FieldType::Slug => {
+                let slug = value.to_lowercase();
+                if slug.is_empty() || !slug.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                    return Err(format!("'{value}' is not a valid slug"));
+                }
+
+                slug
+            }
+            FieldType::Coupon => {
+                if value.is_empty() || value.contains(char::is_whitespace) {
+                    return Err(format!("'{value}' is not a valid coupon code"));
+                }
+
+                value.to_owned()
+            }
+        };
+
+        if normalized.chars().count() > Self::MAX_TEXT_LEN {
+            return Err(format!(
+                "the value is longer than {} characters",
+                Self::MAX_TEXT_LEN
+            ));
+        }
+
+        Ok(normalized)
+    }
+
+    fn normalize_url(url: &str) -> Result<String, String> {
+        if url.is_empty() {
+            return Err("the url is empty".into());
+        }
+
+        let parsed = if url.contains("://") {
+            url::Url::parse(url)
+        } else {
+            url::Url::parse(&format!("https://{url}"))
+        }
+        .map_err(|err| format!("'{url}' {err}"))?;
+
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(format!("'{url}' is not a http url"));
+        }
+        match parsed.host_str() {
+            Some(host) if host.contains('.') => {}
+            _ => return Err(format!("'{url}' has no valid host")),
+        }
+        // The same video is stored the same way whichever link to it was given
+        let parsed = VideoLink::parse(&parsed)
+            .map(|video| video.canonical(VideoLink::start_secs(&parsed)))
+            .unwrap_or(parsed);
+
+        Ok(parsed[url::Position::BeforeHost..].to_owned())
+    }
+}
+
+/// A personalization field declared by a campaign. Each field is backed by its
+/// own merge field on the campaign's list.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CampaignField {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: FieldType,
+    /// The value of members without a personalization or template
+    #[serde(default)]
+    pub default: String,
+    /// A url template members without a personalization get their value
+    /// rendered from, see [`UrlTemplate`]
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Whether members get a link to the worker that records their clicks or
+    /// the loads of their image and redirects to their value, rather than the
+    /// value itself
+    #[serde(default)]
+    pub tracking: Option<Tracking>,
+    /// The url field of a video whose thumbnail members get when they have no
+    /// personalization or template for this field, see [`VideoLink::thumbnail`]
+    #[serde(default)]
+    pub thumbnail_of: Option<String>,
+    /// Whether members get their image with a play button and their first
+    /// name drawn over it, which only tracked images can
+    #[serde(default)]
+    pub overlay: bool,
+    /// The tracked link field whose link members get as a QR code, this
+    /// field holding the url of the code's image
+    #[serde(default)]
+    pub qr_code_of: Option<String>,
+    /// The tag of the field's merge field, `None` while the campaign holds no
+    /// merge field for it
+    #[serde(default, skip_deserializing)]
+    pub tag: Option<String>,
+}
+
+impl CampaignField {
+    const MAX_NAME_LEN: usize = 32;
+
+    /// The video and image used for campaigns that were not given a default
+    const DEFAULT_VIDEO_URL: &'static str = "vimeo.com/226053498";
+    const DEFAULT_IMAGE_URL: &'static str = "s3.amazonaws.com/creare-websites-wpms-legacy/wp-content/uploads/sites/32/2016/03/01200959/canstockphoto22402523-arcos-creator.com_-1024x1024.jpg";
+
+    /// The fields of a campaign that did not declare its own
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            CampaignField {
+                name: "video".into(),
+                ty: FieldType::Url,
+                default: Self::DEFAULT_VIDEO_URL.into(),
+                template: None,
+                tracking: Some(Tracking::Click),
+                thumbnail_of: None,
+                overlay: false,
+                qr_code_of: None,
+                tag: None,
+            },
+            CampaignField {
+                name: "image".into(),
+                ty: FieldType::Url,
+                default: Self::DEFAULT_IMAGE_URL.into(),
+                template: None,
+                tracking: Some(Tracking::Impression),
+                thumbnail_of: Some("video".into()),
+                overlay: false,
+                qr_code_of: None,
+                tag: None,
+            },
+        ]
+    }
+
+    /// The name of the field's merge field in mailchimp
+    pub fn merge_field_name(&self, campaign_id: &str) -> String {
+        format!("{}/{}", self.name, campaign_id)
+    }
+
+    /// Checks the declaration of the field and normalizes its default
+    fn validate(&mut self) -> Result<(), String> {
+        let name = &self.name;
+        if name.is_empty()
+            || name.len() > Self::MAX_NAME_LEN
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        {
+            return Err(format!(
+                "'{name}' is not a valid field name, names are up to {} lowercase letters, digits or underscores",
+                Self::MAX_NAME_LEN
+            ));
+        }
+        // Personalizations are sent as objects with the email next to the values
+        if name == "email" {
+            return Err("'email' can not be used as a field name".into());
+        }
+
+        if let Some(template) = &self.template {
+            if matches!(self.ty, FieldType::Text | FieldType::Coupon) {
+                return Err(format!(
+                    "The {} field '{name}' can not have a template",
+                    self.ty.as_str()
+                ));
+            }
+            UrlTemplate(template).validate()?;
+        }
+
+        if self.tracking.is_some() && self.ty != FieldType::Url {
+            return Err(format!(
+                "Only url fields can be tracked, '{name}' is not one"
+            ));
+        }
+        if self.overlay && self.tracking != Some(Tracking::Impression) {
+            return Err(format!(
+                "Only images tracked by impression can have an overlay, '{name}' is not one"
+            ));
+        }
+        if self.thumbnail_of.is_some() && self.ty != FieldType::Url {
+            return Err(format!(
+                "Only url fields can hold a thumbnail, '{name}' is not one"
+            ));
+        }
+        // The value of the field is always the url of the QR code
+        if self.qr_code_of.is_some()
+            && (self.ty != FieldType::Url
+                || self.template.is_some()
+                || self.tracking.is_some()
+                || self.thumbnail_of.is_some())
+        {
+            return Err(format!(
+                "The QR code field '{name}' must be a url field without a template, tracking or thumbnail"
+            ));
+        }
+
+        if !self.default.is_empty() {
+            self.default = self
+                .ty
+                .normalize(&self.default)
+                .map_err(|err| format!("Invalid default of '{name}': {err}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the video a field holds the thumbnail of is a url field of
+    /// the campaign, which does not hold a thumbnail itself
+    fn validate_thumbnail_of(&self, fields: &[CampaignField]) -> Result<(), String> {
+        let Some(video) = &self.thumbnail_of else {
+            return Ok(());
+        };
+
+        match fields.iter().find(|field| &field.name == video) {
+            Some(field) if field.ty == FieldType::Url && field.thumbnail_of.is_none() => Ok(()),
+            Some(_) => Err(format!(
+                "'{}' can not hold the thumbnail of '{video}', which is no video url field",
+                self.name
+            )),
+            None => Err(format!(
+                "'{}' holds the thumbnail of '{video}', which is no field of the campaign",
+                self.name
+            )),
+        }
+    }
+
+    /// Checks that the field a QR code is made of is a url field of the
+    /// campaign whose clicks are tracked, as only tracked links are signed
+    fn validate_qr_code_of(&self, fields: &[CampaignField]) -> Result<(), String> {
+        let Some(link) = &self.qr_code_of else {
+            return Ok(());
+        };
+
+        match fields.iter().find(|field| &field.name == link) {
+            Some(field) if field.tracking == Some(Tracking::Click) => Ok(()),
+            Some(_) => Err(format!(
+                "'{}' can not hold the QR code of '{link}', whose clicks are not tracked",
+                self.name
+            )),
+            None => Err(format!(
+                "'{}' holds the QR code of '{link}', which is no field of the campaign",
+                self.name
+            )),
+        }
+    }
+
+    /// The value of a member. A personalization takes precedence over the
+    /// template, which takes precedence over the thumbnail of the member's
+    /// video, which takes precedence over the default.
+    pub(super) fn value(
+        &self,
+        campaign: &DbCampaign,
+        personalization: Option<&Personalization>,
+        member: &TemplateMember,
+    ) -> String {
+        if let Some(value) = personalization.and_then(|p| p.values.get(&self.name)) {
+            return value.clone();
+        }
+
+        self.template
+            .as_deref()
+            .map(|template| UrlTemplate(template).render(&campaign.id, &campaign.list_id, member))
+            .and_then(|value| self.ty.normalize(&value).ok())
+            .or_else(|| self.thumbnail(campaign, personalization, member))
+            .unwrap_or_else(|| self.default.clone())
+    }
+
+    /// The thumbnail of the member's video, stored without a scheme like the
+    /// values of url fields
+    fn thumbnail(
+        &self,
+        campaign: &DbCampaign,
+        personalization: Option<&Personalization>,
+        member: &TemplateMember,
+    ) -> Option<String> {
+        let video = self.thumbnail_of.as_ref()?;
+        // The video field holds no thumbnail itself, so this does not recurse further
+        let video = campaign
+            .fields
+            .iter()
+            .find(|field| &field.name == video && field.thumbnail_of.is_none())?
+            .value(campaign, personalization, member);
+        let thumbnail =
+            VideoLink::parse(&url::Url::parse(&format!("https://{video}")).ok()?)?.thumbnail()?;
+
+        Some(thumbnail[url::Position::BeforeHost..].to_owned())
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct DbCampaignField {
+    #[serde(rename = "CampaignId")]
+    campaign_id: String,
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Type")]
+    ty: FieldType,
+    #[serde(rename = "Tag")]
+    tag: Option<String>,
+    #[serde(rename = "DefaultValue")]
+    default: String,
+    #[serde(rename = "Template")]
+    template: Option<String>,
+    #[serde(rename = "Tracking")]
+    tracking: Option<Tracking>,
+    #[serde(rename = "ThumbnailOf")]
+    thumbnail_of: Option<String>,
+    #[serde(rename = "Overlay")]
+    overlay: u8,
+    #[serde(rename = "QrCodeOf")]
+    qr_code_of: Option<String>,
+}
+
+impl From<DbCampaignField> for CampaignField {
+    fn from(field: DbCampaignField) -> Self {
+        CampaignField {
+            name: field.name,
+            ty: field.ty,
+            default: field.default,
+            template: field.template,
+            tracking: field.tracking,
+            thumbnail_of: field.thumbnail_of,
+            overlay: field.overlay != 0,
+            qr_code_of: field.qr_code_of,
+            tag: field.tag,
+        }
+    }
+}
+
+impl Session {
+    pub(super) async fn campaign_fields(
+        &self,
+        campaign_id: &str,
+    ) -> worker::Result<Vec<CampaignField>> {
+        Ok(self
+            .db
+            .prepare("SELECT * FROM CampaignFields WHERE CampaignId = ? ORDER BY Position;")
+            .bind(&[campaign_id.into()])?
+            .all()
+            .await?
+            .results::<DbCampaignField>()?
+            .into_iter()
+            .map(CampaignField::from)
+            .collect())
+    }
+
+    /// The fields of the campaigns of a list keyed by campaign id
+    pub(super) async fn list_fields(
+        &self,
+        list_id: &str,
+    ) -> worker::Result<HashMap<String, Vec<CampaignField>>> {
+        let mut fields = HashMap::<_, Vec<_>>::new();
+
+        for field in self
+            .db
+            .prepare("SELECT * FROM CampaignFields WHERE CampaignId IN (SELECT Id FROM Campaigns WHERE ListId = ?) ORDER BY Position;")
+            .bind(&[list_id.into()])?
+            .all()
+            .await?
+            .results::<DbCampaignField>()?
+        {
+            fields
+                .entry(field.campaign_id.clone())
+                .or_default()
+                .push(field.into());
+        }
+
+        Ok(fields)
+    }
+
+    /// Stores the declarations of a campaign's fields in the given order,
+    /// keeping the tags of fields that already exist
+    pub(super) async fn store_campaign_fields(
+        &self,
+        campaign_id: &str,
+        fields: &[CampaignField],
+    ) -> worker::Result<()> {
+        for (position, field) in fields.iter().enumerate() {
+            self.db
+                .prepare(format!(
+                    "INSERT INTO CampaignFields (CampaignId, Name, Type, DefaultValue, Template, Tracking, ThumbnailOf, Overlay, QrCodeOf, Position) VALUES (?, ?, ?, ?, ?, ?, ?, {}, ?, {}) ON CONFLICT (CampaignId, Name) DO UPDATE SET Type = excluded.Type, DefaultValue = excluded.DefaultValue, Template = excluded.Template, Tracking = excluded.Tracking, ThumbnailOf = excluded.ThumbnailOf, Overlay = excluded.Overlay, QrCodeOf = excluded.QrCodeOf, Position = excluded.Position;",
+                    u8::from(field.overlay),
+                    position
+                ))
+                .bind(&[
+                    campaign_id.into(),
+                    field.name.as_str().into(),
+                    field.ty.as_str().into(),
+                    field.default.as_str().into(),
+                    field
+                        .template
+                        .as_deref()
+                        .map(JsValue::from)
+                        .unwrap_or(JsValue::NULL),
+                    field
+                        .tracking
+                        .map(|tracking| JsValue::from(tracking.as_str()))
+                        .unwrap_or(JsValue::NULL),
+                    field
+                        .thumbnail_of
+                        .as_deref()
+                        .map(JsValue::from)
+                        .unwrap_or(JsValue::NULL),
+                    field
+                        .qr_code_of
+                        .as_deref()
+                        .map(JsValue::from)
+                        .unwrap_or(JsValue::NULL),
+                ])?
+                .all()
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Gives every field of a campaign without a merge field one. Returns
+    /// `false` if the list ran out of merge fields.
+    pub(super) async fn acquire_field_tags(
+        &self,
+        token: &Token,
+        campaign: &mut DbCampaign,
+    ) -> worker::Result<bool> {
+        let list = List(campaign.list_id.clone());
+
+        for field in campaign
+            .fields
+            .iter_mut()
+            .filter(|field| field.tag.is_none())
+        {
+            let Some(tag) = self
+                .acquire_merge_field(
+                    token,
+                    &list,
+                    &campaign.id,
+                    &field.merge_field_name(&campaign.id),
+                )
+                .await?
+            else {
+                return Ok(false);
+            };
+
+            self.db
+                .prepare("UPDATE CampaignFields SET Tag = ? WHERE CampaignId = ? AND Name = ?;")
+                .bind(&[
+                    tag.as_str().into(),
+                    campaign.id.as_str().into(),
+                    field.name.as_str().into(),
+                ])?
+                .all()
+                .await?;
+            field.tag = Some(tag);
+        }
+
+        Ok(true)
+    }
+
+    pub async fn fields(
+        &self,
+        session_id: impl Into<JsValue>,
+        campaign_id: &str,
+    ) -> worker::Result<Response> {
+        let Some(campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+
+        Response::from_json(&serde_json::json!({
+            "fields": campaign.fields,
+        }))
+    }
+
+    /// Replaces the personalization fields of a campaign. Fields that are
+    /// left out are removed along with their personalizations and merge
+    /// fields, and new fields get a merge field.
+    pub async fn set_fields(
+        &self,
+        session_id: impl Into<JsValue> + Copy,
+        campaign_id: &str,
+        mut fields: Vec<CampaignField>,
+    ) -> worker::Result<Response> {
+        let mut names = HashSet::new();
+        for field in &mut fields {
+            if let Err(err) = field.validate() {
+                return Response::error(err, 400);
+            }
+            if !names.insert(field.name.clone()) {
+                return Response::error(
+                    format!("The field '{}' is declared twice", field.name),
+                    400,
+                );
+            }
+        }
+        for field in &fields {
+            if let Err(err) = field
+                .validate_thumbnail_of(&fields)
+                .and_then(|_| field.validate_qr_code_of(&fields))
+            {
+                return Response::error(err, 400);
+            }
+        }
+
+        let Some(mut campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+        let token = self.access_token(session_id).await?;
+        let list = List(campaign.list_id.clone());
+
+        let existing = campaign
+            .fields
+            .iter()
+            .map(|field| (field.name.clone(), field))
+            .collect::<BTreeMap<_, _>>();
+        for (name, field) in &existing {
+            let retyped = fields
+                .iter()
+                .find(|new| &new.name == name)
+                .map(|new| new.ty != field.ty);
+
+            match retyped {
+                // The stored values may not be valid for the new type
+                Some(true) => {
+                    self.db
+                        .prepare("DELETE FROM Personalizations WHERE CampaignId = ? AND Field = ?;")
+                        .bind(&[campaign.id.as_str().into(), name.as_str().into()])?
+                        .all()
+                        .await?;
+                    self.db
+                        .prepare("DELETE FROM CouponCodes WHERE CampaignId = ? AND Field = ?;")
+                        .bind(&[campaign.id.as_str().into(), name.as_str().into()])?
+                        .all()
+                        .await?;
+                }
+                Some(false) => {}
+                None => {
+                    if field.tag.is_some() {
+                        self.release_merge_field(
+                            &token,
+                            &list,
+                            &field.merge_field_name(&campaign.id),
+                        )
+                        .await?;
+                    }
+
+                    self.db
+                        .prepare("DELETE FROM CampaignFields WHERE CampaignId = ? AND Name = ?;")
+                        .bind(&[campaign.id.as_str().into(), name.as_str().into()])?
+                        .all()
+                        .await?;
+                }
+            }
+        }
+
+        for field in &mut fields {
+            field.tag = existing
+                .get(&field.name)
+                .and_then(|field| field.tag.clone());
+        }
+        self.store_campaign_fields(&campaign.id, &fields).await?;
+        campaign.fields = fields;
+
+        // A released campaign gets its merge fields back once it is populated again
+        if campaign.released == 0 {
+            if !self.acquire_field_tags(&token, &mut campaign).await? {
+                return Self::merge_fields_exhausted(&list);
+            }
+            self.assign_coupon_codes(&campaign).await?;
+            self.push_campaign_values(&token, &campaign, None).await?;
+        }
+
+        Response::from_json(&serde_json::json!({
+            "fields": campaign.fields,
+            "coupons": self.coupon_report(&campaign).await?,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(json: serde_json::Value) -> CampaignField {
+        serde_json::from_value(json).expect("Failed to parse field")
+    }
+
+    #[test]
+    fn normalizes_values() {
+        assert_eq!(
+            FieldType::Url.normalize(" example.com/a?b=c ").as_deref(),
+            Ok("example.com/a?b=c")
+        );
+        assert_eq!(
+            FieldType::Url.normalize("http://Example.com/a").as_deref(),
+            Ok("example.com/a")
+        );
+        assert!(FieldType::Url.normalize("").is_err());
+        assert!(FieldType::Url.normalize("ftp://example.com/a").is_err());
+        assert!(FieldType::Url.normalize("localhost/a").is_err());
+
+        assert_eq!(
+            FieldType::Text.normalize(" Hi there ").as_deref(),
+            Ok("Hi there")
+        );
+        assert!(FieldType::Text.normalize(&"a".repeat(256)).is_err());
+
+        assert_eq!(
+            FieldType::Slug.normalize("Spring-Sale").as_deref(),
+            Ok("spring-sale")
+        );
+        assert!(FieldType::Slug.normalize("spring sale").is_err());
+    }
+
+    #[test]
+    fn validates_fields() {
+        let mut video = field(serde_json::json!({
+            "name": "video_2",
+            "type": "url",
+            "default": "https://example.com/v.mp4",
+            "template": "https://cdn.example.com/{subscriber_hash}.mp4"
+        }));
+        assert_eq!(video.validate(), Ok(()));
+        assert_eq!(video.default, "example.com/v.mp4");
+        assert_eq!(video.merge_field_name("c1"), "video_2/c1");
+
+        for invalid in [
+            serde_json::json!({ "name": "Video", "type": "url" }),
+            serde_json::json!({ "name": "", "type": "url" }),
+            serde_json::json!({ "name": "a".repeat(33), "type": "url" }),
+            serde_json::json!({ "name": "email", "type": "text" }),
+            serde_json::json!({ "name": "alt", "type": "text", "template": "{name}" }),
+            serde_json::json!({ "name": "video", "type": "url", "template": "{nope}" }),
+            serde_json::json!({ "name": "video", "type": "url", "default": "not a url" }),
+        ] {
+            assert!(field(invalid.clone()).validate().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn prefers_personalizations_over_templates_over_defaults() {
+        let mut video = field(serde_json::json!({
+            "name": "video",
+            "type": "url",
+            "default": "example.com/default.mp4",
+            "template": "https://cdn.example.com/{campaign_id}/{name}.mp4"
+        }));
+        let campaign: DbCampaign = serde_json::from_value(serde_json::json!({
+            "Id": "c1",
+            "ListId": "l1",
+            "Released": 0,
+            "LandingPage": 0
+        }))
+        .unwrap();
+        let merges = BTreeMap::new();
+        let member = TemplateMember {
+            email: "ann@example.com",
+            name: "Ann Lee",
+            merges: &merges,
+        };
+        let personalization = Personalization {
+            email: member.email.into(),
+            values: BTreeMap::from([("video".to_owned(), "example.com/ann.mp4".to_owned())]),
+        };
+
+        assert_eq!(
+            video.value(&campaign, Some(&personalization), &member),
+            "example.com/ann.mp4"
+        );
+        assert_eq!(
+            video.value(&campaign, None, &member),
+            "cdn.example.com/c1/Ann%20Lee.mp4"
+        );
+        video.template = None;
+        assert_eq!(
+            video.value(&campaign, None, &member),
+            "example.com/default.mp4"
+        );
+    }
+}
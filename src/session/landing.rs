@@ -0,0 +1,253 @@
+use std::net::IpAddr;
+
+use worker::{wasm_bindgen::JsValue, Response};
+
+use super::{tracking::DbLinkTarget, Session, Tracking};
+use crate::{
+    landing_page::{Cta, LandingPage},
+    link_signing::LinkError,
+    routing::Visitor,
+    video_link::VideoLink,
+};
+
+/// Whether the video links of a campaign lead to the landing page rather than
+/// straight to the video, and what the page shows besides the video
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct LandingSettings {
+    pub enabled: bool,
+    /// Shown instead of the campaign title
+    #[serde(default)]
+    pub headline: Option<String>,
+    #[serde(default)]
+    pub cta_label: Option<String>,
+    #[serde(default)]
+    pub cta_url: Option<String>,
+}
+
+impl LandingSettings {
+    fn validate(&mut self) -> Result<(), String> {
+        for value in [&mut self.headline, &mut self.cta_label, &mut self.cta_url] {
+            *value = value
+                .take()
+                .map(|value| value.trim().to_owned())
+                .filter(|value| !value.is_empty());
+        }
+
+        match (&self.cta_label, &self.cta_url) {
+            (Some(_), Some(url)) => match url::Url::parse(url) {
+                Ok(url) if url.scheme() == "https" || url.scheme() == "http" => Ok(()),
+                _ => Err(format!("The button url '{url}' is not a web address")),
+            },
+            (None, None) => Ok(()),
+            _ => Err("The button needs both a label and a url".into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct DbLandingSettings {
+    #[serde(rename = "LandingPage")]
+    landing_page: u8,
+    #[serde(rename = "Headline")]
+    headline: Option<String>,
+    #[serde(rename = "CtaLabel")]
+    cta_label: Option<String>,
+    #[serde(rename = "CtaUrl")]
+    cta_url: Option<String>,
+}
+
+impl From<DbLandingSettings> for LandingSettings {
+    fn from(settings: DbLandingSettings) -> Self {
+        LandingSettings {
+            enabled: settings.landing_page != 0,
+            headline: settings.headline,
+            cta_label: settings.cta_label,
+            cta_url: settings.cta_url,
+        }
+    }
+}
+
+impl Session {
+    pub async fn landing_settings(
+        &self,
+        session_id: impl Into<JsValue>,
+        campaign_id: &str,
+    ) -> worker::Result<Response> {
+        let user_id = self.user_id(session_id).await?;
+
+        let Some(settings) = self
+            .db
+            .prepare(format!(
+                "SELECT LandingPage, Headline, CtaLabel, CtaUrl FROM Campaigns WHERE Id = ? AND UserId = {user_id};"
+            ))
+            .bind(&[campaign_id.into()])?
+            .all()
+            .await?
+            .results::<DbLandingSettings>()?
+            .pop()
+        else {
+            return Response::error("Campaign has not been added", 404);
+        };
+
+        Response::from_json(&LandingSettings::from(settings))
+    }
+
+    /// Stores the landing page of a campaign and writes the members' video
+    /// links again, as they change between the landing page and the video
+    pub async fn set_landing_settings(
+        &self,
+        session_id: impl Into<JsValue> + Copy,
+        campaign_id: &str,
+        mut settings: LandingSettings,
+    ) -> worker::Result<Response> {
+        if let Err(err) = settings.validate() {
+            return Response::error(err, 400);
+        }
+
+        let Some(mut campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+
+        let optional =
+            |value: &Option<String>| value.as_deref().map(JsValue::from).unwrap_or(JsValue::NULL);
+        self.db
+            .prepare(format!(
+                "UPDATE Campaigns SET LandingPage = {}, Headline = ?, CtaLabel = ?, CtaUrl = ? WHERE Id = ?;",
+                u8::from(settings.enabled)
+            ))
+            .bind(&[
+                optional(&settings.headline),
+                optional(&settings.cta_label),
+                optional(&settings.cta_url),
+                campaign.id.as_str().into(),
+            ])?
+            .all()
+            .await?;
+
+        let relink = campaign.landing_page != u8::from(settings.enabled);
+        campaign.landing_page = u8::from(settings.enabled);
+        if relink && campaign.released == 0 {
+            let token = self.access_token(session_id).await?;
+            self.push_campaign_values(&token, &campaign, None).await?;
+        }
+
+        Response::from_json(&settings)
+    }
+
+    /// Records the member's view and renders their landing page, playing the
+    /// variant of the first routing rule the member meets
+    pub async fn watch_page(
+        &self,
+        token: &str,
+        user_agent: Option<String>,
+        ip: Option<IpAddr>,
+        country: Option<String>,
+        accept_language: Option<String>,
+    ) -> worker::Result<Result<String, LinkError>> {
+        let link = match self.verified_link(token).await? {
+            Ok(link) => link,
+            Err(err) => return Ok(Err(err)),
+        };
+        let visitor = Visitor::new(
+            country.as_deref(),
+            user_agent.as_deref(),
+            accept_language.as_deref(),
+        );
+        let route = self.route(&link, &visitor).await?;
+        let variant = route.as_ref().map(|route| route.variant.as_str());
+        self.record_hit(&link, Tracking::Click, user_agent, ip, country, variant)
+            .await?;
+
+        let video = match route {
+            Some(route) => route.url,
+            None => link.url()?,
+        };
+
+        Ok(Ok(self.landing_page(&link, token, video).await?.html()))
+    }
+
+    /// Describes a landing page for oEmbed consumers, without recording a view
+    pub async fn oembed(
+        &self,
+        page_url: &url::Url,
+        max_width: Option<u32>,
+    ) -> worker::Result<Result<serde_json::Value, LinkError>> {
+        let Some(token) = page_url.path().strip_prefix(Self::WATCH_PATH) else {
+            return Ok(Err(LinkError::Malformed));
+        };
+        let link = match self.verified_link(token).await? {
+            Ok(link) => link,
+            Err(err) => return Ok(Err(err)),
+        };
+
+        Ok(Ok(self
+            .landing_page(&link, token, link.url()?)
+            .await?
+            .oembed(max_width)))
+    }
+
+    async fn landing_page(
+        &self,
+        link: &DbLinkTarget,
+        token: &str,
+        video: url::Url,
+    ) -> worker::Result<LandingPage> {
+        #[derive(serde::Deserialize)]
+        struct DbLandingPage {
+            #[serde(rename = "Title")]
+            title: String,
+            #[serde(rename = "Headline")]
+            headline: Option<String>,
+            #[serde(rename = "CtaLabel")]
+            cta_label: Option<String>,
+            #[serde(rename = "CtaUrl")]
+            cta_url: Option<String>,
+            #[serde(rename = "FullName")]
+            full_name: Option<String>,
+            #[serde(rename = "ImageUrl")]
+            image_url: Option<String>,
+        }
+
+        // The member's first tracked image is their thumbnail
+        let page = self
+            .db
+            .prepare("SELECT Campaigns.Title, Campaigns.Headline, Campaigns.CtaLabel, Campaigns.CtaUrl, Members.FullName, \
+                (SELECT Image.Url FROM Links AS Image JOIN CampaignFields ON CampaignFields.CampaignId = Image.CampaignId AND CampaignFields.Name = Image.Field \
+                WHERE Image.CampaignId = ?1 AND Image.EmailId = ?2 AND CampaignFields.Tracking = 'impression' ORDER BY CampaignFields.Position LIMIT 1) AS ImageUrl \
+                FROM Campaigns LEFT JOIN Members ON Members.ListId = Campaigns.ListId AND lower(trim(Members.EmailId)) = ?2 WHERE Campaigns.Id = ?1;")
+            .bind(&[link.campaign_id.as_str().into(), link.email.as_str().into()])?
+            .all()
+            .await?
+            .results::<DbLandingPage>()?
+            .pop()
+            .ok_or_else(|| worker::Error::RustError("Failed to find the campaign of a link".into()))?;
+
+        let page_url = self
+            .watch_uri
+            .join(token)
+            .map_err(|err| worker::Error::RustError(format!("Invalid landing page url: {err}")))?;
+
+        // Without an image of their own members get the video's thumbnail
+        let image = page.image_url.or_else(|| {
+            VideoLink::parse(&video)
+                .and_then(|video| video.thumbnail())
+                .map(String::from)
+        });
+
+        Ok(LandingPage {
+            title: page.headline.unwrap_or(page.title),
+            name: page
+                .full_name
+                .map(|name| name.trim().to_owned())
+                .filter(|name| !name.is_empty()),
+            video,
+            image,
+            cta: page
+                .cta_label
+                .zip(page.cta_url)
+                .map(|(label, url)| Cta { label, url }),
+            page_url,
+            oembed_url: self.oembed_uri.clone(),
+        })
+    }
+}
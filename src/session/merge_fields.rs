@@ -0,0 +1,456 @@
+use worker::{wasm_bindgen::JsValue, Response};
+
+use super::Session;
+use crate::{
+    mailchimp::{
+        campaign::MailChimpCampaign,
+        lists::{List, MergeField, MergeFieldParams, MergeFields},
+        Token,
+    },
+    merge_tag,
+};
+
+/// A merge field created for a campaign. Fields without a campaign are free
+/// slots that the next campaign populated on the list reuses.
+#[derive(Debug, serde::Deserialize)]
+struct DbMergeField {
+    #[serde(rename = "MergeId")]
+    merge_id: u64,
+    #[serde(rename = "Tag")]
+    tag: String,
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "CampaignId")]
+    campaign_id: Option<String>,
+}
+
+impl DbMergeField {
+    /// The name a freed field is renamed to, so that the audience's owner can
+    /// tell it is unused. The tag is kept, as the next campaign takes it over.
+    fn unused_name(&self) -> String {
+        format!("Unused/{}", self.tag)
+    }
+}
+
+impl Session {
+    /// Returns the tag of the merge field named `name` of a campaign. The field
+    /// is reused if the campaign already has it, otherwise a free slot of the
+    /// list is taken over before a new field is created. `None` if the list has
+    /// no merge fields left.
+    pub(super) async fn acquire_merge_field(
+        &self,
+        token: &Token,
+        list: &List,
+        campaign_id: &str,
+        name: &str,
+    ) -> worker::Result<Option<String>> {
+        let owned = self
+            .db
+            .prepare("SELECT * FROM MergeFields WHERE ListId = ? AND Name = ?;")
+            .bind(&[list.0.as_str().into(), name.into()])?
+            .all()
+            .await?
+            .results::<DbMergeField>()?
+            .pop();
+        if let Some(field) = owned {
+            if list.merge_field(token, field.merge_id).await?.is_some() {
+                return Ok(Some(field.tag));
+            }
+
+            // The field was deleted in mailchimp
+            self.delete_merge_field_slot(list, field.merge_id).await?;
+        }
+
+        // Claiming the slot in a single statement keeps two campaigns populated at
+        // the same time from taking over the same field
+        while let Some(slot) = self
+            .db
+            .prepare("UPDATE MergeFields SET CampaignId = ?, Name = ? WHERE ListId = ? AND MergeId = (SELECT MergeId FROM MergeFields WHERE ListId = ? AND CampaignId IS NULL LIMIT 1) RETURNING *;")
+            .bind(&[
+                campaign_id.into(),
+                name.into(),
+                list.0.as_str().into(),
+                list.0.as_str().into(),
+            ])?
+            .all()
+            .await?
+            .results::<DbMergeField>()?
+            .pop()
+        {
+            if list
+                .update_merge_field(token, slot.merge_id, &MergeFieldParams::rename(name))
+                .await?
+                .is_some()
+            {
+                return Ok(Some(slot.tag));
+            }
+
+            // The field was deleted in mailchimp
+            self.delete_merge_field_slot(list, slot.merge_id).await?;
+        }
+
+        let fields = list.merge_fields(token).await?;
+        let field = match fields.by_name(name) {
+            Some(field) => field.clone(),
+            None => {
+                match Self::add_merge_field_with_generated_tag(token, list, fields, name).await? {
+                    Some(field) => field,
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        self.db
+            .prepare(format!(
+                "INSERT INTO MergeFields (ListId, MergeId, Tag, Name, CampaignId) VALUES (?, {}, ?, ?, ?) ON CONFLICT (ListId, MergeId) DO UPDATE SET Name = excluded.Name, CampaignId = excluded.CampaignId;",
+                field.merge_id
+            ))
+            .bind(&[
+                list.0.as_str().into(),
+                field.tag.as_str().into(),
+                name.into(),
+                campaign_id.into(),
+            ])?
+            .all()
+            .await?;
+
+        Ok(Some(field.tag))
+    }
+
+    /// Creates a field named `name` with a generated tag, moving on to the next
+    /// tag when one is already used by another field of the list. The tag
+    /// mailchimp returns is the one stored with the name. `None` if the list
+    /// has no merge fields left.
+    async fn add_merge_field_with_generated_tag(
+        token: &Token,
+        list: &List,
+        mut fields: MergeFields,
+        name: &str,
+    ) -> worker::Result<Option<MergeField>> {
+        let mut next = 0;
+        while let Some((attempt, tag)) =
+            merge_tag::first_free(name, next, |tag| fields.by_tag(tag).is_some())
+        {
+            next = attempt + 1;
+
+            let added = list
+                .add_merge_field(token, &MergeFieldParams::hidden_text(name, &tag))
+                .await;
+            if let Ok(Some(field)) = added {
+                return Ok(Some(field));
+            }
+
+            // The tag may have been taken since the fields were fetched, which
+            // mailchimp can refuse like a list without merge fields left
+            fields = list.merge_fields(token).await?;
+            if fields.by_tag(&tag).is_none() {
+                return added;
+            }
+        }
+
+        Err(worker::Error::RustError(format!(
+            "Failed to find a free merge tag for {name}"
+        )))
+    }
+
+    pub(super) fn merge_fields_exhausted(list: &List) -> worker::Result<Response> {
+        Response::error(
+            format!(
+                "The audience {} has used all of the merge fields its plan allows. Release the merge fields of sent or deleted campaigns or remove unused fields in mailchimp.",
+                list.0
+            ),
+            409,
+        )
+    }
+
+    async fn delete_merge_field_slot(&self, list: &List, merge_id: u64) -> worker::Result<()> {
+        self.db
+            .prepare(format!(
+                "DELETE FROM MergeFields WHERE ListId = ? AND MergeId = {};",
+                merge_id
+            ))
+            .bind(&[list.0.as_str().into()])?
+            .all()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Frees the merge fields of a campaign so that other campaigns of its list
+    /// can reuse them. Returns the number of released fields.
+    pub(super) async fn release_merge_fields(
+        &self,
+        token: &Token,
+        campaign_id: &str,
+    ) -> worker::Result<usize> {
+        #[derive(serde::Deserialize)]
+        struct DbCampaign {
+            #[serde(rename = "ListId")]
+            list_id: String,
+        }
+
+        // The campaign is released first so that its values stop being written
+        // before another campaign can take over the fields
+        let Some(campaign) = self
+            .db
+            .prepare("UPDATE Campaigns SET Released = 1 WHERE Id = ? RETURNING ListId;")
+            .bind(&[campaign_id.into()])?
+            .all()
+            .await?
+            .results::<DbCampaign>()?
+            .pop()
+        else {
+            return Ok(0);
+        };
+        let list = List(campaign.list_id);
+
+        self.db
+            .prepare("UPDATE CampaignFields SET Tag = NULL WHERE CampaignId = ?;")
+            .bind(&[campaign_id.into()])?
+            .all()
+            .await?;
+
+        let slots = self
+            .db
+            .prepare("SELECT * FROM MergeFields WHERE ListId = ? AND CampaignId = ?;")
+            .bind(&[list.0.as_str().into(), campaign_id.into()])?
+            .all()
+            .await?
+            .results::<DbMergeField>()?;
+
+        for slot in &slots {
+            self.free_merge_field_slot(token, &list, slot).await?;
+        }
+
+        Ok(slots.len())
+    }
+
+    /// Frees the merge field named `name` after its campaign field was removed
+    pub(super) async fn release_merge_field(
+        &self,
+        token: &Token,
+        list: &List,
+        name: &str,
+    ) -> worker::Result<()> {
+        let slot = self
+            .db
+            .prepare("SELECT * FROM MergeFields WHERE ListId = ? AND Name = ?;")
+            .bind(&[list.0.as_str().into(), name.into()])?
+            .all()
+            .await?
+            .results::<DbMergeField>()?
+            .pop();
+
+        if let Some(slot) = slot {
+            self.free_merge_field_slot(token, list, &slot).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Marks a slot as free, renaming its merge field so that the audience's
+    /// owner can tell it is unused
+    async fn free_merge_field_slot(
+        &self,
+        token: &Token,
+        list: &List,
+        slot: &DbMergeField,
+    ) -> worker::Result<()> {
+        let name = slot.unused_name();
+
+        if list
+            .update_merge_field(token, slot.merge_id, &MergeFieldParams::rename(&name))
+            .await?
+            .is_none()
+        {
+            return self.delete_merge_field_slot(list, slot.merge_id).await;
+        }
+
+        self.db
+            .prepare(format!(
+                "UPDATE MergeFields SET Name = ?, CampaignId = NULL WHERE ListId = ? AND MergeId = {};",
+                slot.merge_id
+            ))
+            .bind(&[name.as_str().into(), list.0.as_str().into()])?
+            .all()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Releases the merge fields of the session's campaigns that were sent or
+    /// deleted in mailchimp. With `delete_free_slots` the free slots are then
+    /// deleted from mailchimp, giving the fields back to the audience.
+    pub async fn cleanup_merge_fields(
+        &self,
+        session_id: impl Into<JsValue> + Copy,
+        delete_free_slots: bool,
+    ) -> worker::Result<Response> {
+        #[derive(serde::Deserialize)]
+        struct DbCampaign {
+            #[serde(rename = "Id")]
+            id: String,
+            #[serde(rename = "Title")]
+            title: String,
+        }
+
+        let user_id = self.user_id(session_id).await?;
+        let token = self.access_token(session_id).await?;
+
+        let campaigns = self
+            .db
+            .prepare(format!(
+                "SELECT Id, Title FROM Campaigns WHERE UserId = {} AND Released = 0;",
+                user_id
+            ))
+            .bind(&[])?
+            .all()
+            .await?
+            .results::<DbCampaign>()?;
+
+        let mut released = Vec::new();
+        for campaign in campaigns {
+            let reason = match MailChimpCampaign::find(&token, &campaign.id).await? {
+                None => "deleted",
+                Some(mailchimp_campaign) if mailchimp_campaign.status == "sent" => "sent",
+                Some(_) => continue,
+            };
+
+            let fields = self.release_merge_fields(&token, &campaign.id).await?;
+            released.push(serde_json::json!({
+                "campaign_id": campaign.id,
+                "title": campaign.title,
+                "reason": reason,
+                "released_fields": fields,
+            }));
+        }
+
+        let mut deleted = Vec::new();
+        if delete_free_slots {
+            #[derive(serde::Deserialize)]
+            struct DbSlot {
+                #[serde(rename = "ListId")]
+                list_id: String,
+                #[serde(rename = "MergeId")]
+                merge_id: u64,
+                #[serde(rename = "Tag")]
+                tag: String,
+            }
+
+            let slots = self
+                .db
+                .prepare(format!(
+                    "DELETE FROM MergeFields WHERE CampaignId IS NULL AND ListId IN (SELECT Id FROM Lists WHERE UserId = {}) RETURNING ListId, MergeId, Tag;",
+                    user_id
+                ))
+                .bind(&[])?
+                .all()
+                .await?
+                .results::<DbSlot>()?;
+
+            for slot in slots {
+                List(slot.list_id.clone())
+                    .delete_merge_field(&token, slot.merge_id)
+                    .await?;
+                deleted.push(serde_json::json!({
+                    "list_id": slot.list_id,
+                    "tag": slot.tag,
+                }));
+            }
+        }
+
+        Response::from_json(&serde_json::json!({
+            "released": released,
+            "deleted": deleted,
+        }))
+    }
+
+    /// Reports how many merge fields each list of the session's user uses and
+    /// which campaigns hold them
+    pub async fn merge_field_usage(
+        &self,
+        session_id: impl Into<JsValue> + Copy,
+    ) -> worker::Result<Response> {
+        #[derive(serde::Deserialize)]
+        struct DbList {
+            #[serde(rename = "Id")]
+            id: String,
+        }
+
+        let user_id = self.user_id(session_id).await?;
+        let token = self.access_token(session_id).await?;
+
+        let lists = self
+            .db
+            .prepare(format!("SELECT Id FROM Lists WHERE UserId = {};", user_id))
+            .bind(&[])?
+            .all()
+            .await?
+            .results::<DbList>()?;
+
+        let mut usage = Vec::with_capacity(lists.len());
+        for list in lists {
+            let list = List(list.id);
+            let merge_fields = list.merge_fields(&token).await?;
+            let slots = self
+                .db
+                .prepare("SELECT * FROM MergeFields WHERE ListId = ?;")
+                .bind(&[list.0.as_str().into()])?
+                .all()
+                .await?
+                .results::<DbMergeField>()?;
+
+            let free_slots = slots
+                .iter()
+                .filter(|slot| slot.campaign_id.is_none())
+                .count();
+            let fields = slots
+                .iter()
+                .map(|slot| {
+                    serde_json::json!({
+                        "tag": slot.tag,
+                        "name": slot.name,
+                        "campaign_id": slot.campaign_id,
+                        "missing": merge_fields.by_tag(&slot.tag).is_none(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            let other_fields = merge_fields
+                .merge_fields
+                .iter()
+                .filter(|field| !slots.iter().any(|slot| slot.merge_id == field.merge_id))
+                .collect::<Vec<_>>();
+
+            // The limit depends on the plan of the account, which the API does
+            // not tell, so the count is informational
+            usage.push(serde_json::json!({
+                "list_id": list.0,
+                "used": merge_fields.total_items,
+                "free_slots": free_slots,
+                "fields": fields,
+                "other_fields": other_fields,
+            }));
+        }
+
+        Response::from_json(&serde_json::json!({
+            "lists": usage,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frees_slots_keeping_their_tag() {
+        let slot = DbMergeField {
+            merge_id: 7,
+            tag: "MCL_VIDEO".into(),
+            name: "Video".into(),
+            campaign_id: Some("c1".into()),
+        };
+
+        assert_eq!(slot.unused_name(), "Unused/MCL_VIDEO");
+    }
+}
@@ -0,0 +1,537 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use worker::{wasm_bindgen::JsValue, Response};
+
+use super::{fields::FieldType, CampaignField, Session};
+use crate::{
+    csv_import::CsvImport,
+    mailchimp::{lists::List, Token},
+    url_template::TemplateMember,
+};
+
+/// The values a member gets on a campaign keyed by field name
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Personalization {
+    pub email: String,
+    #[serde(flatten)]
+    pub values: BTreeMap<String, String>,
+}
+
+impl Personalization {
+    /// Emails are case insensitive in mailchimp, so they are stored lowercased
+    pub fn normalize_email(email: &str) -> String {
+        email.trim().to_lowercase()
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub(super) struct DbCampaign {
+    #[serde(rename = "Id")]
+    pub id: String,
+    #[serde(rename = "ListId")]
+    pub list_id: String,
+    /// Set once the merge fields of the campaign were released for reuse
+    #[serde(rename = "Released")]
+    pub released: u8,
+    /// Set when the video links of the campaign lead to its landing page
+    #[serde(rename = "LandingPage")]
+    pub landing_page: u8,
+    #[serde(skip)]
+    pub fields: Vec<CampaignField>,
+}
+
+impl DbCampaign {
+    pub fn has_templates(&self) -> bool {
+        self.fields.iter().any(|field| field.template.is_some())
+    }
+
+    /// The tags of the fields that hold a merge field keyed by field name
+    pub fn merge_tags(&self) -> BTreeMap<&str, &str> {
+        self.fields
+            .iter()
+            .filter_map(|field| Some((field.name.as_str(), field.tag.as_deref()?)))
+            .collect()
+    }
+
+    /// The merge field values of a member
+    pub fn merge_values(
+        &self,
+        personalization: Option<&Personalization>,
+        member: &TemplateMember,
+    ) -> Vec<(String, String)> {
+        self.fields
+            .iter()
+            .filter_map(|field| {
+                let tag = field.tag.clone()?;
+
+                Some((tag, field.value(self, personalization, member)))
+            })
+            .collect()
+    }
+
+    /// Validates the values of a personalization against the campaign's fields
+    pub fn normalize_personalization(
+        &self,
+        personalization: Personalization,
+    ) -> Result<Personalization, String> {
+        let email = Personalization::normalize_email(&personalization.email);
+
+        let mut values = BTreeMap::new();
+        for (name, value) in personalization.values {
+            let Some(field) = self.fields.iter().find(|field| field.name == name) else {
+                return Err(format!("{email}: the campaign has no '{name}' field"));
+            };
+            if field.ty == FieldType::Coupon {
+                return Err(format!(
+                    "{email}: '{name}' is given out from the coupon code pool"
+                ));
+            }
+            if let Some(link) = &field.qr_code_of {
+                return Err(format!(
+                    "{email}: '{name}' holds the QR code of the member's '{link}'"
+                ));
+            }
+            let value = field
+                .ty
+                .normalize(&value)
+                .map_err(|err| format!("{email}: invalid {name}: {err}"))?;
+
+            values.insert(name, value);
+        }
+
+        Ok(Personalization { email, values })
+    }
+
+    /// Whether the member's `merges` differ from the `values` rendered for
+    /// them. Tracked links are signed anew on every write, so the url a link
+    /// leads to is compared instead, and QR codes change along with their link.
+    fn merges_changed(
+        &self,
+        values: &[(String, String)],
+        merges: &BTreeMap<String, String>,
+        links: &HashMap<(String, String), String>,
+    ) -> bool {
+        let link_url = |field: &str| links.get(&(self.id.clone(), field.to_owned()));
+
+        values.iter().any(|(tag, value)| {
+            let merge = merges.get(tag).map(String::as_str).unwrap_or_default();
+            let Some(field) = self
+                .fields
+                .iter()
+                .find(|field| field.tag.as_ref() == Some(tag))
+            else {
+                return merge != value;
+            };
+
+            match (&field.tracking, &field.qr_code_of) {
+                // Empty values are not tracked
+                (Some(_), _) if !value.is_empty() => {
+                    merge.is_empty() || link_url(&field.name) != Some(&format!("https://{value}"))
+                }
+                (None, Some(link)) if link_url(link).is_some() => merge.is_empty(),
+                _ => merge != value,
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct DbPersonalization {
+    #[serde(rename = "CampaignId")]
+    campaign_id: String,
+    #[serde(rename = "EmailId")]
+    email: String,
+    #[serde(rename = "Field")]
+    field: String,
+    #[serde(rename = "Value")]
+    value: String,
+}
+
+impl Session {
+    /// D1 only allows 100 bound parameters per query, so bulk inserts are chunked
+    const PERSONALIZATIONS_PER_INSERT: usize = 25;
+
+    pub(super) async fn db_campaign(
+        &self,
+        campaign_id: &str,
+    ) -> worker::Result<Option<DbCampaign>> {
+        let campaign = self
+            .db
+            .prepare("SELECT * FROM Campaigns WHERE Id = ?;")
+            .bind(&[campaign_id.into()])?
+            .all()
+            .await?
+            .results::<DbCampaign>()?
+            .pop();
+
+        self.with_fields(campaign).await
+    }
+
+    async fn with_fields(
+        &self,
+        campaign: Option<DbCampaign>,
+    ) -> worker::Result<Option<DbCampaign>> {
+        let Some(mut campaign) = campaign else {
+            return Ok(None);
+        };
+        campaign.fields = self.campaign_fields(&campaign.id).await?;
+
+        Ok(Some(campaign))
+    }
+
+    /// Fetches a campaign if it was added by the session's user
+    pub(super) async fn db_campaign_of_session(
+        &self,
+        session_id: impl Into<JsValue>,
+        campaign_id: &str,
+    ) -> worker::Result<Option<DbCampaign>> {
+        let user_id = self.user_id(session_id).await?;
+
+        let campaign = self
+            .db
+            .prepare(format!(
+                "SELECT * FROM Campaigns WHERE Id = ? AND UserId = {};",
+                user_id
+            ))
+            .bind(&[campaign_id.into()])?
+            .all()
+            .await?
+            .results::<DbCampaign>()?
+            .pop();
+
+        self.with_fields(campaign).await
+    }
+
+    pub(super) async fn campaigns_of_list(&self, list_id: &str) -> worker::Result<Vec<DbCampaign>> {
+        let mut fields = self.list_fields(list_id).await?;
+
+        Ok(self
+            .db
+            .prepare("SELECT * FROM Campaigns WHERE ListId = ? AND Released = 0;")
+            .bind(&[list_id.into()])?
+            .all()
+            .await?
+            .results::<DbCampaign>()?
+            .into_iter()
+            .map(|mut campaign| {
+                campaign.fields = fields.remove(&campaign.id).unwrap_or_default();
+                campaign
+            })
+            .collect())
+    }
+
+    /// The personalizations of a campaign keyed by the normalized member email,
+    /// including the coupon codes members were given
+    pub(super) async fn campaign_personalizations(
+        &self,
+        campaign_id: &str,
+    ) -> worker::Result<HashMap<String, Personalization>> {
+        let personalizations = self
+            .db
+            .prepare("SELECT CampaignId, EmailId, Field, Value FROM Personalizations WHERE CampaignId = ?1 UNION ALL SELECT CampaignId, EmailId, Field, Code AS Value FROM CouponCodes WHERE CampaignId = ?1 AND EmailId IS NOT NULL;")
+            .bind(&[campaign_id.into()])?
+            .all()
+            .await?
+            .results::<DbPersonalization>()?;
+
+        Ok(Self::group_personalizations(
+            personalizations,
+            |personalization| personalization.email.clone(),
+        ))
+    }
+
+    /// The personalizations of a member keyed by the campaign id, including the
+    /// coupon codes the member was given
+    pub(super) async fn member_personalizations(
+        &self,
+        email: &str,
+        list_id: &str,
+    ) -> worker::Result<HashMap<String, Personalization>> {
+        let personalizations = self
+            .db
+            .prepare("SELECT CampaignId, EmailId, Field, Value FROM Personalizations WHERE EmailId = ?1 AND CampaignId IN (SELECT Id FROM Campaigns WHERE ListId = ?2) UNION ALL SELECT CampaignId, EmailId, Field, Code AS Value FROM CouponCodes WHERE EmailId = ?1 AND CampaignId IN (SELECT Id FROM Campaigns WHERE ListId = ?2);")
+            .bind(&[Personalization::normalize_email(email).into(), list_id.into()])?
+            .all()
+            .await?
+            .results::<DbPersonalization>()?;
+
+        Ok(Self::group_personalizations(
+            personalizations,
+            |personalization| personalization.campaign_id.clone(),
+        ))
+    }
+
+    /// Collects the stored values, one row per field, into personalizations
+    fn group_personalizations(
+        personalizations: Vec<DbPersonalization>,
+        key: impl Fn(&DbPersonalization) -> String,
+    ) -> HashMap<String, Personalization> {
+        let mut grouped = HashMap::<String, Personalization>::new();
+
+        for personalization in personalizations {
+            grouped
+                .entry(key(&personalization))
+                .or_insert_with(|| Personalization {
+                    email: personalization.email.clone(),
+                    values: BTreeMap::new(),
+                })
+                .values
+                .insert(personalization.field, personalization.value);
+        }
+
+        grouped
+    }
+
+    /// Writes the merge field values of a campaign to the active members of its
+    /// list. When `emails` is given only those members are written.
+    pub(super) async fn push_campaign_values(
+        &self,
+        token: &Token,
+        campaign: &DbCampaign,
+        emails: Option<&HashSet<String>>,
+    ) -> worker::Result<()> {
+        // The tags of a released campaign may belong to another campaign by now
+        if campaign.released != 0 {
+            return Ok(());
+        }
+
+        let personalizations = self.campaign_personalizations(&campaign.id).await?;
+        let list = List(campaign.list_id.clone());
+
+        // The members are fetched from mailchimp as url templates need their merge fields
+        let mut values = list
+            .fetch_members(token, Option::<&str>::None)
+            .await?
+            .members
+            .into_iter()
+            .filter(|member| member.is_active())
+            .filter_map(|member| {
+                let email = Personalization::normalize_email(&member.email_address);
+                let wanted = match emails {
+                    Some(emails) => emails.contains(&email),
+                    None => true,
+                };
+
+                if wanted {
+                    let merges = member.merge_values();
+                    let values = campaign.merge_values(
+                        personalizations.get(&email),
+                        &TemplateMember {
+                            email: &member.email_address,
+                            name: &member.full_name,
+                            merges: &merges,
+                        },
+                    );
+
+                    Some((member.email_address, values))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if !values.is_empty() {
+            self.track_links(campaign, &mut values).await?;
+            list.set_member_merge_field_batch(token, values).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the merge field values of every campaign in `campaigns` to a
+    /// member. Campaigns whose values the member already has are skipped, as
+    /// every write comes back as a profile webhook call rendering them again.
+    pub(super) async fn push_member_values(
+        &self,
+        token: &Token,
+        campaigns: &[DbCampaign],
+        email: &str,
+        name: &str,
+        merges: &BTreeMap<String, String>,
+        list_id: &str,
+    ) -> worker::Result<()> {
+        let personalizations = self.member_personalizations(email, list_id).await?;
+        let member = TemplateMember {
+            email,
+            name,
+            merges,
+        };
+
+        let links = self.member_link_urls(email).await?;
+
+        let mut values = Vec::with_capacity(campaigns.len());
+        for campaign in campaigns {
+            let campaign_values =
+                campaign.merge_values(personalizations.get(&campaign.id), &member);
+            if !campaign.merges_changed(&campaign_values, merges, &links) {
+                continue;
+            }
+
+            let mut campaign_values = [(email.to_owned(), campaign_values)];
+            self.track_links(campaign, &mut campaign_values).await?;
+            values.extend(campaign_values);
+        }
+        if values.is_empty() {
+            return Ok(());
+        }
+
+        List(list_id.to_owned())
+            .set_member_merge_field_batch(token, values)
+            .await
+    }
+
+    pub async fn personalizations(
+        &self,
+        session_id: impl Into<JsValue>,
+        campaign_id: &str,
+    ) -> worker::Result<Response> {
+        let Some(campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+
+        let mut personalizations = self
+            .campaign_personalizations(&campaign.id)
+            .await?
+            .into_values()
+            .collect::<Vec<_>>();
+        personalizations.sort_by(|a, b| a.email.cmp(&b.email));
+
+        Response::from_json(&serde_json::json!({
+            "fields": campaign.fields,
+            "personalizations": personalizations,
+        }))
+    }
+
+    /// Stores the personalizations of a campaign, replacing earlier values of
+    /// the same fields, and writes them to mailchimp
+    pub async fn set_personalizations(
+        &self,
+        session_id: impl Into<JsValue> + Copy,
+        campaign_id: &str,
+        personalizations: Vec<Personalization>,
+    ) -> worker::Result<Response> {
+        let Some(campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+        let token = self.access_token(session_id).await?;
+
+        let personalizations = match personalizations
+            .into_iter()
+            .map(|personalization| campaign.normalize_personalization(personalization))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(personalizations) => personalizations,
+            Err(err) => return Response::error(err, 400),
+        };
+
+        let updated = self
+            .store_personalizations(&token, &campaign, personalizations)
+            .await?;
+
+        Response::from_json(&serde_json::json!({
+            "updated": updated,
+        }))
+    }
+
+    /// Validates a csv of member emails with their values of the campaign's
+    /// fields and stores the rows that passed. Each row's outcome is reported back.
+    pub async fn import_personalizations_csv(
+        &self,
+        session_id: impl Into<JsValue> + Copy,
+        campaign_id: &str,
+        body: &[u8],
+    ) -> worker::Result<Response> {
+        #[derive(serde::Deserialize)]
+        struct DbMember {
+            #[serde(rename = "EmailId")]
+            email: String,
+        }
+
+        let Some(campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+        let token = self.access_token(session_id).await?;
+
+        let members = self
+            .db
+            .prepare("SELECT EmailId FROM Members WHERE ListId = ?;")
+            .bind(&[campaign.list_id.as_str().into()])?
+            .all()
+            .await?
+            .results::<DbMember>()?
+            .into_iter()
+            .map(|member| Personalization::normalize_email(&member.email))
+            .collect::<HashSet<_>>();
+
+        let import = match CsvImport::parse(body, &members, &campaign.fields) {
+            Ok(import) => import,
+            Err(err) => return Response::error(err, 400),
+        };
+
+        let accepted = import.accepted.len();
+        let rejected = import.rows.len() - accepted;
+        self.store_personalizations(&token, &campaign, import.accepted)
+            .await?;
+
+        Response::from_json(&serde_json::json!({
+            "accepted": accepted,
+            "rejected": rejected,
+            "rows": import.rows,
+        }))
+    }
+
+    /// Upserts normalized personalizations and writes them to the members in
+    /// mailchimp. Returns the number of members that were updated.
+    async fn store_personalizations(
+        &self,
+        token: &Token,
+        campaign: &DbCampaign,
+        personalizations: Vec<Personalization>,
+    ) -> worker::Result<usize> {
+        let rows = personalizations
+            .iter()
+            .flat_map(|personalization| {
+                personalization
+                    .values
+                    .iter()
+                    .map(|(field, value)| (&personalization.email, field, value))
+            })
+            .collect::<Vec<_>>();
+
+        for chunk in rows.chunks(Self::PERSONALIZATIONS_PER_INSERT) {
+            let values = chunk
+                .iter()
+                .flat_map(|(email, field, value)| {
+                    [
+                        campaign.id.as_str().into(),
+                        email.as_str().into(),
+                        field.as_str().into(),
+                        value.as_str().into(),
+                    ]
+                })
+                .collect::<Vec<JsValue>>();
+
+            self.db
+                .prepare(format!(
+                    "INSERT INTO Personalizations (CampaignId, EmailId, Field, Value) VALUES {} ON CONFLICT (CampaignId, EmailId, Field) DO UPDATE SET Value = excluded.Value;",
+                    vec!["(?, ?, ?, ?)"; chunk.len()].join(",")
+                ))
+                .bind(&values)?
+                .all()
+                .await?;
+        }
+
+        if personalizations.is_empty() {
+            return Ok(0);
+        }
+
+        let emails = personalizations
+            .iter()
+            .map(|personalization| personalization.email.clone())
+            .collect::<HashSet<_>>();
+        self.push_campaign_values(token, campaign, Some(&emails))
+            .await?;
+
+        Ok(emails.len())
+    }
+}
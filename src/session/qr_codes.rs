@@ -0,0 +1,123 @@
+use worker::{wasm_bindgen::JsValue, Response};
+
+use super::{personalization::DbCampaign, Personalization, Session, Tracking};
+use crate::{
+    link_signing::{self, LinkError},
+    qr_code::{self, Format},
+};
+
+impl Session {
+    /// Renders the QR code of a member's tracked link, which is the signed
+    /// link itself so scans are recorded as clicks when they are followed
+    pub async fn qr_code(
+        &self,
+        token: &str,
+        format: Format,
+    ) -> worker::Result<Result<Vec<u8>, LinkError>> {
+        let link = match self.verified_link(token).await? {
+            Ok(link) if link.tracking == Some(Tracking::Click) => link,
+            Ok(_) => return Ok(Err(LinkError::NotFound)),
+            Err(err) => return Ok(Err(err)),
+        };
+
+        self.render_qr_code(token, link.landing_page != 0, format)
+            .map(Ok)
+    }
+
+    /// Renders the QR code of the link a member got for a tracked link field
+    /// of a campaign, for users to print it. The first field with tracked
+    /// clicks is used when no `field` is given.
+    pub async fn member_qr_code(
+        &self,
+        session_id: impl Into<JsValue>,
+        campaign_id: &str,
+        email: &str,
+        field: Option<&str>,
+        format: Format,
+    ) -> worker::Result<Response> {
+        #[derive(serde::Deserialize)]
+        struct DbLink {
+            #[serde(rename = "Id")]
+            id: String,
+            #[serde(rename = "CreatedAt")]
+            created_at: i64,
+        }
+
+        let Some(campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+        let Some(field) = Self::qr_code_field(&campaign, field) else {
+            return Response::error(
+                format!(
+                    "The campaign has no field '{}' with tracked clicks",
+                    field.unwrap_or_default()
+                ),
+                404,
+            );
+        };
+        let email = Personalization::normalize_email(email);
+
+        let Some(link) = self
+            .db
+            .prepare("SELECT Id, CreatedAt FROM Links WHERE CampaignId = ? AND EmailId = ? AND Field = ?;")
+            .bind(&[
+                campaign.id.as_str().into(),
+                email.as_str().into(),
+                field.into(),
+            ])?
+            .all()
+            .await?
+            .results::<DbLink>()?
+            .pop()
+        else {
+            return Response::error(
+                format!("'{email}' has no '{field}' link, populate the campaign first"),
+                404,
+            );
+        };
+
+        // The same token the member got in the email
+        let token = link_signing::sign(
+            self.link_key()?,
+            &campaign.id,
+            &email,
+            &link.id,
+            link.created_at + link_signing::LIFETIME_SECS,
+        );
+        let image = self.render_qr_code(&token, campaign.landing_page != 0, format)?;
+
+        let mut resp = Response::from_bytes(image)?;
+        resp.headers_mut()
+            .set("Content-Type", format.content_type())?;
+
+        Ok(resp)
+    }
+
+    fn qr_code_field<'a>(campaign: &'a DbCampaign, field: Option<&str>) -> Option<&'a str> {
+        campaign
+            .fields
+            .iter()
+            .filter(|candidate| candidate.tracking == Some(Tracking::Click))
+            .find(|candidate| field.is_none_or(|field| candidate.name == field))
+            .map(|field| field.name.as_str())
+    }
+
+    fn render_qr_code(
+        &self,
+        token: &str,
+        landing_page: bool,
+        format: Format,
+    ) -> worker::Result<Vec<u8>> {
+        // The same link the member gets in the email
+        let base = if landing_page {
+            &self.watch_uri
+        } else {
+            &self.link_uri
+        };
+        let url = base
+            .join(token)
+            .map_err(|err| worker::Error::RustError(format!("Invalid link url: {err}")))?;
+
+        qr_code::render(url.as_str(), format).map_err(worker::Error::RustError)
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::{BTreeMap, HashSet};
+
+use worker::{wasm_bindgen::JsValue, Response};
+
+use super::{tracking::DbLinkTarget, Session, Tracking};
+use crate::{
+    routing::{self, Device, RoutingRule, Visitor},
+    url_template::{TemplateMember, UrlTemplate},
+};
+
+/// Where a followed link is sent, as routed by one of the campaign's rules
+pub(super) struct Route {
+    pub variant: String,
+    pub url: url::Url,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct DbRoutingRule {
+    #[serde(rename = "Field")]
+    field: String,
+    #[serde(rename = "Variant")]
+    variant: String,
+    #[serde(rename = "Countries")]
+    countries: String,
+    #[serde(rename = "Devices")]
+    devices: String,
+    #[serde(rename = "Languages")]
+    languages: String,
+    #[serde(rename = "Url")]
+    url: String,
+}
+
+impl From<DbRoutingRule> for RoutingRule {
+    fn from(rule: DbRoutingRule) -> Self {
+        let list = |values: &str| {
+            values
+                .split(',')
+                .filter(|value| !value.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+
+        RoutingRule {
+            field: rule.field,
+            variant: rule.variant,
+            countries: list(&rule.countries),
+            devices: rule.devices.split(',').filter_map(Device::parse).collect(),
+            languages: list(&rule.languages),
+            url: rule.url,
+        }
+    }
+}
+
+impl Session {
+    pub async fn routing_rules(
+        &self,
+        session_id: impl Into<JsValue>,
+        campaign_id: &str,
+    ) -> worker::Result<Response> {
+        let Some(campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+
+        let rules = self
+            .db
+            .prepare("SELECT * FROM RoutingRules WHERE CampaignId = ? ORDER BY Position;")
+            .bind(&[campaign.id.as_str().into()])?
+            .all()
+            .await?
+            .results::<DbRoutingRule>()?
+            .into_iter()
+            .map(RoutingRule::from)
+            .collect::<Vec<_>>();
+
+        Response::from_json(&rules)
+    }
+
+    /// Replaces the routing rules of a campaign. Clicks are routed by the
+    /// first rule of their field they meet, in the order of `rules`.
+    pub async fn set_routing_rules(
+        &self,
+        session_id: impl Into<JsValue>,
+        campaign_id: &str,
+        mut rules: Vec<RoutingRule>,
+    ) -> worker::Result<Response> {
+        let Some(campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+
+        let mut variants = HashSet::new();
+        for rule in &mut rules {
+            if let Err(err) = rule.validate() {
+                return Response::error(err, 400);
+            }
+            if !variants.insert(rule.variant.clone()) {
+                return Response::error(
+                    format!("The variant '{}' is declared twice", rule.variant),
+                    400,
+                );
+            }
+            // Only tracked links reach the worker to be routed
+            if !campaign
+                .fields
+                .iter()
+                .any(|field| field.name == rule.field && field.tracking == Some(Tracking::Click))
+            {
+                return Response::error(
+                    format!(
+                        "The variant '{}' routes '{}', which is no field with tracked clicks",
+                        rule.variant, rule.field
+                    ),
+                    400,
+                );
+            }
+        }
+
+        self.db
+            .prepare("DELETE FROM RoutingRules WHERE CampaignId = ?;")
+            .bind(&[campaign.id.as_str().into()])?
+            .all()
+            .await?;
+        for (position, rule) in rules.iter().enumerate() {
+            self.db
+                .prepare(format!(
+                    "INSERT INTO RoutingRules (CampaignId, Field, Variant, Countries, Devices, Languages, Url, Position) VALUES (?, ?, ?, ?, ?, ?, ?, {position});"
+                ))
+                .bind(&[
+                    campaign.id.as_str().into(),
+                    rule.field.as_str().into(),
+                    rule.variant.as_str().into(),
+                    rule.countries.join(",").into(),
+                    rule.devices
+                        .iter()
+                        .map(Device::as_str)
+                        .collect::<Vec<_>>()
+                        .join(",")
+                        .into(),
+                    rule.languages.join(",").into(),
+                    rule.url.as_str().into(),
+                ])?
+                .all()
+                .await?;
+        }
+
+        Response::from_json(&rules)
+    }
+
+    /// Where a click on a link is routed to by the first rule the visitor
+    /// meets, `None` if the click goes to the member's own url
+    pub(super) async fn route(
+        &self,
+        link: &DbLinkTarget,
+        visitor: &Visitor,
+    ) -> worker::Result<Option<Route>> {
+        #[derive(serde::Deserialize)]
+        struct DbMember {
+            #[serde(rename = "ListId")]
+            list_id: String,
+            #[serde(rename = "FullName")]
+            full_name: Option<String>,
+        }
+
+        let rules = self
+            .db
+            .prepare(
+                "SELECT * FROM RoutingRules WHERE CampaignId = ? AND Field = ? ORDER BY Position;",
+            )
+            .bind(&[link.campaign_id.as_str().into(), link.field.as_str().into()])?
+            .all()
+            .await?
+            .results::<DbRoutingRule>()?
+            .into_iter()
+            .map(RoutingRule::from)
+            .collect::<Vec<_>>();
+
+        let Some(rule) = routing::route(&rules, visitor) else {
+            return Ok(None);
+        };
+
+        let member = self
+            .db
+            .prepare("SELECT Campaigns.ListId, Members.FullName FROM Campaigns LEFT JOIN Members ON Members.ListId = Campaigns.ListId AND lower(trim(Members.EmailId)) = ?2 WHERE Campaigns.Id = ?1;")
+            .bind(&[link.campaign_id.as_str().into(), link.email.as_str().into()])?
+            .all()
+            .await?
+            .results::<DbMember>()?
+            .pop()
+            .ok_or_else(|| worker::Error::RustError("Failed to find the campaign of a link".into()))?;
+        let url = UrlTemplate(&rule.url).render_route(
+            &link.campaign_id,
+            &member.list_id,
+            &TemplateMember {
+                email: &link.email,
+                name: member.full_name.as_deref().unwrap_or_default(),
+                // Merge fields are only known to mailchimp
+                merges: &BTreeMap::new(),
+            },
+            &link.url,
+        );
+        let url = url::Url::parse(&url)
+            .map_err(|err| worker::Error::RustError(format!("Invalid variant url: {err}")))?;
+
+        Ok(Some(Route {
+            variant: rule.variant.clone(),
+            url,
+        }))
+    }
+}
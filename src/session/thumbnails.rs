@@ -0,0 +1,53 @@
+use worker::Fetch;
+
+use super::Session;
+use crate::{
+    link_signing::LinkError,
+    thumbnail::{self, Format},
+};
+
+impl Session {
+    /// Renders the image of a signed link with its overlay. Views are recorded
+    /// by the image redirect, not here.
+    pub async fn thumbnail(
+        &self,
+        token: &str,
+        format: Format,
+    ) -> worker::Result<Result<Vec<u8>, LinkError>> {
+        #[derive(serde::Deserialize)]
+        struct DbMember {
+            #[serde(rename = "FullName")]
+            full_name: String,
+        }
+
+        let link = match self.verified_link(token).await? {
+            Ok(link) if link.overlay != 0 => link,
+            Ok(_) => return Ok(Err(LinkError::NotFound)),
+            Err(err) => return Ok(Err(err)),
+        };
+
+        let first_name = self
+            .db
+            .prepare("SELECT Members.FullName FROM Members JOIN Campaigns ON Campaigns.ListId = Members.ListId WHERE Campaigns.Id = ? AND lower(trim(Members.EmailId)) = ?;")
+            .bind(&[link.campaign_id.as_str().into(), link.email.as_str().into()])?
+            .all()
+            .await?
+            .results::<DbMember>()?
+            .pop()
+            .and_then(|member| member.full_name.split_whitespace().next().map(String::from));
+
+        let mut resp = Fetch::Url(link.url()?).send().await?;
+        if resp.status_code() != 200 {
+            return Err(worker::Error::RustError(format!(
+                "Failed to fetch the image {}: {}",
+                link.url,
+                resp.status_code()
+            )));
+        }
+        let base = resp.bytes().await?;
+
+        thumbnail::render(&base, first_name.as_deref(), format)
+            .map(Ok)
+            .map_err(worker::Error::RustError)
+    }
+}
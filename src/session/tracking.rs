@@ -0,0 +1,585 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use worker::{wasm_bindgen::JsValue, Response};
+
+use super::{personalization::DbCampaign, Personalization, Session};
+use crate::{
+    hit_filter::{self, MachineReason},
+    link_signing::{self, LinkError, SignedLink},
+    qr_code,
+    routing::Visitor,
+    thumbnail::Format,
+};
+
+/// How the links of a tracked field are recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tracking {
+    /// Members follow the link, like the link to their video
+    Click,
+    /// Members load the link as an image, which counts as an open of the
+    /// personalized block
+    Impression,
+}
+
+impl Tracking {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Tracking::Click => "click",
+            Tracking::Impression => "impression",
+        }
+    }
+}
+
+/// A link as it is followed, along with what its signature is checked against
+#[derive(Debug, Clone, serde::Deserialize)]
+pub(super) struct DbLinkTarget {
+    #[serde(rename = "Id")]
+    pub id: String,
+    #[serde(rename = "Url")]
+    pub url: String,
+    #[serde(rename = "CampaignId")]
+    pub campaign_id: String,
+    #[serde(rename = "EmailId")]
+    pub email: String,
+    #[serde(rename = "Field")]
+    pub field: String,
+    #[serde(rename = "SentAt")]
+    pub sent_at: Option<i64>,
+    /// The active members of the list when the campaign was sent
+    #[serde(rename = "Recipients")]
+    pub recipients: i64,
+    #[serde(rename = "LandingPage")]
+    pub landing_page: u8,
+    #[serde(rename = "Tracking")]
+    pub tracking: Option<Tracking>,
+    /// Set when the link is an image that gets an overlay drawn over it
+    #[serde(rename = "Overlay")]
+    pub overlay: u8,
+}
+
+impl DbLinkTarget {
+    pub fn url(&self) -> worker::Result<url::Url> {
+        url::Url::parse(&self.url)
+            .map_err(|err| worker::Error::RustError(format!("Invalid link url: {err}")))
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct DbLink {
+    #[serde(rename = "Id")]
+    id: String,
+    #[serde(rename = "EmailId")]
+    email: String,
+    #[serde(rename = "Field")]
+    field: String,
+}
+
+/// The clicks and impressions of a member on the links of a campaign. Only
+/// the hits of the member count, machine hits are counted apart.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct MemberViews {
+    #[serde(rename(deserialize = "EmailId"))]
+    email: String,
+    #[serde(rename(deserialize = "Clicks"))]
+    clicks: u64,
+    #[serde(rename(deserialize = "Impressions"))]
+    impressions: u64,
+    #[serde(rename(deserialize = "MachineHits"))]
+    machine_hits: u64,
+    #[serde(rename(deserialize = "FirstSeenAt"))]
+    first_seen_at: Option<i64>,
+    #[serde(rename(deserialize = "LastSeenAt"))]
+    last_seen_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct MachineHits {
+    #[serde(rename(deserialize = "Reason"))]
+    reason: MachineReason,
+    #[serde(rename(deserialize = "Hits"))]
+    hits: u64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct CountryViews {
+    #[serde(rename(deserialize = "Country"))]
+    country: Option<String>,
+    #[serde(rename(deserialize = "Clicks"))]
+    clicks: u64,
+    #[serde(rename(deserialize = "Impressions"))]
+    impressions: u64,
+}
+
+/// The clicks routed to a variant, `None` for the clicks that went to the
+/// members' own urls
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct VariantViews {
+    #[serde(rename(deserialize = "Variant"))]
+    variant: Option<String>,
+    #[serde(rename(deserialize = "Clicks"))]
+    clicks: u64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct Hit {
+    #[serde(rename(deserialize = "Field"))]
+    field: String,
+    #[serde(rename(deserialize = "Kind"))]
+    kind: Tracking,
+    #[serde(rename(deserialize = "HitAt"))]
+    hit_at: i64,
+    #[serde(rename(deserialize = "UserAgent"))]
+    user_agent: Option<String>,
+    #[serde(rename(deserialize = "Country"))]
+    country: Option<String>,
+    /// Why the hit was made by a machine, `None` for the member's own hits
+    #[serde(rename(deserialize = "Reason"))]
+    reason: Option<MachineReason>,
+    #[serde(rename(deserialize = "Variant"))]
+    variant: Option<String>,
+}
+
+impl Session {
+    /// D1 only allows 100 bound parameters per query, so bulk inserts are chunked
+    const LINKS_PER_INSERT: usize = 20;
+
+    const LINK_ID_ALPHABET: &'static [u8; 62] =
+        b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+    /// A random id that is short enough for a merge field
+    fn link_id() -> String {
+        let mut random = uuid::Uuid::new_v4().as_u128();
+        let mut id = String::with_capacity(22);
+        while random > 0 {
+            id.push(Self::LINK_ID_ALPHABET[(random % 62) as usize] as char);
+            random /= 62;
+        }
+
+        id
+    }
+
+    /// Whether a token can be a [`link_id`](Self::link_id), so malformed
+    /// tokens are not looked up
+    fn is_link_id(token: &str) -> bool {
+        (1..=22).contains(&token.len()) && token.bytes().all(|byte| byte.is_ascii_alphanumeric())
+    }
+
+    /// The urls the tracked links of a member lead to, keyed by the campaign
+    /// and field of the link
+    pub(super) async fn member_link_urls(
+        &self,
+        email: &str,
+    ) -> worker::Result<HashMap<(String, String), String>> {
+        #[derive(serde::Deserialize)]
+        struct DbLinkUrl {
+            #[serde(rename = "CampaignId")]
+            campaign_id: String,
+            #[serde(rename = "Field")]
+            field: String,
+            #[serde(rename = "Url")]
+            url: String,
+        }
+
+        Ok(self
+            .db
+            .prepare("SELECT CampaignId, Field, Url FROM Links WHERE EmailId = ?;")
+            .bind(&[Personalization::normalize_email(email).into()])?
+            .all()
+            .await?
+            .results::<DbLinkUrl>()?
+            .into_iter()
+            .map(|link| ((link.campaign_id, link.field), link.url))
+            .collect())
+    }
+
+    /// Replaces the values of a campaign's tracked fields in the merge field
+    /// `values` of members by links to the worker, which redirect to the value.
+    /// A member keeps the link of a field when its value changes, but the link
+    /// is signed anew and expires [`link_signing::LIFETIME_SECS`] from now.
+    /// QR code fields get the image of the link they are made of.
+    pub(super) async fn track_links(
+        &self,
+        campaign: &DbCampaign,
+        values: &mut [(String, Vec<(String, String)>)],
+    ) -> worker::Result<()> {
+        let tracked = campaign
+            .fields
+            .iter()
+            .filter_map(|field| Some((field.tag.clone()?, (field.name.clone(), field.tracking?))))
+            .collect::<HashMap<_, _>>();
+        if tracked.is_empty() {
+            return Ok(());
+        }
+        let key = self.link_key()?;
+        let qr_codes = campaign
+            .fields
+            .iter()
+            .filter_map(|field| Some((field.tag.clone()?, field.qr_code_of.clone()?)))
+            .collect::<HashMap<_, _>>();
+
+        let rows = values
+            .iter()
+            .flat_map(|(email, values)| {
+                values.iter().filter_map(|(tag, value)| {
+                    let (field, _) = tracked.get(tag).filter(|_| !value.is_empty())?;
+
+                    Some((
+                        Personalization::normalize_email(email),
+                        field.as_str(),
+                        format!("https://{value}"),
+                    ))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let created_at = time::OffsetDateTime::now_utc().unix_timestamp();
+        let mut links = HashMap::new();
+        for chunk in rows.chunks(Self::LINKS_PER_INSERT) {
+            let binds = chunk
+                .iter()
+                .flat_map(|(email, field, url)| {
+                    [
+                        Self::link_id().into(),
+                        campaign.id.as_str().into(),
+                        email.as_str().into(),
+                        (*field).into(),
+                        url.as_str().into(),
+                    ]
+                })
+                .collect::<Vec<JsValue>>();
+
+            for link in self
+                .db
+                .prepare(format!(
+                    "INSERT INTO Links (Id, CampaignId, EmailId, Field, Url, CreatedAt) VALUES {} ON CONFLICT (CampaignId, EmailId, Field) DO UPDATE SET Url = excluded.Url RETURNING Id, EmailId, Field;",
+                    vec![format!("(?, ?, ?, ?, ?, {created_at})"); chunk.len()].join(",")
+                ))
+                .bind(&binds)?
+                .all()
+                .await?
+                .results::<DbLink>()?
+            {
+                links.insert((link.email, link.field), link.id);
+            }
+        }
+
+        for (email, values) in values.iter_mut() {
+            let email = Personalization::normalize_email(email);
+            let mut tokens = HashMap::new();
+            for (tag, value) in values.iter_mut() {
+                let Some((field, tracking)) = tracked.get(tag) else {
+                    continue;
+                };
+                let Some(id) = links.get(&(email.clone(), field.clone())) else {
+                    continue;
+                };
+                let token = link_signing::sign(
+                    key,
+                    &campaign.id,
+                    &email,
+                    id,
+                    created_at + link_signing::LIFETIME_SECS,
+                );
+
+                // Url fields are stored without a scheme
+                let base = match tracking {
+                    Tracking::Click if campaign.landing_page != 0 => &self.watch_uri,
+                    Tracking::Click => &self.link_uri,
+                    Tracking::Impression => &self.image_uri,
+                };
+                *value = format!("{}{token}", &base[url::Position::BeforeHost..]);
+                tokens.insert(field.as_str(), token);
+            }
+
+            // Members without the link keep the field's default
+            for (tag, value) in values.iter_mut() {
+                let Some(token) = qr_codes.get(tag).and_then(|link| tokens.get(link.as_str()))
+                else {
+                    continue;
+                };
+                *value = format!(
+                    "{}{token}.{}",
+                    &self.qr_code_uri[url::Position::BeforeHost..],
+                    qr_code::Format::Png.extension()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records a click or impression of a signed link, returning where it
+    /// redirects to. Links that are unknown, tampered with or expired are not
+    /// recorded. Images with an overlay redirect to their rendering, which
+    /// unlike the redirect can be cached. Clicks go to the variant of the
+    /// first routing rule the member meets, if any.
+    pub async fn follow_link(
+        &self,
+        token: &str,
+        kind: Tracking,
+        user_agent: Option<String>,
+        ip: Option<IpAddr>,
+        country: Option<String>,
+        accept_language: Option<String>,
+    ) -> worker::Result<Result<url::Url, LinkError>> {
+        let link = match self.verified_link(token).await? {
+            Ok(link) => link,
+            Err(err) => return Ok(Err(err)),
+        };
+        let route = match kind {
+            Tracking::Click => {
+                let visitor = Visitor::new(
+                    country.as_deref(),
+                    user_agent.as_deref(),
+                    accept_language.as_deref(),
+                );
+                self.route(&link, &visitor).await?
+            }
+            Tracking::Impression => None,
+        };
+        let variant = route.as_ref().map(|route| route.variant.as_str());
+        self.record_hit(&link, kind, user_agent, ip, country, variant)
+            .await?;
+
+        if link.overlay != 0 {
+            return self
+                .thumbnail_uri
+                .join(&format!("{token}.{}", Format::Jpeg.extension()))
+                .map(Ok)
+                .map_err(|err| worker::Error::RustError(format!("Invalid thumbnail url: {err}")));
+        }
+
+        match route {
+            Some(route) => Ok(Ok(route.url)),
+            None => link.url().map(Ok),
+        }
+    }
+
+    /// The link of a signed token, once its signature and expiry are checked
+    pub(super) async fn verified_link(
+        &self,
+        token: &str,
+    ) -> worker::Result<Result<DbLinkTarget, LinkError>> {
+        let signed = match SignedLink::parse(token) {
+            Ok(signed) if Self::is_link_id(signed.link_id) => signed,
+            Ok(_) => return Ok(Err(LinkError::Malformed)),
+            Err(err) => return Ok(Err(err)),
+        };
+
+        let Some(link) = self
+            .db
+            .prepare("SELECT Links.Id, Links.Url, Links.CampaignId, Links.EmailId, Links.Field, Campaigns.SentAt, Campaigns.Recipients, Campaigns.LandingPage, CampaignFields.Tracking, CampaignFields.Overlay FROM Links JOIN Campaigns ON Campaigns.Id = Links.CampaignId JOIN CampaignFields ON CampaignFields.CampaignId = Links.CampaignId AND CampaignFields.Name = Links.Field WHERE Links.Id = ?;")
+            .bind(&[signed.link_id.into()])?
+            .all()
+            .await?
+            .results::<DbLinkTarget>()?
+            .pop()
+        else {
+            return Ok(Err(LinkError::NotFound));
+        };
+
+        Ok(signed
+            .verify(
+                self.link_key()?,
+                &link.campaign_id,
+                &link.email,
+                time::OffsetDateTime::now_utc().unix_timestamp(),
+            )
+            .map(|()| link))
+    }
+
+    /// Records a hit on a link, along with the variant a click was routed to.
+    /// Hits that look like they were made by scanners or privacy proxies are
+    /// recorded as machine hits.
+    pub(super) async fn record_hit(
+        &self,
+        link: &DbLinkTarget,
+        kind: Tracking,
+        user_agent: Option<String>,
+        ip: Option<IpAddr>,
+        country: Option<String>,
+        variant: Option<&str>,
+    ) -> worker::Result<()> {
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        let reason = hit_filter::classify(
+            user_agent.as_deref(),
+            ip,
+            link.sent_at.map(|sent_at| now - sent_at),
+            link.recipients,
+        );
+        let ip_hash = ip.map(hit_filter::hash_ip);
+
+        self.db
+            .prepare(format!(
+                "INSERT INTO Hits (LinkId, Kind, HitAt, UserAgent, IpHash, Country, Machine, Reason, Variant) VALUES (?, ?, {}, ?, ?, ?, {}, ?, ?);",
+                now,
+                u8::from(reason.is_some())
+            ))
+            .bind(&[
+                link.id.as_str().into(),
+                kind.as_str().into(),
+                user_agent.map(JsValue::from).unwrap_or(JsValue::NULL),
+                ip_hash.as_deref().map(JsValue::from).unwrap_or(JsValue::NULL),
+                country.map(JsValue::from).unwrap_or(JsValue::NULL),
+                reason
+                    .map(|reason| JsValue::from(reason.as_str()))
+                    .unwrap_or(JsValue::NULL),
+                variant.map(JsValue::from).unwrap_or(JsValue::NULL),
+            ])?
+            .all()
+            .await?;
+
+        // Once the same ip hit every link of the member within moments, the
+        // hits turn out to be a scanner's rather than the member's
+        if let (None, Some(ip_hash)) = (reason, ip_hash) {
+            self.db
+                .prepare(format!(
+                    "UPDATE Hits SET Machine = 1, Reason = ?4 \
+                    WHERE IpHash = ?3 AND HitAt >= {since} AND Machine = 0 AND LinkId IN (SELECT Id FROM Links WHERE CampaignId = ?1 AND EmailId = ?2) \
+                    AND (SELECT COUNT(*) FROM Links WHERE CampaignId = ?1 AND EmailId = ?2) > 1 \
+                    AND (SELECT COUNT(DISTINCT Recent.LinkId) FROM Hits AS Recent JOIN Links ON Links.Id = Recent.LinkId WHERE Links.CampaignId = ?1 AND Links.EmailId = ?2 AND Recent.IpHash = ?3 AND Recent.HitAt >= {since}) \
+                    = (SELECT COUNT(*) FROM Links WHERE CampaignId = ?1 AND EmailId = ?2);",
+                    since = now - hit_filter::BURST_SECS
+                ))
+                .bind(&[
+                    link.campaign_id.as_str().into(),
+                    link.email.as_str().into(),
+                    ip_hash.as_str().into(),
+                    MachineReason::SameIpEveryLink.as_str().into(),
+                ])?
+                .all()
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Who of a campaign's members clicked their links or loaded their images,
+    /// how often, from where and which variants they were routed to. Machine
+    /// hits are reported apart by reason.
+    pub async fn views(
+        &self,
+        session_id: impl Into<JsValue>,
+        campaign_id: &str,
+    ) -> worker::Result<Response> {
+        #[derive(serde::Deserialize)]
+        struct DbCount {
+            #[serde(rename = "Count")]
+            count: u64,
+        }
+
+        let Some(campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+
+        let links = self
+            .db
+            .prepare("SELECT COUNT(*) AS Count FROM Links WHERE CampaignId = ?;")
+            .bind(&[campaign.id.as_str().into()])?
+            .all()
+            .await?
+            .results::<DbCount>()?
+            .pop()
+            .map_or(0, |count| count.count);
+        let members = self
+            .db
+            .prepare("SELECT Links.EmailId, SUM(Hits.Kind = 'click' AND Hits.Machine = 0) AS Clicks, SUM(Hits.Kind = 'impression' AND Hits.Machine = 0) AS Impressions, SUM(Hits.Machine = 1) AS MachineHits, MIN(CASE WHEN Hits.Machine = 0 THEN Hits.HitAt END) AS FirstSeenAt, MAX(CASE WHEN Hits.Machine = 0 THEN Hits.HitAt END) AS LastSeenAt FROM Hits JOIN Links ON Links.Id = Hits.LinkId WHERE Links.CampaignId = ? GROUP BY Links.EmailId ORDER BY LastSeenAt DESC;")
+            .bind(&[campaign.id.as_str().into()])?
+            .all()
+            .await?
+            .results::<MemberViews>()?;
+        let countries = self
+            .db
+            .prepare("SELECT Hits.Country, SUM(Hits.Kind = 'click') AS Clicks, SUM(Hits.Kind = 'impression') AS Impressions FROM Hits JOIN Links ON Links.Id = Hits.LinkId WHERE Links.CampaignId = ? AND Hits.Machine = 0 GROUP BY Hits.Country ORDER BY COUNT(*) DESC;")
+            .bind(&[campaign.id.as_str().into()])?
+            .all()
+            .await?
+            .results::<CountryViews>()?;
+        let machine = self
+            .db
+            .prepare("SELECT Hits.Reason, COUNT(*) AS Hits FROM Hits JOIN Links ON Links.Id = Hits.LinkId WHERE Links.CampaignId = ? AND Hits.Machine = 1 GROUP BY Hits.Reason ORDER BY Hits DESC;")
+            .bind(&[campaign.id.as_str().into()])?
+            .all()
+            .await?
+            .results::<MachineHits>()?;
+        let variants = self
+            .db
+            .prepare("SELECT Hits.Variant, COUNT(*) AS Clicks FROM Hits JOIN Links ON Links.Id = Hits.LinkId WHERE Links.CampaignId = ? AND Hits.Kind = 'click' AND Hits.Machine = 0 GROUP BY Hits.Variant ORDER BY Clicks DESC;")
+            .bind(&[campaign.id.as_str().into()])?
+            .all()
+            .await?
+            .results::<VariantViews>()?;
+
+        Response::from_json(&serde_json::json!({
+            "links": links,
+            "human": {
+                "clicks": members.iter().map(|member| member.clicks).sum::<u64>(),
+                "impressions": members.iter().map(|member| member.impressions).sum::<u64>(),
+                "members_clicked": members.iter().filter(|member| member.clicks > 0).count(),
+                "members_opened": members.iter().filter(|member| member.impressions > 0).count(),
+            },
+            "machine": {
+                "hits": machine.iter().map(|reason| reason.hits).sum::<u64>(),
+                "reasons": machine,
+            },
+            "members": members,
+            "countries": countries,
+            "variants": variants,
+        }))
+    }
+
+    /// Every click and impression of a member on the links of a campaign,
+    /// latest first
+    pub async fn member_views(
+        &self,
+        session_id: impl Into<JsValue>,
+        campaign_id: &str,
+        email: &str,
+    ) -> worker::Result<Response> {
+        let Some(campaign) = self.db_campaign_of_session(session_id, campaign_id).await? else {
+            return Response::error("Campaign has not been added", 404);
+        };
+        let email = Personalization::normalize_email(email);
+
+        let hits = self
+            .db
+            .prepare("SELECT Links.Field, Hits.Kind, Hits.HitAt, Hits.UserAgent, Hits.Country, Hits.Reason, Hits.Variant FROM Hits JOIN Links ON Links.Id = Hits.LinkId WHERE Links.CampaignId = ? AND Links.EmailId = ? ORDER BY Hits.HitAt DESC;")
+            .bind(&[campaign.id.as_str().into(), email.as_str().into()])?
+            .all()
+            .await?
+            .results::<Hit>()?;
+
+        Response::from_json(&serde_json::json!({
+            "email": email,
+            "hits": hits,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_link_ids() {
+        let ids = (0..100).map(|_| Session::link_id()).collect::<Vec<_>>();
+
+        assert!(ids.iter().all(|id| Session::is_link_id(id)));
+        assert_eq!(
+            ids.iter().collect::<std::collections::HashSet<_>>().len(),
+            ids.len()
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_link_ids() {
+        assert!(Session::is_link_id("3bKx9aZ0"));
+        assert!(!Session::is_link_id(""));
+        assert!(!Session::is_link_id(&"a".repeat(23)));
+        assert!(!Session::is_link_id("3bKx-9aZ0"));
+        assert!(!Session::is_link_id("../links"));
+        assert!(!Session::is_link_id("3bKx9aZ0 OR 1=1"));
+        assert!(!Session::is_link_id("äbc"));
+    }
+}
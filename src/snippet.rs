@@ -0,0 +1,273 @@
+/// Where a snippet goes in a campaign's content
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Placement {
+    /// Right after the opening `<body>` tag
+    Top,
+    /// Right before the closing `</body>` tag
+    Bottom,
+    /// In place of the first occurrence of a text the user put in the content,
+    /// e.g. `[video]`
+    Marker(String),
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Placement::Marker(Snippet::DEFAULT_MARKER.into())
+    }
+}
+
+/// What inserting a snippet did to the content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Insertion {
+    Inserted,
+    /// The snippet was already in the content and was replaced
+    Updated,
+    /// The snippet was already in the content as is
+    Unchanged,
+}
+
+/// The linked thumbnail embedded in a campaign, along with its plain-text
+/// fallback. The html is wrapped in comments so that it is found and replaced
+/// rather than added again when it is inserted a second time.
+pub struct Snippet {
+    html: String,
+    plain_text: String,
+}
+
+impl Snippet {
+    pub const DEFAULT_MARKER: &'static str = "[video]";
+
+    const START: &'static str = "<!-- mailchimp_list:video -->";
+    const END: &'static str = "<!-- /mailchimp_list:video -->";
+    /// Starts the line of the plain-text fallback
+    const PLAIN_TEXT_PREFIX: &'static str = "Watch the video: ";
+
+    /// The snippet linking the image of the `image_tag` merge field to the url
+    /// of the `link_tag` merge field. Url fields are stored without a scheme.
+    pub fn video(link_tag: &str, image_tag: &str) -> Self {
+        Snippet {
+            html: format!(
+                "{}<a href=\"https://*|{link_tag}|*\"><img src=\"https://*|{image_tag}|*\" alt=\"Watch the video\" style=\"max-width: 100%;\"></a>{}",
+                Self::START,
+                Self::END
+            ),
+            plain_text: format!("{}https://*|{link_tag}|*", Self::PLAIN_TEXT_PREFIX),
+        }
+    }
+
+    /// Puts the snippet in the html of a campaign, replacing the snippet
+    /// inserted earlier if there is one
+    pub fn insert_html(
+        &self,
+        html: &str,
+        placement: &Placement,
+    ) -> Result<(String, Insertion), String> {
+        if let Some((start, end)) = Self::existing(html)? {
+            if html[start..end] == self.html {
+                return Ok((html.to_owned(), Insertion::Unchanged));
+            }
+
+            return Ok((
+                format!("{}{}{}", &html[..start], self.html, &html[end..]),
+                Insertion::Updated,
+            ));
+        }
+
+        // Lowercasing keeps the byte offsets of the ascii tags
+        let lowercase = html.to_ascii_lowercase();
+        let (start, end) = match placement {
+            Placement::Top => {
+                let at = lowercase
+                    .find("<body")
+                    .and_then(|body| Some(body + lowercase[body..].find('>')? + 1))
+                    .unwrap_or(0);
+                (at, at)
+            }
+            Placement::Bottom => {
+                let at = lowercase.rfind("</body>").unwrap_or(html.len());
+                (at, at)
+            }
+            Placement::Marker(marker) => {
+                let Some(at) = html.find(marker.as_str()).filter(|_| !marker.is_empty()) else {
+                    return Err(format!("The campaign's content has no '{marker}' marker"));
+                };
+                (at, at + marker.len())
+            }
+        };
+
+        Ok((
+            format!("{}{}{}", &html[..start], self.html, &html[end..]),
+            Insertion::Inserted,
+        ))
+    }
+
+    /// Puts the fallback line in the plain text of a campaign, replacing the
+    /// line inserted earlier if there is one. A marker missing from the plain
+    /// text puts the line at the bottom.
+    pub fn insert_plain_text(&self, plain_text: &str, placement: &Placement) -> String {
+        let mut replaced = false;
+        let lines = plain_text
+            .lines()
+            .map(|line| {
+                if line.starts_with(Self::PLAIN_TEXT_PREFIX) && !replaced {
+                    replaced = true;
+                    self.plain_text.as_str()
+                } else {
+                    line
+                }
+            })
+            .collect::<Vec<_>>();
+        if replaced {
+            return lines.join("\n");
+        }
+
+        match placement {
+            Placement::Marker(marker)
+                if !marker.is_empty() && plain_text.contains(marker.as_str()) =>
+            {
+                plain_text.replacen(marker.as_str(), &self.plain_text, 1)
+            }
+            Placement::Top => format!("{}\n\n{plain_text}", self.plain_text),
+            _ if plain_text.trim().is_empty() => self.plain_text.clone(),
+            _ => format!("{}\n\n{}", plain_text.trim_end(), self.plain_text),
+        }
+    }
+
+    /// The byte range of the snippet inserted earlier. A start without an end
+    /// means the snippet was edited by hand, which is not guessed around.
+    fn existing(html: &str) -> Result<Option<(usize, usize)>, String> {
+        let Some(start) = html.find(Self::START) else {
+            return Ok(None);
+        };
+        let Some(end) = html[start..].find(Self::END) else {
+            return Err(format!(
+                "The campaign's content has the start of an earlier snippet but not its end, remove the '{}' comment or put back '{}' after it",
+                Self::START,
+                Self::END
+            ));
+        };
+
+        Ok(Some((start, start + end + Self::END.len())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = "<html><BODY class=\"main\"><p>Hi</p>[video]<p>Bye</p></body></html>";
+
+    fn marker() -> Placement {
+        Placement::default()
+    }
+
+    #[test]
+    fn inserts_once() {
+        let snippet = Snippet::video("VIDEO", "THUMB");
+
+        let (html, insertion) = snippet.insert_html(HTML, &marker()).unwrap();
+        assert_eq!(insertion, Insertion::Inserted);
+        assert!(!html.contains("[video]"));
+        assert!(html.contains("<p>Hi</p><!-- mailchimp_list:video --><a href=\"https://*|VIDEO|*\"><img src=\"https://*|THUMB|*\""));
+
+        // The marker is gone, so the snippet itself is found again
+        let (again, insertion) = snippet.insert_html(&html, &marker()).unwrap();
+        assert_eq!(insertion, Insertion::Unchanged);
+        assert_eq!(again, html);
+    }
+
+    #[test]
+    fn updates_a_changed_snippet() {
+        let (html, _) = Snippet::video("VIDEO", "THUMB")
+            .insert_html(HTML, &marker())
+            .unwrap();
+
+        for changed in [
+            Snippet::video("VIDEO2", "THUMB"),
+            Snippet::video("VIDEO", "THUMB2"),
+        ] {
+            let (updated, insertion) = changed.insert_html(&html, &Placement::Top).unwrap();
+            assert_eq!(insertion, Insertion::Updated);
+            assert_eq!(updated.matches(Snippet::START).count(), 1);
+            assert!(updated.starts_with("<html><BODY class=\"main\"><p>Hi</p><!--"));
+            assert!(updated.ends_with("<p>Bye</p></body></html>"));
+            assert_ne!(updated, html);
+        }
+    }
+
+    #[test]
+    fn places_at_the_top_or_bottom() {
+        let snippet = Snippet::video("VIDEO", "THUMB");
+
+        let (top, _) = snippet.insert_html(HTML, &Placement::Top).unwrap();
+        assert!(top.starts_with("<html><BODY class=\"main\"><!-- mailchimp_list:video -->"));
+        let (bottom, _) = snippet.insert_html(HTML, &Placement::Bottom).unwrap();
+        assert!(bottom.ends_with("<!-- /mailchimp_list:video --></body></html>"));
+        // Content without a body gets the snippet at its start or end
+        let (top, _) = snippet.insert_html("<p>Hi</p>", &Placement::Top).unwrap();
+        assert!(top.starts_with(Snippet::START));
+        let (bottom, _) = snippet
+            .insert_html("<p>Hi</p>", &Placement::Bottom)
+            .unwrap();
+        assert!(bottom.ends_with(Snippet::END));
+    }
+
+    #[test]
+    fn rejects_missing_markers_and_unterminated_snippets() {
+        let snippet = Snippet::video("VIDEO", "THUMB");
+
+        assert!(snippet.insert_html("<p>Hi</p>", &marker()).is_err());
+        assert!(snippet
+            .insert_html("<p>Hi</p>", &Placement::Marker(String::new()))
+            .is_err());
+
+        let unterminated = format!(
+            "<p>Hi</p>{}<a href=\"https://example.com\">",
+            Snippet::START
+        );
+        assert!(snippet.insert_html(&unterminated, &Placement::Top).is_err());
+        // An end before the start does not terminate it
+        let reversed = format!("{}<p>Hi</p>{}", Snippet::END, Snippet::START);
+        assert!(snippet.insert_html(&reversed, &Placement::Top).is_err());
+    }
+
+    #[test]
+    fn replaces_the_plain_text_line() {
+        let snippet = Snippet::video("VIDEO", "THUMB");
+
+        let plain_text = snippet.insert_plain_text("Hi\n\n[video]\n\nBye", &marker());
+        assert_eq!(
+            plain_text,
+            "Hi\n\nWatch the video: https://*|VIDEO|*\n\nBye"
+        );
+        assert_eq!(
+            snippet.insert_plain_text(&plain_text, &marker()),
+            plain_text
+        );
+
+        let changed = Snippet::video("VIDEO2", "THUMB").insert_plain_text(&plain_text, &marker());
+        assert_eq!(changed, "Hi\n\nWatch the video: https://*|VIDEO2|*\n\nBye");
+        assert_eq!(changed.matches(Snippet::PLAIN_TEXT_PREFIX).count(), 1);
+    }
+
+    #[test]
+    fn places_the_plain_text_line() {
+        let snippet = Snippet::video("VIDEO", "THUMB");
+
+        assert_eq!(
+            snippet.insert_plain_text("Hi", &Placement::Top),
+            "Watch the video: https://*|VIDEO|*\n\nHi"
+        );
+        // A marker only in the html puts the line at the bottom
+        assert_eq!(
+            snippet.insert_plain_text("Hi\n", &marker()),
+            "Hi\n\nWatch the video: https://*|VIDEO|*"
+        );
+        assert_eq!(
+            snippet.insert_plain_text(" ", &Placement::Bottom),
+            "Watch the video: https://*|VIDEO|*"
+        );
+    }
+}
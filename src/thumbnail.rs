@@ -0,0 +1,229 @@
+use std::io::Cursor;
+
+use image::{imageops::FilterType, ImageOutputFormat, Rgba, RgbaImage};
+
+use crate::bitmap_font;
+
+/// Thumbnails are scaled down to the width of an email
+const MAX_WIDTH: u32 = 640;
+const JPEG_QUALITY: u8 = 85;
+
+const BLACK: [u8; 3] = [0, 0, 0];
+const WHITE: [u8; 3] = [255, 255, 255];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    Jpeg,
+}
+
+impl Format {
+    /// The format of a file name like `token.png`, along with the name
+    /// without the extension. Jpeg is used when there is no known extension.
+    pub fn from_file_name(name: &str) -> (&str, Format) {
+        match name.strip_suffix(".png") {
+            Some(name) => (name, Format::Png),
+            None => (
+                name.strip_suffix(".jpg")
+                    .or_else(|| name.strip_suffix(".jpeg"))
+                    .unwrap_or(name),
+                Format::Jpeg,
+            ),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Jpeg => "jpg",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Png => "image/png",
+            Format::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+/// Draws a play button over the middle of a thumbnail and greets the member
+/// by their first name in a band along the bottom
+pub fn render(base: &[u8], first_name: Option<&str>, format: Format) -> Result<Vec<u8>, String> {
+    let mut image = image::load_from_memory(base)
+        .map_err(|err| format!("The thumbnail is no png, jpeg, gif or webp image: {err}"))?
+        .to_rgba8();
+    if image.width() > MAX_WIDTH {
+        let height = image.height() * MAX_WIDTH / image.width();
+        image = image::imageops::resize(&image, MAX_WIDTH, height.max(1), FilterType::Triangle);
+    }
+
+    draw_play_button(&mut image);
+    if let Some(first_name) = first_name {
+        draw_greeting(&mut image, &format!("Hi {first_name}!"));
+    }
+
+    let mut encoded = Cursor::new(Vec::new());
+    match format {
+        Format::Png => image.write_to(&mut encoded, ImageOutputFormat::Png),
+        // Jpeg has no alpha channel
+        Format::Jpeg => image::DynamicImage::ImageRgba8(image)
+            .to_rgb8()
+            .write_to(&mut encoded, ImageOutputFormat::Jpeg(JPEG_QUALITY)),
+    }
+    .map_err(|err| format!("Failed to encode the thumbnail: {err}"))?;
+
+    Ok(encoded.into_inner())
+}
+
+/// A translucent disc with a triangle pointing right
+fn draw_play_button(image: &mut RgbaImage) {
+    let (width, height) = image.dimensions();
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+    let radius = width.min(height) as f32 / 7.0;
+    if radius < 4.0 {
+        return;
+    }
+
+    // The left edge and the tip of the triangle, centred on its centroid
+    let (left, tip, half_height) = (cx - radius * 0.3, cx + radius * 0.5, radius * 0.45);
+
+    let (x0, x1) = ((cx - radius).floor() as u32, (cx + radius).ceil() as u32);
+    let (y0, y1) = ((cy - radius).floor() as u32, (cy + radius).ceil() as u32);
+    for y in y0..y1.min(height) {
+        for x in x0..x1.min(width) {
+            let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+            let distance = ((px - cx).powi(2) + (py - cy).powi(2)).sqrt();
+            // Smooths the edge of the disc over a pixel
+            let coverage = (radius - distance + 0.5).clamp(0.0, 1.0);
+            if coverage == 0.0 {
+                continue;
+            }
+
+            let pixel = image.get_pixel_mut(x, y);
+            // The triangle narrows from its left edge to the tip
+            let half = half_height * (tip - px) / (tip - left);
+            if px >= left && px <= tip && (py - cy).abs() <= half {
+                blend(pixel, WHITE, 0.95 * coverage);
+            } else {
+                blend(pixel, BLACK, 0.55 * coverage);
+            }
+        }
+    }
+}
+
+/// A translucent band along the bottom with the text in white
+fn draw_greeting(image: &mut RgbaImage, text: &str) {
+    let (width, height) = image.dimensions();
+    // A name missing some of its letters would greet someone else
+    let Some(glyphs) = text
+        .chars()
+        .map(bitmap_font::glyph)
+        .collect::<Option<Vec<_>>>()
+    else {
+        return;
+    };
+
+    // Glyphs are one column apart, and the text takes a tenth of the height
+    // unless it would be wider than the image
+    let advance = bitmap_font::WIDTH + 1;
+    let padding = (height / 24).max(2);
+    let fitting = width.saturating_sub(2 * padding) / (glyphs.len() as u32 * advance);
+    let scale = (height / 10 / bitmap_font::HEIGHT).max(1).min(fitting);
+    if scale == 0 {
+        return;
+    }
+
+    let band = bitmap_font::HEIGHT * scale + 2 * padding;
+    if band >= height {
+        return;
+    }
+    for y in height - band..height {
+        for x in 0..width {
+            blend(image.get_pixel_mut(x, y), BLACK, 0.5);
+        }
+    }
+
+    let top = height - band + padding;
+    for (i, rows) in glyphs.iter().enumerate() {
+        let left = padding + i as u32 * advance * scale;
+        for (row, bits) in rows.iter().enumerate() {
+            for column in 0..bitmap_font::WIDTH {
+                if bits & (1 << (bitmap_font::WIDTH - 1 - column)) == 0 {
+                    continue;
+                }
+
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let pixel = image.get_pixel_mut(
+                            left + column * scale + dx,
+                            top + row as u32 * scale + dy,
+                        );
+                        blend(pixel, WHITE, 1.0);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn blend(pixel: &mut Rgba<u8>, color: [u8; 3], alpha: f32) {
+    for (channel, color) in pixel.0.iter_mut().zip(color) {
+        *channel = (*channel as f32 * (1.0 - alpha) + color as f32 * alpha).round() as u8;
+    }
+    // Transparent parts of a png become as opaque as what is drawn over them
+    pixel.0[3] = (pixel.0[3] as f32 + (255.0 - pixel.0[3] as f32) * alpha).round() as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_pixel(width, height, Rgba([128, 128, 128, 255]))
+    }
+
+    fn encode(image: &RgbaImage, format: ImageOutputFormat) -> Vec<u8> {
+        let mut encoded = Cursor::new(Vec::new());
+        image
+            .write_to(&mut encoded, format)
+            .expect("Failed to encode image");
+        encoded.into_inner()
+    }
+
+    #[test]
+    fn renders_gif_thumbnails() {
+        // Loom serves its thumbnails as animated gifs
+        let base = encode(&gray(320, 180), ImageOutputFormat::Gif);
+        let rendered = render(&base, Some("Ann"), Format::Png).expect("Failed to render gif");
+
+        let image = image::load_from_memory(&rendered).unwrap();
+        assert_eq!((image.width(), image.height()), (320, 180));
+    }
+
+    #[test]
+    fn scales_down_wide_thumbnails() {
+        let base = encode(&gray(1280, 720), ImageOutputFormat::Png);
+        let rendered = render(&base, None, Format::Jpeg).expect("Failed to render png");
+
+        let image = image::load_from_memory(&rendered).unwrap();
+        assert_eq!((image.width(), image.height()), (MAX_WIDTH, 360));
+    }
+
+    #[test]
+    fn greets_only_with_every_glyph() {
+        let mut greeted = gray(320, 180);
+        draw_greeting(&mut greeted, "Hi Zoë!");
+        assert_ne!(greeted, gray(320, 180));
+
+        // A name the font can not draw is left out rather than drawn in part
+        let mut skipped = gray(320, 180);
+        draw_greeting(&mut skipped, "Hi 李娜!");
+        assert_eq!(skipped, gray(320, 180));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(render(b"<svg></svg>", None, Format::Png).is_err());
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+
+use md5::{Digest, Md5};
+
+/// The member a url template is rendered for
+pub struct TemplateMember<'a> {
+    pub email: &'a str,
+    pub name: &'a str,
+    /// The member's merge field values keyed by tag
+    pub merges: &'a BTreeMap<String, String>,
+}
+
+/// A url with placeholders that is rendered per member, e.g.
+/// `https://cdn.example.com/{campaign_id}/{subscriber_hash}.mp4`
+///
+/// The supported placeholders are `{campaign_id}`, `{list_id}`, `{email}`,
+/// `{subscriber_hash}`, `{name}` and `{merge:TAG}` for merge field values.
+pub struct UrlTemplate<'a>(pub &'a str);
+
+enum Segment<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+}
+
+impl<'a> UrlTemplate<'a> {
+    fn segments(&self) -> Result<Vec<Segment<'a>>, String> {
+        let mut segments = Vec::new();
+        let mut rest = self.0;
+
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                return Err(format!("Unclosed placeholder in '{}'", self.0));
+            };
+
+            segments.push(Segment::Text(&rest[..start]));
+            segments.push(Segment::Placeholder(&rest[start + 1..start + len]));
+            rest = &rest[start + len + 1..];
+        }
+        segments.push(Segment::Text(rest));
+
+        Ok(segments)
+    }
+
+    /// Checks that every placeholder of the template is a known one
+    pub fn validate(&self) -> Result<(), String> {
+        for segment in self.segments()? {
+            if let Segment::Placeholder(placeholder) = segment {
+                match placeholder {
+                    "campaign_id" | "list_id" | "email" | "subscriber_hash" | "name" => {}
+                    merge
+                        if merge
+                            .strip_prefix("merge:")
+                            .is_some_and(|tag| !tag.is_empty()) => {}
+                    unknown => return Err(format!("Unknown placeholder {{{unknown}}}")),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks a template a followed link is routed to, see
+    /// [`render_route`](Self::render_route). Merge fields are not known when
+    /// a link is followed, so they can not be used.
+    pub fn validate_route(&self) -> Result<(), String> {
+        let rest = match self.0.strip_prefix("{url}") {
+            Some(rest) => {
+                let query = rest.split('#').next().unwrap_or_default();
+                if !(rest.is_empty() || rest.starts_with(['?', '#']))
+                    || query.matches('?').count() > 1
+                {
+                    return Err(format!(
+                        "Only query parameters and a fragment can follow {{url}}, like {{url}}?lang=de, not '{rest}'"
+                    ));
+                }
+                rest
+            }
+            None => self.0,
+        };
+
+        let template = UrlTemplate(rest);
+        for segment in template.segments()? {
+            match segment {
+                Segment::Placeholder("url") => {
+                    return Err("{url} can only be put at the start of the url".to_owned())
+                }
+                Segment::Placeholder(merge) if merge.starts_with("merge:") => {
+                    return Err(format!(
+                        "{{{merge}}} can not be used where links are routed to, use {{url}} or the member's email or name"
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        template.validate()
+    }
+
+    /// Renders the template for a member. Values are url encoded, and unknown
+    /// placeholders or merge fields the member has no value for render empty.
+    pub fn render(&self, campaign_id: &str, list_id: &str, member: &TemplateMember) -> String {
+        let Ok(segments) = self.segments() else {
+            return self.0.to_owned();
+        };
+
+        segments
+            .into_iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.to_owned(),
+                Segment::Placeholder(placeholder) => {
+                    let value = match placeholder {
+                        "campaign_id" => campaign_id.to_owned(),
+                        "list_id" => list_id.to_owned(),
+                        "email" => member.email.to_owned(),
+                        "subscriber_hash" => Self::subscriber_hash(member.email),
+                        "name" => member.name.to_owned(),
+                        placeholder => placeholder
+                            .strip_prefix("merge:")
+                            .and_then(|tag| member.merges.get(tag))
+                            .cloned()
+                            .unwrap_or_default(),
+                    };
+
+                    urlencoding::encode(&value).into_owned()
+                }
+            })
+            .collect()
+    }
+
+    /// Renders the template a link of a member is routed to. A template that
+    /// starts with `{url}` leads to the `url` of the member's link, with the
+    /// query parameters and the fragment after it added, e.g. `{url}?lang=de`.
+    pub fn render_route(
+        &self,
+        campaign_id: &str,
+        list_id: &str,
+        member: &TemplateMember,
+        url: &str,
+    ) -> String {
+        let Some(rest) = self.0.strip_prefix("{url}") else {
+            return self.render(campaign_id, list_id, member);
+        };
+        let Ok(mut routed) = url::Url::parse(url) else {
+            return url.to_owned();
+        };
+
+        // Values are url encoded, so a `#` or `?` can only come from the template
+        let rest = UrlTemplate(rest).render(campaign_id, list_id, member);
+        let (query, fragment) = match rest.split_once('#') {
+            Some((query, fragment)) => (query, Some(fragment)),
+            None => (rest.as_str(), None),
+        };
+        let query = query.strip_prefix('?').unwrap_or(query);
+        if !query.is_empty() {
+            routed
+                .query_pairs_mut()
+                .extend_pairs(form_urlencoded::parse(query.as_bytes()));
+        }
+        if fragment.is_some() {
+            routed.set_fragment(fragment);
+        }
+
+        routed.into()
+    }
+
+    /// The id mailchimp uses for a member: the md5 hash of the lowercased email
+    pub fn subscriber_hash(email: &str) -> String {
+        Md5::digest(email.trim().to_lowercase().as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_and_encodes_placeholders() {
+        let merges = BTreeMap::from([
+            ("FNAME".to_owned(), "Ann & Bo".to_owned()),
+            ("CITY".to_owned(), "Zürich".to_owned()),
+        ]);
+        let member = TemplateMember {
+            email: " Ann+News@Example.com",
+            name: "Ann Lee",
+            merges: &merges,
+        };
+        let render = |template: &str| UrlTemplate(template).render("c1", "l/1", &member);
+
+        assert_eq!(
+            render("https://cdn.example.com/{campaign_id}/{list_id}.mp4"),
+            "https://cdn.example.com/c1/l%2F1.mp4"
+        );
+        assert_eq!(
+            render("https://example.com/?to={email}&who={name}"),
+            "https://example.com/?to=%20Ann%2BNews%40Example.com&who=Ann%20Lee"
+        );
+        // The hash of the trimmed, lowercased email, as mailchimp has it
+        assert_eq!(
+            render("https://cdn.example.com/{subscriber_hash}.jpg"),
+            "https://cdn.example.com/0585edafe58c20df6060f739dda60715.jpg"
+        );
+        assert_eq!(
+            render("https://example.com/{merge:FNAME}/{merge:CITY}/{merge:ZIP}"),
+            "https://example.com/Ann%20%26%20Bo/Z%C3%BCrich/"
+        );
+        assert_eq!(
+            render("https://example.com/{nope"),
+            "https://example.com/{nope"
+        );
+    }
+
+    #[test]
+    fn validates_placeholders() {
+        assert_eq!(
+            UrlTemplate("https://example.com/{campaign_id}/{list_id}/{email}").validate(),
+            Ok(())
+        );
+        assert_eq!(
+            UrlTemplate("https://example.com/{subscriber_hash}?n={name}&c={merge:CITY}").validate(),
+            Ok(())
+        );
+        assert_eq!(
+            UrlTemplate("https://example.com/{campaign}").validate(),
+            Err("Unknown placeholder {campaign}".to_owned())
+        );
+        assert!(UrlTemplate("https://example.com/{merge:}")
+            .validate()
+            .is_err());
+        assert_eq!(
+            UrlTemplate("https://example.com/{email").validate(),
+            Err("Unclosed placeholder in 'https://example.com/{email'".to_owned())
+        );
+    }
+
+    #[test]
+    fn renders_routes_with_the_members_url() {
+        let merges = BTreeMap::from([("FNAME".to_owned(), "Ann".to_owned())]);
+        let member = TemplateMember {
+            email: "ann+news@example.com",
+            name: "Ann Lee",
+            merges: &merges,
+        };
+        let route = |template: &str, url: &str| {
+            UrlTemplate(template).render_route("c1", "l1", &member, url)
+        };
+
+        assert_eq!(
+            route("{url}?lang=de&who={email}", "https://vimeo.com/1?h=2"),
+            "https://vimeo.com/1?h=2&lang=de&who=ann%2Bnews%40example.com"
+        );
+        // The video id of a youtube link is kept as it is
+        assert_eq!(
+            route("{url}?lang=de#t=1m", "https://www.youtube.com/watch?v=abc"),
+            "https://www.youtube.com/watch?v=abc&lang=de#t=1m"
+        );
+        assert_eq!(
+            route("{url}", "https://example.com/v.mp4"),
+            "https://example.com/v.mp4"
+        );
+        assert_eq!(
+            route("https://example.com/{name}", "https://example.com/v.mp4"),
+            "https://example.com/Ann%20Lee"
+        );
+    }
+
+    #[test]
+    fn validates_routes() {
+        assert_eq!(UrlTemplate("{url}?lang=de#{name}").validate_route(), Ok(()));
+        assert_eq!(
+            UrlTemplate("https://example.com/{email}").validate_route(),
+            Ok(())
+        );
+        assert!(UrlTemplate("{url}?lang=de?who={email}")
+            .validate_route()
+            .is_err());
+        assert!(UrlTemplate("{url}/de").validate_route().is_err());
+        assert!(UrlTemplate("https://example.com/?to={url}")
+            .validate_route()
+            .is_err());
+        assert!(UrlTemplate("{url}?name={merge:FNAME}")
+            .validate_route()
+            .is_err());
+        assert!(UrlTemplate("{url}?lang={lang").validate_route().is_err());
+        // Field templates have no url of their own
+        assert!(UrlTemplate("{url}").validate().is_err());
+    }
+}
@@ -0,0 +1,404 @@
+/// A link to a video of a known provider, or to a video file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VideoLink {
+    /// `hash` is the key of an unlisted video
+    Vimeo {
+        id: String,
+        hash: Option<String>,
+    },
+    YouTube(String),
+    Wistia(String),
+    Loom(String),
+    File(url::Url),
+}
+
+impl VideoLink {
+    const FILE_EXTENSIONS: &'static [&'static str] = &[".mp4", ".m4v", ".mov", ".webm"];
+
+    /// Recognizes the many forms of links users copy from the providers, e.g.
+    /// `youtu.be/…`, `youtube.com/watch?v=…`, `vimeo.com/channels/…/…` or the
+    /// players' embed urls. `None` for links to anything else.
+    pub fn parse(url: &url::Url) -> Option<Self> {
+        let host = url.host_str()?.to_ascii_lowercase();
+        let host = host
+            .strip_prefix("www.")
+            .or_else(|| host.strip_prefix("m."))
+            .unwrap_or(&host);
+        let segments: Vec<&str> = url
+            .path_segments()
+            .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+            .unwrap_or_default();
+        let query = |key: &str| {
+            url.query_pairs()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.into_owned())
+        };
+
+        let video = match (host, segments.as_slice()) {
+            ("vimeo.com", [id]) => Self::vimeo(id, None),
+            ("vimeo.com", [id, hash]) => Self::vimeo(id, Some(hash)),
+            ("vimeo.com", ["channels", _, id] | ["groups", _, "videos", id])
+            | ("vimeo.com", ["album" | "showcase", _, "video", id]) => Self::vimeo(id, None),
+            ("player.vimeo.com", ["video", id]) => Self::vimeo(id, query("h").as_deref()),
+
+            ("youtube.com" | "music.youtube.com", ["watch"]) => Self::youtube(&query("v")?),
+            ("youtube.com" | "youtube-nocookie.com", ["embed" | "shorts" | "live" | "v", id])
+            | ("youtu.be", [id]) => Self::youtube(id),
+
+            ("loom.com", ["share" | "embed", id]) => Self::loom(id),
+
+            ("wi.st", ["medias", id]) => Self::wistia(id),
+            (host, [.., "medias" | "iframe", id])
+                if host.ends_with(".wistia.com")
+                    || host.ends_with(".wistia.net")
+                    || host == "wistia.com" =>
+            {
+                Self::wistia(id)
+            }
+
+            _ => None,
+        };
+        if video.is_some() {
+            return video;
+        }
+
+        let path = url.path().to_ascii_lowercase();
+        Self::FILE_EXTENSIONS
+            .iter()
+            .any(|extension| path.ends_with(extension))
+            .then(|| {
+                let mut url = url.clone();
+                url.set_fragment(None);
+                VideoLink::File(url)
+            })
+    }
+
+    /// The second a link starts the video at, given as `t=1m30s`, `t=90`,
+    /// `start=90` or `time=90` in the query, or as a `#t=90s` fragment
+    pub fn start_secs(url: &url::Url) -> Option<u32> {
+        let value = url
+            .query_pairs()
+            .find(|(name, _)| matches!(name.as_ref(), "t" | "start" | "time"))
+            .map(|(_, value)| value.into_owned())
+            .or_else(|| url.fragment()?.strip_prefix("t=").map(String::from))?;
+
+        let mut total = 0u32;
+        let mut number = None::<u32>;
+        for c in value.trim().chars() {
+            let unit = match c {
+                'h' => 3600,
+                'm' => 60,
+                's' => 1,
+                _ => {
+                    let digit = c.to_digit(10)?;
+                    number = Some(number.unwrap_or(0).checked_mul(10)?.checked_add(digit)?);
+                    continue;
+                }
+            };
+            total = total.checked_add(number.take()?.checked_mul(unit)?)?;
+        }
+        total = total.checked_add(number.unwrap_or(0))?;
+
+        (total > 0).then_some(total)
+    }
+
+    /// The one link every form of the same video is stored as, keeping the
+    /// second it starts at
+    pub fn canonical(&self, start: Option<u32>) -> url::Url {
+        let url = match self {
+            VideoLink::Vimeo { id, hash: None } => format!("https://vimeo.com/{id}"),
+            VideoLink::Vimeo {
+                id,
+                hash: Some(hash),
+            } => format!("https://vimeo.com/{id}/{hash}"),
+            VideoLink::YouTube(id) => format!("https://www.youtube.com/watch?v={id}"),
+            VideoLink::Wistia(id) => format!("https://fast.wistia.net/embed/iframe/{id}"),
+            VideoLink::Loom(id) => format!("https://www.loom.com/share/{id}"),
+            VideoLink::File(url) => url.to_string(),
+        };
+        let url = url::Url::parse(&url).expect("Video ids are checked to be url safe");
+
+        self.starting_at(url, start, "t")
+    }
+
+    /// The player to embed in a page, `None` for files which browsers play
+    /// themselves
+    pub fn embed_url(&self, start: Option<u32>) -> Option<url::Url> {
+        let url = match self {
+            VideoLink::Vimeo { id, hash: None } => format!("https://player.vimeo.com/video/{id}"),
+            VideoLink::Vimeo {
+                id,
+                hash: Some(hash),
+            } => format!("https://player.vimeo.com/video/{id}?h={hash}"),
+            VideoLink::YouTube(id) => format!("https://www.youtube-nocookie.com/embed/{id}"),
+            VideoLink::Wistia(id) => format!("https://fast.wistia.net/embed/iframe/{id}"),
+            VideoLink::Loom(id) => format!("https://www.loom.com/embed/{id}"),
+            VideoLink::File(_) => return None,
+        };
+        let url = url::Url::parse(&url).expect("Video ids are checked to be url safe");
+
+        Some(self.starting_at(url, start, "start"))
+    }
+
+    /// Puts the start time in a link the way the provider reads it, YouTube
+    /// taking it as `youtube_key` which differs between links and players
+    fn starting_at(&self, mut url: url::Url, start: Option<u32>, youtube_key: &str) -> url::Url {
+        let Some(start) = start else {
+            return url;
+        };
+
+        match self {
+            VideoLink::YouTube(_) => {
+                url.query_pairs_mut()
+                    .append_pair(youtube_key, &start.to_string());
+            }
+            VideoLink::Wistia(_) => {
+                url.query_pairs_mut()
+                    .append_pair("time", &start.to_string());
+            }
+            VideoLink::Loom(_) => {
+                url.query_pairs_mut().append_pair("t", &start.to_string());
+            }
+            VideoLink::Vimeo { .. } => url.set_fragment(Some(&format!("t={start}s"))),
+            VideoLink::File(_) => url.set_fragment(Some(&format!("t={start}"))),
+        }
+
+        url
+    }
+
+    /// The thumbnail of the video where the provider serves it from a url
+    /// derived from the id. Vimeo and Wistia only tell it through their apis.
+    pub fn thumbnail(&self) -> Option<url::Url> {
+        let url = match self {
+            VideoLink::YouTube(id) => format!("https://i.ytimg.com/vi/{id}/hqdefault.jpg"),
+            VideoLink::Loom(id) => {
+                format!("https://cdn.loom.com/sessions/thumbnails/{id}-with-play.gif")
+            }
+            VideoLink::Vimeo { .. } | VideoLink::Wistia(_) | VideoLink::File(_) => return None,
+        };
+
+        Some(url::Url::parse(&url).expect("Video ids are checked to be url safe"))
+    }
+
+    fn vimeo(id: &str, hash: Option<&str>) -> Option<Self> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+
+        Some(VideoLink::Vimeo {
+            id: id.into(),
+            hash: hash
+                .filter(|hash| !hash.is_empty() && hash.chars().all(|c| c.is_ascii_alphanumeric()))
+                .map(Into::into),
+        })
+    }
+
+    fn youtube(id: &str) -> Option<Self> {
+        (id.len() == 11
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        .then(|| VideoLink::YouTube(id.into()))
+    }
+
+    fn wistia(id: &str) -> Option<Self> {
+        (!id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric()))
+            .then(|| VideoLink::Wistia(id.into()))
+    }
+
+    /// Share links may put the title before the id, e.g. `title-of-video-{id}`
+    fn loom(id: &str) -> Option<Self> {
+        let id = id.get(id.len().checked_sub(32)?..)?;
+
+        id.chars()
+            .all(|c| c.is_ascii_hexdigit())
+            .then(|| VideoLink::Loom(id.to_ascii_lowercase()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canonical(link: &str) -> String {
+        let url = url::Url::parse(link).expect("Failed to parse url");
+        VideoLink::parse(&url)
+            .expect("Failed to recognize video")
+            .canonical(VideoLink::start_secs(&url))
+            .into()
+    }
+
+    fn parse(link: &str) -> Option<VideoLink> {
+        VideoLink::parse(&url::Url::parse(link).expect("Failed to parse url"))
+    }
+
+    #[test]
+    fn recognizes_vimeo_links() {
+        let video = |id: &str| VideoLink::Vimeo {
+            id: id.into(),
+            hash: None,
+        };
+
+        assert_eq!(parse("https://vimeo.com/76979871"), Some(video("76979871")));
+        assert_eq!(
+            parse("https://vimeo.com/channels/staffpicks/76979871"),
+            Some(video("76979871"))
+        );
+        assert_eq!(
+            parse("https://vimeo.com/showcase/9915391/video/76979871"),
+            Some(video("76979871"))
+        );
+        assert_eq!(
+            canonical("https://player.vimeo.com/video/76979871?h=8272103f6e&badge=0"),
+            "https://vimeo.com/76979871/8272103f6e"
+        );
+        assert_eq!(
+            canonical("https://vimeo.com/76979871/8272103f6e"),
+            "https://vimeo.com/76979871/8272103f6e"
+        );
+        assert_eq!(parse("https://vimeo.com/about"), None);
+        // Vimeo only tells thumbnails through its api
+        assert_eq!(video("76979871").thumbnail(), None);
+    }
+
+    #[test]
+    fn recognizes_youtube_links() {
+        for link in [
+            "https://youtu.be/dQw4w9WgXcQ",
+            "https://m.youtube.com/watch?v=dQw4w9WgXcQ&feature=share",
+            "https://www.youtube.com/shorts/dQw4w9WgXcQ",
+            "https://www.youtube.com/embed/dQw4w9WgXcQ?rel=0",
+            "https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ",
+        ] {
+            assert_eq!(
+                canonical(link),
+                "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+                "{link}"
+            );
+        }
+        assert_eq!(
+            parse("https://youtu.be/dQw4w9WgXcQ").and_then(|video| video.thumbnail()),
+            Some(url::Url::parse("https://i.ytimg.com/vi/dQw4w9WgXcQ/hqdefault.jpg").unwrap())
+        );
+        assert_eq!(parse("https://www.youtube.com/watch?v=short"), None);
+        assert_eq!(parse("https://www.youtube.com/@channel"), None);
+    }
+
+    #[test]
+    fn recognizes_wistia_links() {
+        for link in [
+            "https://acme.wistia.com/medias/abc123xyz",
+            "https://fast.wistia.net/embed/iframe/abc123xyz?videoFoam=true",
+            "https://wi.st/medias/abc123xyz",
+        ] {
+            assert_eq!(
+                canonical(link),
+                "https://fast.wistia.net/embed/iframe/abc123xyz",
+                "{link}"
+            );
+        }
+        assert_eq!(
+            parse("https://wi.st/medias/abc123xyz").and_then(|video| video.thumbnail()),
+            None
+        );
+    }
+
+    #[test]
+    fn recognizes_loom_links() {
+        let id = "0123456789abcdef0123456789abcdef";
+
+        assert_eq!(
+            canonical(&format!("https://www.loom.com/share/{id}")),
+            format!("https://www.loom.com/share/{id}")
+        );
+        // Share links may start with the title of the video
+        assert_eq!(
+            canonical(&format!(
+                "https://www.loom.com/share/Quarterly-Update-{}?sid=1",
+                id.to_ascii_uppercase()
+            )),
+            format!("https://www.loom.com/share/{id}")
+        );
+        assert_eq!(
+            parse(&format!("https://www.loom.com/embed/{id}")).and_then(|video| video.thumbnail()),
+            Some(
+                url::Url::parse(&format!(
+                    "https://cdn.loom.com/sessions/thumbnails/{id}-with-play.gif"
+                ))
+                .unwrap()
+            )
+        );
+        assert_eq!(parse("https://www.loom.com/share/not-an-id"), None);
+    }
+
+    #[test]
+    fn recognizes_video_files() {
+        assert_eq!(
+            canonical("https://cdn.example.com/videos/Intro.MP4?v=2#top"),
+            "https://cdn.example.com/videos/Intro.MP4?v=2"
+        );
+        assert_eq!(
+            parse("https://cdn.example.com/videos/intro.mp4").and_then(|video| video.thumbnail()),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_other_links() {
+        assert_eq!(parse("https://example.com/watch?v=dQw4w9WgXcQ"), None);
+        assert_eq!(parse("https://notvimeo.com/76979871"), None);
+        assert_eq!(parse("https://dailymotion.com/video/x7tgad0"), None);
+        assert_eq!(parse("https://cdn.example.com/videos/intro.mp4.html"), None);
+    }
+
+    #[test]
+    fn reads_start_times() {
+        let start = |link| VideoLink::start_secs(&url::Url::parse(link).unwrap());
+
+        assert_eq!(start("https://youtu.be/dQw4w9WgXcQ?t=90"), Some(90));
+        assert_eq!(start("https://youtu.be/dQw4w9WgXcQ?t=1m30s"), Some(90));
+        assert_eq!(
+            start("https://youtube.com/embed/dQw4w9WgXcQ?start=1h"),
+            Some(3600)
+        );
+        assert_eq!(start("https://vimeo.com/1#t=45s"), Some(45));
+        assert_eq!(start("https://vimeo.com/1"), None);
+        assert_eq!(start("https://vimeo.com/1#t=0"), None);
+        assert_eq!(start("https://vimeo.com/1#t=m"), None);
+        assert_eq!(start("https://vimeo.com/1#t=1x"), None);
+        assert_eq!(start("https://vimeo.com/1#t=99999999999"), None);
+    }
+
+    #[test]
+    fn keeps_start_times() {
+        assert_eq!(
+            canonical("https://youtu.be/dQw4w9WgXcQ?t=1m30s&si=share"),
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=90"
+        );
+        assert_eq!(
+            canonical("https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PL1"),
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ"
+        );
+        assert_eq!(
+            canonical("https://player.vimeo.com/video/76979871?h=8272103f6e#t=30s"),
+            "https://vimeo.com/76979871/8272103f6e#t=30s"
+        );
+        assert_eq!(
+            canonical("https://example.com/videos/intro.mp4#t=12"),
+            "https://example.com/videos/intro.mp4#t=12"
+        );
+
+        let video = VideoLink::YouTube("dQw4w9WgXcQ".into());
+        assert_eq!(
+            video.embed_url(Some(90)).unwrap().as_str(),
+            "https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ?start=90"
+        );
+        assert_eq!(
+            VideoLink::Wistia("abc123".into())
+                .embed_url(Some(5))
+                .unwrap()
+                .as_str(),
+            "https://fast.wistia.net/embed/iframe/abc123?time=5"
+        );
+    }
+}